@@ -0,0 +1,270 @@
+use chrono::{TimeDelta, Utc};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::crypto::PublicKey;
+use crate::params::ChainParams;
+use crate::pow;
+use crate::sha256::Hash;
+use crate::types::{
+    fee_rate, Block, BlockHeader, Blockchain, Mempool, MempoolEntry,
+    SpendingCondition, Transaction, TransactionOutput,
+};
+use crate::util::MerkleRoot;
+use std::cmp::Ordering;
+use std::collections::{BTreeSet, BinaryHeap, HashMap};
+
+// Block templates for miners, filled with the mempool
+// transactions paying the most per byte
+
+// room left for the length of the transaction list
+// growing as transactions are added
+const TRANSACTION_LIST_OVERHEAD: u64 = 8;
+
+/// Fee information about a transaction in a block template
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TemplateTransaction {
+    pub txid: Hash,
+    /// Fee paid by the transaction
+    pub fee: u64,
+    /// Serialized size in bytes
+    pub size: u64,
+}
+
+impl TemplateTransaction {
+    // fee per 1000 bytes
+    pub fn fee_rate(&self) -> u64 {
+        fee_rate(self.fee, self.size)
+    }
+}
+
+/// A block to mine, together with what its
+/// transactions pay
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct BlockTemplate {
+    pub block: Block,
+    /// Transactions after the coinbase, in block order
+    pub transactions: Vec<TemplateTransaction>,
+    /// Fees of all transactions, paid to the coinbase
+    pub total_fees: u64,
+    /// Serialized size of the block in bytes
+    pub size: u64,
+}
+
+/// Builds block templates on top of the active chain
+pub struct BlockAssembler {
+    max_block_size: u64,
+}
+
+impl BlockAssembler {
+    /// Create an assembler for blocks of at most
+    /// `max_block_size` serialized bytes
+    pub fn new(max_block_size: u64) -> Self {
+        BlockAssembler { max_block_size }
+    }
+    // an assembler for full size blocks of a chain
+    pub fn for_chain(params: &ChainParams) -> Self {
+        Self::new(params.max_block_size)
+    }
+    // build a template with a coinbase paying the block
+    // reward and all fees to `pubkey`
+    pub fn assemble(
+        &self,
+        blockchain: &Blockchain,
+        pubkey: PublicKey,
+    ) -> BlockTemplate {
+        let coinbase = Transaction::new(
+            vec![],
+            vec![TransactionOutput {
+                pubkey,
+                unique_id: Uuid::new_v4(),
+                // the largest value, so the size doesn't
+                // grow when the real one is filled in
+                value: u64::MAX,
+                condition: SpendingCondition::Signature,
+                timelock: None,
+            }],
+        );
+        // the timestamp has to be after the median time past,
+        // even if our clock is behind the chain's
+        let timestamp = match blockchain.median_time_past() {
+            Some(median) => Utc::now().max(median + TimeDelta::seconds(1)),
+            None => Utc::now(),
+        };
+        let mut block = Block::new(
+            BlockHeader {
+                timestamp,
+                prev_block_hash: blockchain
+                    .blocks()
+                    .last()
+                    .map(|last_block| last_block.hash())
+                    .unwrap_or(Hash::zero()),
+                nonce: 0,
+                bits: pow::target_to_bits(blockchain.target()),
+                merkle_root: MerkleRoot::calculate(std::slice::from_ref(&coinbase)),
+            },
+            vec![coinbase],
+        );
+        let base_size = block.size() as u64 + TRANSACTION_LIST_OVERHEAD;
+        let entries = self.select_transactions(
+            blockchain.mempool(),
+            self.max_block_size.saturating_sub(base_size),
+        );
+        let transactions: Vec<TemplateTransaction> = entries
+            .iter()
+            .map(|entry| TemplateTransaction {
+                txid: entry.transaction.hash(),
+                fee: entry.fee,
+                size: entry.size,
+            })
+            .collect();
+        let total_fees = transactions.iter().map(|tx| tx.fee).sum();
+        block
+            .transactions
+            .extend(entries.into_iter().map(|entry| entry.transaction.clone()));
+        block.transactions[0].outputs[0].value =
+            blockchain.calculate_block_reward() + total_fees;
+        block.header.merkle_root = MerkleRoot::calculate(&block.transactions);
+        let size = block.size() as u64;
+        BlockTemplate {
+            block,
+            transactions,
+            total_fees,
+            size,
+        }
+    }
+    // Pick mempool transactions of at most `max_size` bytes in
+    // total by ancestor feerate: a transaction is only included
+    // together with its unconfirmed ancestors, and the package
+    // paying the most per byte goes first. This lets a child
+    // paying a high fee pull its parents into the block
+    // (child-pays-for-parent).
+    // Packages are kept up to date as their ancestors get
+    // selected, and picked from a queue by feerate.
+    // The result is ordered so parents come before children
+    pub fn select_transactions<'a>(
+        &self,
+        mempool: &'a Mempool,
+        max_size: u64,
+    ) -> Vec<&'a MempoolEntry> {
+        let entries: Vec<&MempoolEntry> = mempool.iter().collect();
+        let txids: Vec<Hash> =
+            entries.iter().map(|entry| entry.transaction.hash()).collect();
+        let positions: HashMap<Hash, usize> = txids
+            .iter()
+            .enumerate()
+            .map(|(idx, txid)| (*txid, idx))
+            .collect();
+        let ancestors: Vec<Vec<usize>> = txids
+            .iter()
+            .map(|txid| {
+                mempool
+                    .ancestors(txid)
+                    .iter()
+                    .filter_map(|ancestor| positions.get(ancestor).copied())
+                    .collect()
+            })
+            .collect();
+        let mut descendants: Vec<Vec<usize>> = vec![vec![]; entries.len()];
+        for (idx, ancestors) in ancestors.iter().enumerate() {
+            for &ancestor in ancestors {
+                descendants[ancestor].push(idx);
+            }
+        }
+        // package of every candidate: itself plus
+        // ancestors that are not selected yet
+        let mut packages: Vec<Package> = entries
+            .iter()
+            .enumerate()
+            .map(|(idx, entry)| Package {
+                fee: entry.fee
+                    + ancestors[idx].iter().map(|&a| entries[a].fee).sum::<u64>(),
+                size: entry.size
+                    + ancestors[idx].iter().map(|&a| entries[a].size).sum::<u64>(),
+                index: idx,
+            })
+            .collect();
+        let mut queue: BinaryHeap<Package> = packages.iter().copied().collect();
+        let mut selected = vec![false; entries.len()];
+        let mut size = 0;
+        while let Some(package) = queue.pop() {
+            let idx = package.index;
+            let current = packages[idx];
+            // selected with a descendant, or queued again
+            // since ancestors of it got selected
+            if selected[idx]
+                || (package.fee, package.size) != (current.fee, current.size)
+            {
+                continue;
+            }
+            if size + package.size > max_size {
+                // doesn't fit, smaller packages still might. It
+                // is queued again if ancestors of it get selected
+                continue;
+            }
+            size += package.size;
+            let added: Vec<usize> = ancestors[idx]
+                .iter()
+                .copied()
+                .chain([idx])
+                .filter(|&tx| !selected[tx])
+                .collect();
+            let mut updated = BTreeSet::new();
+            for tx in added {
+                selected[tx] = true;
+                for &descendant in &descendants[tx] {
+                    let package = &mut packages[descendant];
+                    package.fee -= entries[tx].fee;
+                    package.size -= entries[tx].size;
+                    updated.insert(descendant);
+                }
+            }
+            queue.extend(
+                updated
+                    .into_iter()
+                    .filter(|&descendant| !selected[descendant])
+                    .map(|descendant| packages[descendant]),
+            );
+        }
+        // mempool order keeps parents before children
+        entries
+            .into_iter()
+            .zip(selected)
+            .filter_map(|(entry, selected)| selected.then_some(entry))
+            .collect()
+    }
+}
+
+// A candidate in the selection queue, with the fee and size of
+// its package. The best paying one is the greatest, the earlier
+// arrival between equal feerates
+#[derive(Clone, Copy, Debug)]
+struct Package {
+    fee: u64,
+    size: u64,
+    // position of the candidate in mempool order
+    index: usize,
+}
+
+impl Ord for Package {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // compare fee / size without dividing
+        (self.fee as u128 * other.size as u128)
+            .cmp(&(other.fee as u128 * self.size as u128))
+            .then_with(|| other.index.cmp(&self.index))
+    }
+}
+
+impl PartialOrd for Package {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Package {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Package {}
@@ -1,35 +1,28 @@
 use std::{env, process::exit};
 
-use btclib::{crypto::PrivateKey, sha256::Hash, types::{Block, BlockHeader, Transaction, TransactionOutput}, util::{MerkleRoot, Saveable}};
-use chrono::Utc;
-use uuid::Uuid;
+use btclib::{params::ChainParams, util::Saveable};
 
 fn main() {
-  let path = if let Some(arg) = env::args().nth(1) {
-    arg
-  } else {
-    eprintln!("Usage: block_gen <block_file>");
+  let (Some(network), Some(path)) = (env::args().nth(1), env::args().nth(2)) else {
+    eprintln!("Usage: block_gen <network> <block_file>");
     exit(1);
   };
-  let private_key = PrivateKey::new_key();
-  let transactions = vec![Transaction::new(
-    vec![],
-    vec![TransactionOutput {
-      unique_id: Uuid::new_v4(),
-      value: btclib::INITIAL_REWARD * 10u64.pow(8),
-      pubkey: private_key.public_key(),
-    }],
-  )];
-  let merkle_root = MerkleRoot::calculate(&transactions);
-  let block = Block::new(
-    BlockHeader::new(
-      Utc::now(),
-      0,
-      Hash::zero(),
-      merkle_root,
-      btclib::MIN_TARGET,
-    ),
-    transactions,
-  );
+  let params = ChainParams::load(&network).unwrap_or_else(|e| {
+    eprintln!("Failed to load network {network}: {e}");
+    exit(1);
+  });
+  let mut block = params.genesis_block();
+  // custom networks may not have found their nonce yet
+  if !block.header.hash().matches_target(block.header.target()) {
+    block.header.nonce = 0;
+    while !block.header.hash().matches_target(block.header.target()) {
+      block.header.nonce += 1;
+    }
+    println!(
+      "genesis nonce of {} should be {}",
+      params.name, block.header.nonce
+    );
+  }
+  println!("genesis block: {}", block.hash());
   block.save_to_file(path).expect("Failed to save block");
-}
\ No newline at end of file
+}
@@ -1,6 +1,6 @@
 use std::{env, process::exit};
 
-use btclib::{crypto::PrivateKey, types::{Transaction, TransactionOutput}, util::Saveable};
+use btclib::{crypto::PrivateKey, params::ChainParams, types::{SpendingCondition, Transaction, TransactionOutput}, util::Saveable};
 use uuid::Uuid;
 
 fn main() {
@@ -15,8 +15,10 @@ fn main() {
     vec![],
     vec![TransactionOutput {
       unique_id: Uuid::new_v4(),
-      value: btclib::INITIAL_REWARD * 10u64.pow(8),
+      value: ChainParams::mainnet().block_reward(0),
       pubkey: private_key.public_key(),
+      condition: SpendingCondition::Signature,
+      timelock: None,
     }],
   );
   transaction
@@ -0,0 +1,184 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::error::{BtcError, Result};
+use crate::params::ChainParams;
+use crate::sha256::Hash;
+use crate::types::{Block, BlockHeader, Mempool, Transaction};
+use crate::util::MerkleRoot;
+
+// Compact blocks (as in BIP 152): most transactions of a new
+// block are in the receiver's mempool already, so a block is
+// relayed as its header and a short ID per transaction. The
+// receiver rebuilds the block from its mempool and asks only
+// for the transactions it doesn't have
+
+// short IDs are the low 48 bits of a hash
+const SHORT_ID_MASK: u64 = (1 << 48) - 1;
+
+/// A 48 bit identifier of a transaction in a compact block,
+/// salted with the block so collisions can't be planned
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ShortId(u64);
+
+impl ShortId {
+    // the short ID of a transaction in the compact
+    // block with short ID key `key`
+    pub fn new(key: &Hash, txid: &Hash) -> Self {
+        let bytes = Hash::hash(&(key, txid)).as_bytes();
+        let low = u64::from_le_bytes(bytes[..8].try_into().unwrap());
+        ShortId(low & SHORT_ID_MASK)
+    }
+}
+
+/// A transaction sent along with a compact block,
+/// because the receiver can't have it yet
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PrefilledTransaction {
+    /// Position of the transaction in the block
+    pub index: u32,
+    pub transaction: Transaction,
+}
+
+/// A block with its transactions replaced by short IDs,
+/// except for the coinbase
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CompactBlock {
+    pub header: BlockHeader,
+    /// Random number salting the short IDs
+    pub nonce: u64,
+    /// Short IDs of the transactions that are not prefilled,
+    /// in block order
+    pub short_ids: Vec<ShortId>,
+    /// Prefilled transactions, ordered by index
+    pub prefilled: Vec<PrefilledTransaction>,
+}
+
+impl CompactBlock {
+    // make a compact block with the coinbase prefilled
+    pub fn new(block: &Block, nonce: u64) -> Self {
+        let mut compact = CompactBlock {
+            header: block.header.clone(),
+            nonce,
+            short_ids: vec![],
+            prefilled: vec![],
+        };
+        let key = compact.short_id_key();
+        for (index, transaction) in block.transactions.iter().enumerate() {
+            if index == 0 {
+                compact.prefilled.push(PrefilledTransaction {
+                    index: 0,
+                    transaction: transaction.clone(),
+                });
+            } else {
+                compact.short_ids.push(ShortId::new(&key, &transaction.hash()));
+            }
+        }
+        compact
+    }
+    // the hash of the block
+    pub fn hash(&self) -> Hash {
+        self.header.hash()
+    }
+    // number of transactions in the block
+    pub fn transaction_count(&self) -> usize {
+        self.short_ids.len() + self.prefilled.len()
+    }
+    // key of the short IDs, committing to the header and nonce
+    fn short_id_key(&self) -> Hash {
+        Hash::hash(&(self.header.hash(), self.nonce))
+    }
+}
+
+/// A block being rebuilt from a compact block,
+/// with the transactions found so far
+#[derive(Clone, Debug)]
+pub struct PartialBlock {
+    header: BlockHeader,
+    transactions: Vec<Option<Transaction>>,
+}
+
+impl PartialBlock {
+    // Fill in the prefilled transactions and the ones in the
+    // mempool. Short IDs matching several mempool transactions
+    // are left missing. Compact blocks claiming more
+    // transactions than fit in a block are rejected before
+    // anything is allocated for them
+    pub fn new(
+        compact: CompactBlock,
+        mempool: &Mempool,
+        params: &ChainParams,
+    ) -> Result<Self> {
+        let count = compact.transaction_count();
+        let max_count = params.max_block_size as usize / crate::MIN_TRANSACTION_SIZE;
+        if count > max_count {
+            return Err(BtcError::BlockTooLarge);
+        }
+        let key = compact.short_id_key();
+        let mut transactions: Vec<Option<Transaction>> = vec![None; count];
+        let mut last_index = None;
+        for prefilled in compact.prefilled {
+            let index = prefilled.index as usize;
+            if index >= count || last_index.is_some_and(|last| index <= last) {
+                return Err(BtcError::InvalidCompactBlock);
+            }
+            transactions[index] = Some(prefilled.transaction);
+            last_index = Some(index);
+        }
+        let mut candidates: HashMap<ShortId, Option<&Hash>> = HashMap::new();
+        for txid in mempool.txids() {
+            candidates
+                .entry(ShortId::new(&key, txid))
+                .and_modify(|candidate| *candidate = None)
+                .or_insert(Some(txid));
+        }
+        let empty_slots = transactions
+            .iter_mut()
+            .filter(|transaction| transaction.is_none());
+        for (slot, short_id) in empty_slots.zip(&compact.short_ids) {
+            *slot = candidates
+                .get(short_id)
+                .copied()
+                .flatten()
+                .and_then(|txid| mempool.get(txid))
+                .map(|entry| entry.transaction.clone());
+        }
+        Ok(PartialBlock {
+            header: compact.header,
+            transactions,
+        })
+    }
+    // the hash of the block
+    pub fn hash(&self) -> Hash {
+        self.header.hash()
+    }
+    // indexes of the transactions still missing
+    pub fn missing(&self) -> Vec<u32> {
+        self.transactions
+            .iter()
+            .enumerate()
+            .filter(|(_, transaction)| transaction.is_none())
+            .map(|(index, _)| index as u32)
+            .collect()
+    }
+    // Complete the block with the missing transactions, in
+    // order. Fails if they don't fit or the block doesn't
+    // match its merkle root, e.g. when a short ID matched
+    // the wrong mempool transaction
+    pub fn fill(self, missing: Vec<Transaction>) -> Result<Block> {
+        let mut missing = missing.into_iter();
+        let transactions = self
+            .transactions
+            .into_iter()
+            .map(|transaction| transaction.or_else(|| missing.next()))
+            .collect::<Option<Vec<_>>>()
+            .ok_or(BtcError::InvalidCompactBlock)?;
+        if missing.next().is_some() {
+            return Err(BtcError::InvalidCompactBlock);
+        }
+        if MerkleRoot::calculate(&transactions) != self.header.merkle_root {
+            return Err(BtcError::InvalidMerkleRoot);
+        }
+        Ok(Block::new(self.header, transactions))
+    }
+}
@@ -14,17 +14,18 @@ use crate::sha256::Hash;
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Signature(pub ECDSASignature<Secp256k1>);
 impl Signature {
-    // sign a crate::types::TransactionOutput from its Sha256 hash
-    pub fn sign_output(output_hash: &Hash, private_key: &PrivateKey) -> Self {
+    // sign a hash, usually the signature hash of a transaction
+    // input (see crate::types::Transaction::signature_hash)
+    pub fn sign(hash: &Hash, private_key: &PrivateKey) -> Self {
         let signing_key = &private_key.0;
-        let signature = signing_key.sign(&output_hash.as_bytes());
+        let signature = signing_key.sign(&hash.as_bytes());
         Signature(signature)
     }
     // verify a signature
-    pub fn verify(&self, output_hash: &Hash, public_key: &PublicKey) -> bool {
+    pub fn verify(&self, hash: &Hash, public_key: &PublicKey) -> bool {
         public_key
             .0
-            .verify(&output_hash.as_bytes(), &self.0)
+            .verify(&hash.as_bytes(), &self.0)
             .is_ok()
     }
 }
@@ -74,7 +75,7 @@ impl PrivateKey {
         PrivateKey(SigningKey::random(&mut rand::thread_rng()))
     }
     pub fn public_key(&self) -> PublicKey {
-        PublicKey(self.0.verifying_key().clone())
+        PublicKey(*self.0.verifying_key())
     }
 }
 
@@ -7,6 +7,20 @@ pub enum BtcError {
     InvalidBlock,
     #[error("Invalid block header")]
     InvalidBlockHeader,
+    #[error("Block is too large")]
+    BlockTooLarge,
+    #[error("Parent of the block is unknown")]
+    UnknownParent,
+    #[error("Block is not the genesis block of the network")]
+    InvalidGenesisBlock,
+    #[error("Block target does not match the expected difficulty")]
+    UnexpectedTarget,
+    #[error("Block timestamp is not after the median time past")]
+    TimestampTooOld,
+    #[error("Block timestamp is too far in the future")]
+    TimestampTooFarInFuture,
+    #[error("Invalid compact block")]
+    InvalidCompactBlock,
     #[error("Invalid transaction input")]
     InvalidTransactionInput,
     #[error("Invalid transaction output")]
@@ -21,6 +35,96 @@ pub enum BtcError {
     InvalidPublicKey,
     #[error("Invalid private key")]
     InvalidPrivateKey,
+    #[error("Invalid spending condition")]
+    InvalidSpendingCondition,
+    #[error("Output is timelocked")]
+    OutputLocked,
+    #[error("Transaction has no inputs")]
+    EmptyInputs,
+    #[error("Transaction has no outputs")]
+    EmptyOutputs,
+    #[error("Transaction output has zero value")]
+    ZeroValueOutput,
+    #[error("Transaction spends the same output twice")]
+    DuplicateInput,
+    #[error("Transaction spends an unknown output")]
+    MissingInput,
+    #[error("Transaction values overflow")]
+    ValueOverflow,
+    #[error("Transaction outputs exceed its inputs")]
+    InsufficientInputValue,
+    #[error("Transaction is too large")]
+    TransactionTooLarge,
+    #[error("Transaction is already in the mempool")]
+    TransactionAlreadyInMempool,
+    #[error("Conflicting transaction does not signal replaceability")]
+    ReplacementNotSignaled,
+    #[error("Replacement does not pay a higher absolute fee")]
+    ReplacementFeeTooLow,
+    #[error("Replacement does not pay a higher feerate")]
+    ReplacementFeeRateTooLow,
+    #[error("Replacement would evict too many transactions")]
+    TooManyReplacements,
+    #[error("Replacement spends an output of a transaction it replaces")]
+    ReplacementSpendsConflict,
+    #[error("Replacement spends an unconfirmed output the transactions it replaces don't")]
+    ReplacementAddsUnconfirmedInput,
+    #[error("Transaction fee rate is below the mempool minimum")]
+    FeeRateTooLow,
+    #[error("Mempool is full")]
+    MempoolFull,
 }
 
 pub type Result<T> = std::result::Result<T, BtcError>;
+
+/// Errors talking to a peer over the wire protocol
+#[derive(Error, Debug)]
+pub enum NetworkError {
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Timed out waiting for the peer")]
+    Timeout,
+    #[error("Unknown message kind {0}")]
+    UnknownMessageKind(u8),
+    #[error("Message of kind {kind} has {size} bytes, at most {max} are allowed")]
+    MessageTooLarge { kind: u8, size: u32, max: u32 },
+    #[error("Message does not match its kind {0}")]
+    MessageKindMismatch(u8),
+    #[error("Failed to encode message: {0}")]
+    Encode(String),
+    #[error("Failed to decode message: {0}")]
+    Decode(String),
+    #[error("Peer is on another network (magic {theirs:#010x}, expected {ours:#010x})")]
+    WrongNetwork { theirs: u32, ours: u32 },
+    #[error("Peer speaks protocol version {0}, which is not supported")]
+    UnsupportedVersion(u32),
+    #[error("Connected to ourselves")]
+    SelfConnection,
+    #[error("Unexpected message: {0}")]
+    UnexpectedMessage(&'static str),
+    #[error("Invalid block: {0}")]
+    InvalidBlock(BtcError),
+}
+
+impl NetworkError {
+    // How much the error counts against the peer that caused
+    // it, peers reaching network::BAN_SCORE get banned.
+    // Broken connections and peers on another network or
+    // version are only disconnected, garbage is banned
+    pub fn misbehavior_score(&self) -> u32 {
+        match self {
+            NetworkError::Io(_)
+            | NetworkError::Timeout
+            | NetworkError::Encode(_)
+            | NetworkError::WrongNetwork { .. }
+            | NetworkError::UnsupportedVersion(_)
+            | NetworkError::SelfConnection => 0,
+            NetworkError::UnexpectedMessage(_) => 20,
+            NetworkError::UnknownMessageKind(_)
+            | NetworkError::MessageTooLarge { .. }
+            | NetworkError::MessageKindMismatch(_)
+            | NetworkError::Decode(_)
+            | NetworkError::InvalidBlock(_) => 100,
+        }
+    }
+}
@@ -1,33 +1,56 @@
-use serde::{Deserialize, Serialize};
-use uint::construct_uint;
-construct_uint! {
-    // Construct an unsigned 256-bit integer
-    // consisting of 4 x 64-bit words
-    #[derive(Serialize, Deserialize)]
-    pub struct U256(4);
+// the code generated by construct_uint trips clippy
+#[allow(clippy::manual_div_ceil)]
+mod u256 {
+    use serde::{Deserialize, Serialize};
+    use uint::construct_uint;
+    construct_uint! {
+        // Construct an unsigned 256-bit integer
+        // consisting of 4 x 64-bit words
+        #[derive(Serialize, Deserialize)]
+        pub struct U256(4);
+    }
 }
-// initial reward in bitcoin - multiply by 10^8 to get satoshis
-pub const INITIAL_REWARD: u64 = 50;
-// halving interval in blocks
-pub const HALVING_INTERVAL: u64 = 210;
-// ideal block time in seconds
-pub const IDEAL_BLOCK_TIME: u64 = 10;
-// minimum target
-pub const MIN_TARGET: U256 = U256([
-    0xFFFF_FFFF_FFFF_FFFF,
-    0xFFFF_FFFF_FFFF_FFFF,
-    0xFFFF_FFFF_FFFF_FFFF,
-    0x0000_FFFF_FFFF_FFFF,
-]);
-// difficulty update interval in blocks
-pub const DIFFICULTY_UPDATE_INTERVAL: u64 = 50;
+pub use u256::U256;
 // maximum mempool transaction age in seconds
 pub const MAX_MEMPOOL_TRANSACTION_AGE: u64 = 600;
-// maximum amount of transactions allowed in a block
-pub const BLOCK_TRANSACTION_CAP: usize = 20;
+// maximum size of a serialized transaction in bytes
+pub const MAX_TRANSACTION_SIZE: usize = 100_000;
+// lower bound of the size of a serialized transaction in
+// bytes: each has an output with a public key and unique ID
+pub const MIN_TRANSACTION_SIZE: usize = 100;
+// minimum fee per 1000 bytes for transactions to be
+// accepted into the mempool
+pub const MIN_RELAY_FEE_RATE: u64 = 1000;
+// fee per 1000 bytes a replacement has to pay on top of the
+// fees of the transactions it evicts from the mempool, and
+// the step the mempool minimum fee rate rises by when full
+pub const INCREMENTAL_RELAY_FEE: u64 = 1000;
+// default mempool size limit in bytes
+pub const MAX_MEMPOOL_SIZE: u64 = 300_000_000;
+// default limit on the number of mempool transactions
+pub const MAX_MEMPOOL_TRANSACTIONS: usize = 100_000;
+// seconds it takes the raised mempool minimum fee rate to halve
+pub const MEMPOOL_MIN_FEE_HALF_LIFE: u64 = 12 * 60 * 60;
+// maximum number of mempool transactions a single
+// replacement may evict, descendants included
+pub const MAX_REPLACEMENT_EVICTIONS: usize = 100;
+// side branches forking off the active chain more
+// than this many blocks below the tip are forgotten
+pub const MAX_FORK_DEPTH: usize = 100;
+// number of blocks whose median timestamp
+// a new block's timestamp has to exceed
+pub const MEDIAN_TIME_SPAN: usize = 11;
+// how far a block's timestamp may be ahead
+// of the local clock, in seconds
+pub const MAX_FUTURE_BLOCK_TIME: u64 = 2 * 60 * 60;
+pub mod assembler;
+pub mod compact;
 pub mod crypto;
 pub mod error;
 pub mod network;
+pub mod params;
+pub mod pow;
 pub mod sha256;
 pub mod types;
 pub mod util;
+pub mod validation;
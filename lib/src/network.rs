@@ -1,15 +1,211 @@
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
-use std::io::{Error as IoError, Read, Write};
+use tokio::time::timeout;
+use std::io::{Read, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
 
-use crate::{crypto::PublicKey, types::{Block, Transaction, TransactionOutput}};
+use crate::{assembler::BlockTemplate, compact::CompactBlock, crypto::PublicKey, error::NetworkError, params::{ChainParams, DEFAULT_MAX_BLOCK_SIZE}, sha256::Hash, types::{Block, BlockHeader, OutPoint, Transaction, TransactionOutput, TxLocation}, MAX_TRANSACTION_SIZE, MIN_TRANSACTION_SIZE};
+
+// maximum amount of headers sent in a single Headers message
+pub const MAX_HEADERS: usize = 2000;
+// maximum amount of addresses sent in a single Addr message
+pub const MAX_ADDR: usize = 1000;
+// maximum amount of items in a single Inv, GetData
+// or NotFound message
+pub const MAX_INV: usize = 1000;
+// maximum amount of entries sent in a single History message
+pub const MAX_HISTORY: usize = 1000;
+// version of the wire protocol spoken by this library
+pub const PROTOCOL_VERSION: u32 = 5;
+// oldest protocol version we still talk to. Version 5
+// made headers store their target in compact form, so
+// older peers hash our blocks differently
+pub const MIN_PROTOCOL_VERSION: u32 = 5;
+// services bit of peers that store and serve the blockchain
+pub const NODE_NETWORK: u64 = 1 << 0;
+// misbehavior score at which a peer gets banned
+pub const BAN_SCORE: u32 = 100;
+
+// frame header: message kind and length of the encoded message
+const FRAME_HEADER_SIZE: usize = 5;
+// largest encoded message of any kind
+pub const MAX_MESSAGE_SIZE: u32 = 32_000_000;
+// room around a block in the messages carrying one
+const BLOCK_MESSAGE_OVERHEAD: u32 = 10_000;
+// largest UTXOs or UTXOUpdate message. Nodes send
+// the most valuable outputs of a key that fit
+pub const MAX_UTXO_MESSAGE_SIZE: u32 = 4_000_000;
+// requests and replies without a payload to speak of
+const MAX_CONTROL_MESSAGE_SIZE: u32 = 1_000;
+// block size limit of the network we are on, which the
+// messages carrying blocks are capped by
+static MAX_BLOCK_SIZE: AtomicU64 = AtomicU64::new(DEFAULT_MAX_BLOCK_SIZE);
+
+// Cap the messages carrying blocks for the blocks of a
+// network, before talking to peers on it
+pub fn set_max_block_size(params: &ChainParams) {
+  MAX_BLOCK_SIZE.store(params.max_block_size, Ordering::Relaxed);
+}
+
+// time a peer has to complete the handshake
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+// time a peer has to send the rest of a message once its
+// frame header arrived, so slow peers can't hold us up
+pub const FRAME_TIMEOUT: Duration = Duration::from_secs(30);
+// time a peer has to answer one of our requests
+pub const RESPONSE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Something nodes announce to each other and hand out on request
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize, Serialize)]
+pub enum InventoryItem {
+  Transaction(Hash),
+  Block(Hash),
+  /// A block sent as a CompactBlock, only asked for in GetData
+  CompactBlock(Hash),
+}
+
+/// A confirmed transaction funding or spending a public key
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct HistoryEntry {
+  pub txid: Hash,
+  /// Height of the block containing the transaction
+  pub height: u64,
+  /// Timestamp of that block
+  pub time: DateTime<Utc>,
+  /// Sum of the outputs paying the key
+  pub received: u64,
+  /// Sum of the outputs of the key the transaction spends
+  pub sent: u64,
+}
+
+/// Changes to the UTXOs of a public key, the response to
+/// FetchUTXOsSince
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct UTXOUpdate {
+  /// Height and hash of the tip the update leads to,
+  /// none if the node has no blocks
+  pub tip: Option<(u64, Hash)>,
+  /// Median time past of the tip, which the time locks of
+  /// outputs spent in the next block are checked against
+  #[serde(default)]
+  pub median_time_past: Option<DateTime<Utc>>,
+  /// Whether `created` holds all confirmed UTXOs of the key
+  /// rather than the changes, because the block asked about
+  /// is no longer on the active chain
+  pub full: bool,
+  /// Confirmed outputs of the key created since and still unspent
+  pub created: Vec<(OutPoint, TransactionOutput)>,
+  /// Confirmed outputs of the key spent since
+  pub spent: Vec<OutPoint>,
+  /// Outputs of the key created by mempool transactions
+  pub unconfirmed: Vec<(OutPoint, TransactionOutput)>,
+  /// Outputs of the key spent by mempool transactions
+  pub unconfirmed_spent: Vec<OutPoint>,
+}
+
+/// What a peer tells about itself when a connection is opened
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Version {
+  /// Magic number of the network the peer is on
+  pub magic: u32,
+  /// Wire protocol version of the peer
+  pub version: u32,
+  /// Bitmap of the services the peer offers
+  pub services: u64,
+  /// Height of the peer's active chain
+  pub best_height: u64,
+  /// Software of the peer, e.g. "/node:0.1.0/"
+  pub user_agent: String,
+  /// Port the peer accepts connections on, if any
+  #[serde(default)]
+  pub listen_port: Option<u16>,
+  /// Random number to detect connections to ourselves
+  #[serde(default)]
+  pub nonce: u64,
+}
+
+impl Version {
+  pub fn new(
+    params: &ChainParams,
+    services: u64,
+    best_height: u64,
+    user_agent: impl Into<String>,
+  ) -> Self {
+    Version {
+      magic: params.magic,
+      version: PROTOCOL_VERSION,
+      services,
+      best_height,
+      user_agent: user_agent.into(),
+      listen_port: None,
+      nonce: rand::random(),
+    }
+  }
+  // whether we can talk to a peer announcing `self`,
+  // with `ours` being what we announced
+  pub fn check_compatible(
+    &self,
+    ours: &Version,
+  ) -> Result<(), NetworkError> {
+    if self.magic != ours.magic {
+      return Err(NetworkError::WrongNetwork {
+        theirs: self.magic,
+        ours: ours.magic,
+      });
+    }
+    if self.version < MIN_PROTOCOL_VERSION {
+      return Err(NetworkError::UnsupportedVersion(self.version));
+    }
+    if self.nonce == ours.nonce {
+      return Err(NetworkError::SelfConnection);
+    }
+    Ok(())
+  }
+}
+
+// Open a connection: both sides send their Version, check the
+// one they receive and acknowledge it with VerAck. Any other
+// message before the VerAck fails the handshake, and so does
+// a peer taking longer than HANDSHAKE_TIMEOUT.
+// Returns what the peer announced
+pub async fn handshake(
+  stream: &mut (impl AsyncRead + AsyncWrite + Unpin),
+  ours: &Version,
+) -> Result<Version, NetworkError> {
+  Message::Version(ours.clone()).send_async(stream).await?;
+  let theirs =
+    match Message::receive_async_timeout(stream, HANDSHAKE_TIMEOUT)
+      .await?
+    {
+      Message::Version(theirs) => theirs,
+      _ => {
+        return Err(NetworkError::UnexpectedMessage(
+          "expected Version as the first message",
+        ))
+      }
+    };
+  theirs.check_compatible(ours)?;
+  Message::VerAck.send_async(stream).await?;
+  match Message::receive_async_timeout(stream, HANDSHAKE_TIMEOUT).await? {
+    Message::VerAck => Ok(theirs),
+    _ => Err(NetworkError::UnexpectedMessage(
+      "expected VerAck after Version",
+    )),
+  }
+}
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub enum Message {
+  /// Sent by both sides first thing on a new connection
+  Version(Version),
+  /// Acknowledges the peer's Version, completing the handshake
+  VerAck,
   /// Fetch all UTXOs belonging to a public key
   FetchUTXOs(PublicKey),
   /// UTXOs belonging to a public key. Bool determines if marked
-  UTXOs(Vec<(TransactionOutput, bool)>),
+  UTXOs(Vec<(OutPoint, TransactionOutput, bool)>),
   /// Send a transaction to the network
   SubmitTransaction(Transaction),
   /// Broadcast a new transaction to other nodes
@@ -18,8 +214,8 @@ pub enum Message {
   /// with the coinbase transaction paying the specified
   /// public key
   FetchTemplate(PublicKey),
-  /// The template
-  Template(Block),
+  /// The template, with the fees of its transactions
+  Template(BlockTemplate),
   /// Ask the node to validate a block template.
   /// This is to prevent the node from mining an invalid
   /// block (e.g. if one has been found in the meantime,
@@ -39,66 +235,259 @@ pub enum Message {
   AskDifference(u32),
   /// This is the response to AskDifference
   Difference(i32),
-  /// Ask a node to send a block with the specified height
+  /// Ask a node to send a block with the specified height.
+  /// The response is a NewBlock, or an empty NotFound if the
+  /// node's active chain is not that long
   FetchBlock(usize),
   /// Broadcast a new block to other nodes
   NewBlock(Block),
+  /// Ask a node for the headers of its active chain following
+  /// the first block of the locator that it knows about
+  GetHeaders(Vec<Hash>),
+  /// This is the response to GetHeaders, with at most
+  /// MAX_HEADERS headers
+  Headers(Vec<BlockHeader>),
+  /// Advertise at most MAX_ADDR addresses of nodes
+  /// accepting connections, no response expected
+  Addr(Vec<String>),
+  /// Announce at most MAX_INV transactions and blocks. The
+  /// response is a GetData with the ones the peer wants. A node
+  /// announcing to a peer that connected to it expects no
+  /// response, the peer sends its GetData as a request
+  Inv(Vec<InventoryItem>),
+  /// Ask for transactions and blocks. Every item is answered in
+  /// order with a NewTransaction, a NewBlock, a CompactBlock
+  /// or a NotFound
+  GetData(Vec<InventoryItem>),
+  /// Items of a GetData that are not available
+  NotFound(Vec<InventoryItem>),
+  /// A block with short IDs instead of most transactions.
+  /// The receiver answers with a GetBlockTxn for the
+  /// transactions it can't find in its mempool
+  CompactBlock(CompactBlock),
+  /// Ask for transactions of a block by their indexes, in
+  /// increasing order. The response is a BlockTxn, or a
+  /// NotFound for an unknown block. An empty request is
+  /// not answered
+  GetBlockTxn { block: Hash, indexes: Vec<u32> },
+  /// Transactions of a block asked for with GetBlockTxn
+  BlockTxn { block: Hash, transactions: Vec<Transaction> },
+  /// Ask for a transaction by its txid. The response is a
+  /// TransactionInfo, or a NotFound if the node has no such
+  /// transaction in its mempool or its transaction index
+  FetchTransaction(Hash),
+  /// A transaction, with where it is on the active chain
+  /// unless it's unconfirmed
+  TransactionInfo {
+    transaction: Transaction,
+    location: Option<TxLocation>,
+    confirmations: u64,
+  },
+  /// Ask for the confirmed transactions funding or spending a
+  /// public key, oldest first, skipping the first `offset`.
+  /// The response is a History with at most `limit` and at
+  /// most MAX_HISTORY entries
+  FetchHistory { pubkey: PublicKey, offset: u64, limit: u32 },
+  /// Transactions of a key asked for with FetchHistory,
+  /// and how many the key has in total
+  History { entries: Vec<HistoryEntry>, total: u64 },
+  /// Ask for the changes to the UTXOs of a public key since
+  /// the block with the given height and hash, e.g. the tip
+  /// of the last sync, or for all of them without a block.
+  /// The response is a UTXOUpdate
+  FetchUTXOsSince {
+    pubkey: PublicKey,
+    since: Option<(u64, Hash)>,
+  },
+  /// Changes to the UTXOs of a key asked for with FetchUTXOsSince
+  UTXOUpdate(UTXOUpdate),
 }
 
-// We are going to use length-prefixed encoding for message
-// And we are going to use ciborium (CBOR) for serialization
+// Every message is sent in a frame: a byte with the kind of
+// the message, its length as big endian u32 and the message
+// encoded with ciborium (CBOR). The kind comes first so the
+// length can be checked against the maximum size of that kind
+// before anything gets allocated
 impl Message {
-  pub fn encode(
-    &self,
-  ) -> Result<Vec<u8>, ciborium::ser::Error<IoError>>
-  {
+  // number identifying the kind of message in the frame header
+  pub fn kind(&self) -> u8 {
+    use Message::*;
+    match self {
+      Version(_) => 0,
+      VerAck => 1,
+      FetchUTXOs(_) => 2,
+      UTXOs(_) => 3,
+      SubmitTransaction(_) => 4,
+      NewTransaction(_) => 5,
+      FetchTemplate(_) => 6,
+      Template(_) => 7,
+      ValidateTemplate(_) => 8,
+      TemplateValidity(_) => 9,
+      SubmitTemplate(_) => 10,
+      DiscoverNodes => 11,
+      NodeList(_) => 12,
+      AskDifference(_) => 13,
+      Difference(_) => 14,
+      FetchBlock(_) => 15,
+      NewBlock(_) => 16,
+      GetHeaders(_) => 17,
+      Headers(_) => 18,
+      Addr(_) => 19,
+      Inv(_) => 20,
+      GetData(_) => 21,
+      NotFound(_) => 22,
+      CompactBlock(_) => 23,
+      GetBlockTxn { .. } => 24,
+      BlockTxn { .. } => 25,
+      FetchTransaction(_) => 26,
+      TransactionInfo { .. } => 27,
+      FetchHistory { .. } => 28,
+      History { .. } => 29,
+      FetchUTXOsSince { .. } => 30,
+      UTXOUpdate(_) => 31,
+    }
+  }
+  // largest encoded size accepted for a kind of message,
+  // None for kinds we don't know
+  pub fn max_size(kind: u8) -> Option<u32> {
+    let max_block_size = MAX_BLOCK_SIZE
+      .load(Ordering::Relaxed)
+      .min(MAX_MESSAGE_SIZE as u64) as u32;
+    let max = match kind {
+      // version, verack
+      0 | 1 => MAX_CONTROL_MESSAGE_SIZE,
+      // public keys
+      2 | 6 => MAX_CONTROL_MESSAGE_SIZE,
+      // utxos of a key
+      3 | 31 => MAX_UTXO_MESSAGE_SIZE,
+      // transactions
+      4 | 5 | 27 => MAX_TRANSACTION_SIZE as u32 + MAX_CONTROL_MESSAGE_SIZE,
+      // blocks, or the transactions of one
+      8 | 10 | 16 | 23 | 25 => max_block_size + BLOCK_MESSAGE_OVERHEAD,
+      // a template lists the fee of every transaction
+      // too, which encodes to less than the transaction
+      7 => max_block_size.saturating_mul(2) + BLOCK_MESSAGE_OVERHEAD,
+      // an index of a transaction in a block
+      // encodes to at most 5 bytes
+      24 => {
+        max_block_size / MIN_TRANSACTION_SIZE as u32 * 5
+          + MAX_CONTROL_MESSAGE_SIZE
+      }
+      // small requests and replies
+      9 | 11 | 13 | 14 | 15 | 26 | 28 | 30 => MAX_CONTROL_MESSAGE_SIZE,
+      // node addresses
+      12 | 19 => 100_000,
+      // block locator
+      17 => 100_000,
+      // an inventory item encodes to less than 100 bytes
+      20..=22 => MAX_INV as u32 * 100 + MAX_CONTROL_MESSAGE_SIZE,
+      // a history entry encodes to less than 200 bytes
+      29 => MAX_HISTORY as u32 * 200 + MAX_CONTROL_MESSAGE_SIZE,
+      // a header encodes to about 200 bytes
+      18 => MAX_HEADERS as u32 * 300 + MAX_CONTROL_MESSAGE_SIZE,
+      _ => return None,
+    };
+    Some(max)
+  }
+  pub fn encode(&self) -> Result<Vec<u8>, NetworkError> {
     let mut bytes = Vec::new();
-    ciborium::into_writer(self, &mut bytes)?;
+    ciborium::into_writer(self, &mut bytes)
+      .map_err(|e| NetworkError::Encode(e.to_string()))?;
     Ok(bytes)
   }
-  pub fn decode(
-    data: &[u8],
-  ) -> Result<Self, ciborium::de::Error<IoError>> {
+  pub fn decode(data: &[u8]) -> Result<Self, NetworkError> {
     ciborium::from_reader(data)
+      .map_err(|e| NetworkError::Decode(e.to_string()))
+  }
+  // the frame header and encoded message
+  fn frame(&self) -> Result<Vec<u8>, NetworkError> {
+    let bytes = self.encode()?;
+    let kind = self.kind();
+    let size = Self::check_size(kind, bytes.len() as u64)?;
+    let mut frame = Vec::with_capacity(FRAME_HEADER_SIZE + bytes.len());
+    frame.push(kind);
+    frame.extend_from_slice(&size.to_be_bytes());
+    frame.extend_from_slice(&bytes);
+    Ok(frame)
+  }
+  // kind and length of a frame header,
+  // if the length is allowed for the kind
+  fn parse_header(
+    header: [u8; FRAME_HEADER_SIZE],
+  ) -> Result<(u8, usize), NetworkError> {
+    let kind = header[0];
+    let size = u32::from_be_bytes([header[1], header[2], header[3], header[4]]);
+    let size = Self::check_size(kind, size as u64)?;
+    Ok((kind, size as usize))
+  }
+  fn check_size(kind: u8, size: u64) -> Result<u32, NetworkError> {
+    let max = Self::max_size(kind)
+      .ok_or(NetworkError::UnknownMessageKind(kind))?;
+    if size > max as u64 {
+      return Err(NetworkError::MessageTooLarge {
+        kind,
+        size: size.min(u32::MAX as u64) as u32,
+        max,
+      });
+    }
+    Ok(size as u32)
+  }
+  // decode a message and check it is of the kind
+  // announced in its frame header
+  fn decode_frame(kind: u8, data: &[u8]) -> Result<Self, NetworkError> {
+    let message = Self::decode(data)?;
+    if message.kind() != kind {
+      return Err(NetworkError::MessageKindMismatch(kind));
+    }
+    Ok(message)
   }
   pub fn send(
     &self,
     stream: &mut impl Write,
-  ) -> Result<(), ciborium::ser::Error<IoError>> {
-    let bytes = self.encode()?;
-    let len = bytes.len() as u64;
-    stream.write_all(&len.to_be_bytes())?;
-    stream.write_all(&bytes)?;
+  ) -> Result<(), NetworkError> {
+    stream.write_all(&self.frame()?)?;
     Ok(())
   }
   pub fn receive(
       stream: &mut impl Read,
-    ) -> Result<Self, ciborium::de::Error<IoError>> {
-    let mut len_bytes = [0u8; 8];
-    stream.read_exact(&mut len_bytes)?;
-    let len = u64::from_be_bytes(len_bytes) as usize;
+    ) -> Result<Self, NetworkError> {
+    let mut header = [0u8; FRAME_HEADER_SIZE];
+    stream.read_exact(&mut header)?;
+    let (kind, len) = Self::parse_header(header)?;
     let mut data = vec![0u8; len];
     stream.read_exact(&mut data)?;
-    Self::decode(&data)
+    Self::decode_frame(kind, &data)
   }
   pub async fn send_async(
     &self,
     stream: &mut (impl AsyncWrite + Unpin),
-  ) -> Result<(), ciborium::ser::Error<IoError>> {
-    let bytes = self.encode()?;
-    let len = bytes.len() as u64;
-    stream.write_all(&len.to_be_bytes()).await?;
-    stream.write_all(&bytes).await?;
+  ) -> Result<(), NetworkError> {
+    stream.write_all(&self.frame()?).await?;
     Ok(())
   }
+  // Receive the next message, waiting as long as it takes for
+  // it to start. The rest of the message has to follow within
+  // FRAME_TIMEOUT
   pub async fn receive_async(
     stream: &mut (impl AsyncRead + Unpin),
-  ) -> Result<Self, ciborium::de::Error<IoError>> {
-    let mut len_bytes = [0u8; 8];
-    stream.read_exact(&mut len_bytes).await?;
-    let len = u64::from_be_bytes(len_bytes) as usize;
+  ) -> Result<Self, NetworkError> {
+    let mut header = [0u8; FRAME_HEADER_SIZE];
+    stream.read_exact(&mut header).await?;
+    let (kind, len) = Self::parse_header(header)?;
     let mut data = vec![0u8; len];
-    stream.read_exact(&mut data).await?;
-    Self::decode(&data)
+    timeout(FRAME_TIMEOUT, stream.read_exact(&mut data))
+      .await
+      .map_err(|_| NetworkError::Timeout)??;
+    Self::decode_frame(kind, &data)
+  }
+  // receive the next message, which has to
+  // arrive completely within `duration`
+  pub async fn receive_async_timeout(
+    stream: &mut (impl AsyncRead + Unpin),
+    duration: Duration,
+  ) -> Result<Self, NetworkError> {
+    timeout(duration, Self::receive_async(stream))
+      .await
+      .map_err(|_| NetworkError::Timeout)?
   }
-}
\ No newline at end of file
+}
@@ -0,0 +1,266 @@
+use chrono::{DateTime, Utc};
+use ecdsa::VerifyingKey;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use std::io::{
+  Error as IoError, ErrorKind as IoErrorKind, Read,
+  Result as IoResult, Write,
+};
+use std::path::Path;
+
+use crate::crypto::PublicKey;
+use crate::pow;
+use crate::sha256::Hash;
+use crate::types::{Block, BlockHeader, SpendingCondition, Transaction, TransactionOutput};
+use crate::util::{MerkleRoot, Saveable};
+use crate::U256;
+
+// public key the coinbases of the predefined genesis blocks pay.
+// Its private key was thrown away, so their reward can't be spent
+const GENESIS_PUBKEY: &str =
+    "0209e6916ad181c8ee171b59060b8cb3376d824506c49d692b2b724e1791b853fd";
+// nonces of the predefined genesis blocks
+const MAINNET_NONCE: u64 = 5_070;
+const TESTNET_NONCE: u64 = 6_591;
+const REGTEST_NONCE: u64 = 2;
+// block size limit of the predefined networks
+pub const DEFAULT_MAX_BLOCK_SIZE: u64 = 1_000_000;
+
+/// Consensus parameters of a chain. Nodes, miners and
+/// wallets on the same network have to agree on them
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ChainParams {
+    /// Name of the network
+    pub name: String,
+    /// Magic number identifying the network in the
+    /// wire protocol handshake
+    pub magic: u32,
+    /// Port nodes of this network listen on by default
+    pub default_port: u16,
+    /// Initial block reward in bitcoin, multiply by 10^8
+    /// to get satoshis
+    pub initial_reward: u64,
+    /// Halving interval in blocks
+    pub halving_interval: u64,
+    /// Ideal block time in seconds
+    pub ideal_block_time: u64,
+    /// Minimum target, i.e. the lowest difficulty allowed
+    #[serde(with = "hex_target")]
+    pub min_target: U256,
+    /// Difficulty update interval in blocks
+    pub difficulty_update_interval: u64,
+    /// Keep the target at min_target instead of adjusting it
+    #[serde(default)]
+    pub no_retargeting: bool,
+    /// Maximum serialized size of a block in bytes
+    pub max_block_size: u64,
+    /// What the first block of the chain is made of
+    pub genesis: Genesis,
+}
+
+/// The parts of a network's genesis block that are not implied
+/// by the other parameters. Every node rebuilds the block from
+/// them, so they all start from the same one
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Genesis {
+    /// Timestamp of the block
+    pub timestamp: DateTime<Utc>,
+    /// Nonce making the block meet min_target
+    pub nonce: u64,
+    /// Public key the coinbase pays, as hex encoded SEC1
+    pub pubkey: String,
+}
+
+impl Default for ChainParams {
+    fn default() -> Self {
+        Self::mainnet()
+    }
+}
+
+impl ChainParams {
+    pub fn mainnet() -> Self {
+        ChainParams {
+            name: "mainnet".to_string(),
+            magic: 0xF9BE_B4D9,
+            default_port: 9000,
+            initial_reward: 50,
+            halving_interval: 210,
+            ideal_block_time: 10,
+            min_target: U256([
+                0xFFFF_FFFF_FFFF_FFFF,
+                0xFFFF_FFFF_FFFF_FFFF,
+                0xFFFF_FFFF_FFFF_FFFF,
+                0x0000_FFFF_FFFF_FFFF,
+            ]),
+            difficulty_update_interval: 50,
+            no_retargeting: false,
+            max_block_size: DEFAULT_MAX_BLOCK_SIZE,
+            genesis: Genesis {
+                timestamp: DateTime::from_timestamp(1_735_689_600, 0)
+                    .expect("BUG: invalid genesis timestamp"),
+                nonce: MAINNET_NONCE,
+                pubkey: GENESIS_PUBKEY.to_string(),
+            },
+        }
+    }
+    // a shared test network, with the mainnet rules
+    // but easier blocks
+    pub fn testnet() -> Self {
+        ChainParams {
+            name: "testnet".to_string(),
+            magic: 0x0B11_0907,
+            default_port: 19000,
+            min_target: U256([
+                0xFFFF_FFFF_FFFF_FFFF,
+                0xFFFF_FFFF_FFFF_FFFF,
+                0xFFFF_FFFF_FFFF_FFFF,
+                0x000F_FFFF_FFFF_FFFF,
+            ]),
+            genesis: Genesis {
+                nonce: TESTNET_NONCE,
+                ..Self::mainnet().genesis
+            },
+            ..Self::mainnet()
+        }
+    }
+    // a local network for testing, where blocks can be
+    // mined instantly and the reward halves quickly
+    pub fn regtest() -> Self {
+        ChainParams {
+            name: "regtest".to_string(),
+            magic: 0xFABF_B5DA,
+            default_port: 29000,
+            halving_interval: 150,
+            ideal_block_time: 1,
+            min_target: U256([
+                0xFFFF_FFFF_FFFF_FFFF,
+                0xFFFF_FFFF_FFFF_FFFF,
+                0xFFFF_FFFF_FFFF_FFFF,
+                0x7FFF_FFFF_FFFF_FFFF,
+            ]),
+            no_retargeting: true,
+            genesis: Genesis {
+                nonce: REGTEST_NONCE,
+                ..Self::mainnet().genesis
+            },
+            ..Self::mainnet()
+        }
+    }
+    // one of the predefined networks by name
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "mainnet" => Some(Self::mainnet()),
+            "testnet" => Some(Self::testnet()),
+            "regtest" => Some(Self::regtest()),
+            _ => None,
+        }
+    }
+    // a predefined network, or a custom one
+    // from the TOML file at `network`
+    pub fn load(network: &str) -> IoResult<Self> {
+        match Self::from_name(network) {
+            Some(params) => Ok(params),
+            None => Self::load_from_file(Path::new(network)),
+        }
+    }
+    // The first block of the chain. Its coinbase pays the
+    // initial reward to the genesis public key
+    pub fn genesis_block(&self) -> Block {
+        let pubkey = self
+            .genesis_pubkey()
+            .expect("BUG: genesis public key is checked on load");
+        let transactions = vec![Transaction::new(
+            vec![],
+            vec![TransactionOutput {
+                value: self.block_reward(0),
+                unique_id: Uuid::nil(),
+                pubkey,
+                condition: SpendingCondition::Signature,
+                timelock: None,
+            }],
+        )];
+        let header = BlockHeader::new(
+            self.genesis.timestamp,
+            self.genesis.nonce,
+            Hash::zero(),
+            MerkleRoot::calculate(&transactions),
+            pow::target_to_bits(self.min_target),
+        );
+        Block::new(header, transactions)
+    }
+    fn genesis_pubkey(&self) -> Option<PublicKey> {
+        let bytes = hex::decode(&self.genesis.pubkey).ok()?;
+        VerifyingKey::from_sec1_bytes(&bytes).ok().map(PublicKey)
+    }
+    // block reward in satoshis at the given height
+    pub fn block_reward(&self, height: u64) -> u64 {
+        let halvings = height / self.halving_interval;
+        (self.initial_reward * 10u64.pow(8))
+            .checked_shr(halvings.min(u32::MAX as u64) as u32)
+            .unwrap_or(0)
+    }
+}
+
+// targets are written as hex strings, TOML
+// integers are too small to hold them
+mod hex_target {
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    use crate::U256;
+
+    pub fn serialize<S: Serializer>(
+        target: &U256,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&format!("{target:#x}"))
+    }
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<U256, D::Error> {
+        let target = String::deserialize(deserializer)?;
+        let digits = target.trim_start_matches("0x");
+        U256::from_str_radix(digits, 16)
+            .map_err(|_| D::Error::custom("invalid target"))
+    }
+}
+
+// save and load expecting TOML as format
+impl Saveable for ChainParams {
+  fn load<I: Read>(mut reader: I) -> IoResult<Self> {
+    let mut toml = String::new();
+    reader.read_to_string(&mut toml)?;
+    let params: ChainParams = toml::from_str(&toml).map_err(|e| {
+      IoError::new(
+        IoErrorKind::InvalidData,
+        format!("Failed to deserialize ChainParams: {e}"),
+      )
+    })?;
+    // these are divided by
+    if params.halving_interval == 0
+      || params.ideal_block_time == 0
+      || params.difficulty_update_interval == 0
+    {
+      return Err(IoError::new(
+        IoErrorKind::InvalidData,
+        "ChainParams intervals must not be zero",
+      ));
+    }
+    if params.genesis_pubkey().is_none() {
+      return Err(IoError::new(
+        IoErrorKind::InvalidData,
+        "ChainParams genesis public key is invalid",
+      ));
+    }
+    Ok(params)
+  }
+  fn save<O: Write>(&self, mut writer: O) -> IoResult<()> {
+    let toml = toml::to_string_pretty(self).map_err(|_| {
+      IoError::new(
+        IoErrorKind::InvalidData,
+        "Failed to serialize ChainParams",
+      )
+    })?;
+    writer.write_all(toml.as_bytes())
+  }
+}
@@ -0,0 +1,95 @@
+use crate::U256;
+
+// Proof of work targets: the compact "nBits" form block
+// headers store them in, the retarget math, and difficulty
+
+// sign bit of the mantissa in the compact form, which
+// targets never have
+const COMPACT_SIGN_BIT: u32 = 0x0080_0000;
+const COMPACT_MANTISSA: u32 = 0x007f_ffff;
+
+// Encode a target in the compact form: the high byte is its
+// length in bytes, the low three bytes its most significant
+// bytes. Precision beyond those is cut off, so the encoded
+// target is at most the given one
+pub fn target_to_bits(target: U256) -> u32 {
+    let mut size = target.bits().div_ceil(8);
+    let mut mantissa = if size <= 3 {
+        target.low_u32() << (8 * (3 - size))
+    } else {
+        (target >> (8 * (size - 3))).low_u32()
+    };
+    // the mantissa would read as negative, move
+    // it down a byte to keep the sign bit clear
+    if mantissa & COMPACT_SIGN_BIT != 0 {
+        mantissa >>= 8;
+        size += 1;
+    }
+    mantissa | (size as u32) << 24
+}
+
+// Decode a target from the compact form. None if the
+// bits encode a negative number or one that overflows
+pub fn bits_to_target(bits: u32) -> Option<U256> {
+    let size = bits >> 24;
+    let mantissa = bits & COMPACT_MANTISSA;
+    if bits & COMPACT_SIGN_BIT != 0 && mantissa != 0 {
+        return None;
+    }
+    if size <= 3 {
+        return Some(U256::from(mantissa >> (8 * (3 - size))));
+    }
+    let shift = 8 * (size - 3) as usize;
+    if mantissa != 0 && U256::from(mantissa).bits() + shift > 256 {
+        return None;
+    }
+    Some(U256::from(mantissa) << shift)
+}
+
+// The target after a retarget interval that took
+// `actual_seconds` instead of `ideal_seconds`, i.e.
+// target * actual / ideal rounded down. The actual time
+// is clamped so the target changes at most by a factor
+// of 4 either way, and the result never exceeds
+// `min_target`. Integer math only, so every node
+// computes the same target
+pub fn retarget(
+    target: U256,
+    actual_seconds: i64,
+    ideal_seconds: u64,
+    min_target: U256,
+) -> U256 {
+    let ideal = ideal_seconds.max(1);
+    let actual = actual_seconds.clamp(
+        (ideal / 4).max(1) as i64,
+        ideal.saturating_mul(4).min(i64::MAX as u64) as i64,
+    ) as u64;
+    // target = q * ideal + r, so target * actual / ideal
+    // is q * actual + r * actual / ideal, and r * actual
+    // fits since r < ideal
+    let (q, r) = target.div_mod(U256::from(ideal));
+    let new_target = q.checked_mul(U256::from(actual)).and_then(|scaled| {
+        scaled.checked_add(r * U256::from(actual) / U256::from(ideal))
+    });
+    match new_target {
+        Some(new_target) => new_target.min(min_target),
+        None => min_target,
+    }
+}
+
+// How much harder it is to meet `target` than `min_target`
+// as headers encode it, 1.0 at the minimum difficulty
+pub fn difficulty(target: U256, min_target: U256) -> f64 {
+    let min_target = bits_to_target(target_to_bits(min_target))
+        .expect("BUG: encoded target doesn't decode");
+    to_f64(min_target) / to_f64(target.max(U256::one()))
+}
+
+fn to_f64(value: U256) -> f64 {
+    let bits = value.bits();
+    if bits <= 64 {
+        return value.low_u64() as f64;
+    }
+    let shift = bits - 64;
+    (value >> shift).low_u64() as f64 * 2f64.powi(shift as i32)
+}
@@ -1,5 +1,7 @@
 use std::fmt;
+use std::str::FromStr;
 
+use crate::error::BtcError;
 use crate::U256;
 use serde::{Deserialize, Serialize};
 use sha256::digest;
@@ -8,6 +10,7 @@ use sha256::digest;
 pub struct Hash(U256);
 impl Hash {
     // hash anything that can be serialized via ciborium
+    #[allow(clippy::self_named_constructors)]
     pub fn hash<T: serde::Serialize>(data: &T) -> Self {
         let mut serialized: Vec<u8> = vec![];
         if let Err(e) = ciborium::into_writer(data, &mut serialized) {
@@ -32,9 +35,7 @@ impl Hash {
     }
     // convert to bytes
     pub fn as_bytes(&self) -> [u8; 32] {
-        let mut bytes: Vec<u8> = vec![0; 32];
-        self.0.to_little_endian(); // self.0.to_little_endian(&mut bytes);
-        bytes.as_slice().try_into().unwrap()
+        self.0.to_little_endian()
     }
 }
 
@@ -43,3 +44,13 @@ impl fmt::Display for Hash {
         write!(f, "{:x}", self.0)
     }
 }
+
+// parse the hex digits printed by Display
+impl FromStr for Hash {
+    type Err = BtcError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        U256::from_str_radix(s, 16)
+            .map(Hash)
+            .map_err(|_| BtcError::InvalidHash)
+    }
+}
@@ -1,8 +1,14 @@
 mod block;
 mod blockchain;
+mod legacy;
+mod mempool;
 mod transaction;
+mod txindex;
 pub use block::{Block, BlockHeader};
 pub use blockchain::Blockchain;
+pub use mempool::{fee_rate, Mempool, MempoolEntry};
 pub use transaction::{
-    Transaction, TransactionInput, TransactionOutput
+    OutPoint, SigHashOutputs, SigHashType, SpendingCondition, Timelock,
+    Transaction, TransactionInput, TransactionOutput,
 };
+pub use txindex::{TxIndex, TxLocation};
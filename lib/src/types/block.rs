@@ -1,11 +1,14 @@
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
-use super::{Transaction, TransactionOutput};
+use super::{OutPoint, Transaction, TransactionOutput};
 use crate::error::{BtcError, Result};
+use crate::params::ChainParams;
+use crate::pow;
 use crate::sha256::Hash;
 use crate::util::MerkleRoot;
+use crate::validation;
 use crate::U256;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use crate::util::Saveable;
 use std::io::{
   Error as IoError, ErrorKind as IoErrorKind, Read,
@@ -25,113 +28,130 @@ impl Block {
             transactions,
         }
     }
+    // Blocks are identified by the hash of their header, which
+    // commits to the transactions via the merkle root. Before
+    // headers-first sync it was the hash of the whole block, so
+    // chains from back then don't link up under this one and
+    // Blockchain::is_legacy recognizes their files
     pub fn hash(&self) -> Hash {
-        Hash::hash(self)
+        self.header.hash()
+    }
+    // size of the block when serialized, in bytes
+    pub fn size(&self) -> usize {
+        let mut bytes = vec![];
+        ciborium::into_writer(self, &mut bytes)
+            .expect("BUG: block can't be serialized");
+        bytes.len()
     }
-    // Verify all transactions in the block
+    // Verify all transactions in the block. Time locks are
+    // checked against the median time past of the chain the
+    // block extends, which, unlike the block's own timestamp,
+    // its miner can't move ahead
     pub fn verify_transactions(
         &self,
+        params: &ChainParams,
         predicted_block_height: u64,
-        utxos: &HashMap<Hash, (bool, TransactionOutput)>,
+        median_time_past: DateTime<Utc>,
+        utxos: &HashMap<OutPoint, (bool, TransactionOutput)>,
     ) -> Result<()> {
-        let mut inputs: HashMap<Hash, TransactionOutput> = HashMap::new();
+        let mut inputs: HashSet<OutPoint> = HashSet::new();
+        // outputs of earlier transactions in the block,
+        // which later ones may spend
+        let mut created: HashMap<OutPoint, &TransactionOutput> = HashMap::new();
         // reject completely empty blocks
         if self.transactions.is_empty() {
             return Err(BtcError::InvalidTransaction);
         }
         // verify coinbase transaction
-        self.verify_coinbase_transaction(predicted_block_height, utxos)?;
+        if self.size() as u64 > params.max_block_size {
+            return Err(BtcError::BlockTooLarge);
+        }
+        self.verify_coinbase_transaction(params, predicted_block_height, utxos)?;
         for transaction in self.transactions.iter().skip(1) {
-            let mut input_value = 0;
-            let mut output_value = 0;
+            validation::check_transaction(transaction)?;
+            // prevent same-block double-spending
             for input in &transaction.inputs {
-                let prev_output = utxos.get(&input.prev_transaction_output_hash).map(|(_, output)| output);
-                if prev_output.is_none() {
-                    return Err(BtcError::InvalidTransaction);
-                }
-                let prev_output = prev_output.unwrap();
-                // prevent same-block double-spending
-                if inputs.contains_key(&input.prev_transaction_output_hash) {
-                    return Err(BtcError::InvalidTransaction);
+                if !inputs.insert(input.prev_output) {
+                    return Err(BtcError::DuplicateInput);
                 }
-                // check if the signature is valid
-                if !input
-                    .signature
-                    .verify(&input.prev_transaction_output_hash, &prev_output.pubkey)
-                {
-                    return Err(BtcError::InvalidSignature);
-                }
-                input_value += prev_output.value;
-                inputs.insert(input.prev_transaction_output_hash, prev_output.clone());
-            }
-            for output in &transaction.outputs {
-                output_value += output.value;
-            }
-            // It is fine for output value to be less than input value
-            // as the difference is the fee for the miner
-            if input_value < output_value {
-                return Err(BtcError::InvalidTransaction);
             }
+            validation::check_transaction_inputs(
+                transaction,
+                |hash| {
+                    utxos
+                        .get(hash)
+                        .map(|(_, output)| output)
+                        .or_else(|| created.get(hash).copied())
+                },
+                predicted_block_height,
+                median_time_past,
+            )?;
+            created.extend(transaction.utxo_entries());
         }
         Ok(())
     }
     // Verify coinbase transaction
     pub fn verify_coinbase_transaction(
         &self,
+        params: &ChainParams,
         predicted_block_height: u64,
-        utxos: &HashMap<Hash, (bool, TransactionOutput)>,
+        utxos: &HashMap<OutPoint, (bool, TransactionOutput)>,
     ) -> Result<()> {
         // coinbase tx is the first transaction in the block
         let coinbase_transaction = &self.transactions[0];
-        if coinbase_transaction.inputs.len() != 0 {
+        if !coinbase_transaction.inputs.is_empty() {
             return Err(BtcError::InvalidTransaction);
         }
-        if coinbase_transaction.outputs.len() == 0 {
+        if coinbase_transaction.outputs.is_empty() {
             return Err(BtcError::InvalidTransaction);
         }
+        for output in &coinbase_transaction.outputs {
+            output.verify_condition()?;
+        }
         let miner_fees = self.calculate_miner_fees(utxos)?;
-        let block_reward = crate::INITIAL_REWARD * 10u64.pow(8)
-            / 2u64.pow((predicted_block_height / crate::HALVING_INTERVAL) as u32);
-        let total_coinbase_outputs: u64 = coinbase_transaction
-            .outputs
-            .iter()
-            .map(|output| output.value)
-            .sum();
-        if total_coinbase_outputs != block_reward + miner_fees {
+        let block_reward = params.block_reward(predicted_block_height);
+        let total_coinbase_outputs =
+            validation::output_value(coinbase_transaction)?;
+        if Some(total_coinbase_outputs) != block_reward.checked_add(miner_fees) {
             return Err(BtcError::InvalidTransaction);
         }
         Ok(())
     }
-    pub fn calculate_miner_fees(&self, utxos: &HashMap<Hash, (bool, TransactionOutput)>) -> Result<u64> {
-        let mut inputs: HashMap<Hash, TransactionOutput> = HashMap::new();
-        let mut outputs: HashMap<Hash, TransactionOutput> = HashMap::new();
+    pub fn calculate_miner_fees(&self, utxos: &HashMap<OutPoint, (bool, TransactionOutput)>) -> Result<u64> {
+        let mut inputs: HashSet<OutPoint> = HashSet::new();
+        let mut created: HashMap<OutPoint, &TransactionOutput> = HashMap::new();
+        let mut miner_fees: u64 = 0;
         // Check every transaction after coinbase
         for transaction in self.transactions.iter().skip(1) {
+            let mut input_value: u64 = 0;
             for input in &transaction.inputs {
                 // inputs do not contain
                 // the values of the outputs
                 // so we need to match inputs
-                // to outputs
-                let prev_output = utxos.get(&input.prev_transaction_output_hash).map(|(_, output)| output);
-                if prev_output.is_none() {
-                    return Err(BtcError::InvalidTransaction);
-                }
-                let prev_output = prev_output.unwrap();
-                if inputs.contains_key(&input.prev_transaction_output_hash) {
-                    return Err(BtcError::InvalidTransaction);
-                }
-                inputs.insert(input.prev_transaction_output_hash, prev_output.clone());
-            }
-            for output in &transaction.outputs {
-                if outputs.contains_key(&output.hash()) {
-                    return Err(BtcError::InvalidTransaction);
+                // to outputs, which may also
+                // come from earlier transactions
+                // of the block
+                let prev_output = utxos
+                    .get(&input.prev_output)
+                    .map(|(_, output)| output)
+                    .or_else(|| created.get(&input.prev_output).copied())
+                    .ok_or(BtcError::MissingInput)?;
+                if !inputs.insert(input.prev_output) {
+                    return Err(BtcError::DuplicateInput);
                 }
-                outputs.insert(output.hash(), output.clone());
+                input_value = input_value
+                    .checked_add(prev_output.value)
+                    .ok_or(BtcError::ValueOverflow)?;
             }
+            let fee = input_value
+                .checked_sub(validation::output_value(transaction)?)
+                .ok_or(BtcError::InsufficientInputValue)?;
+            miner_fees = miner_fees
+                .checked_add(fee)
+                .ok_or(BtcError::ValueOverflow)?;
+            created.extend(transaction.utxo_entries());
         }
-        let input_value: u64 = inputs.values().map(|output| output.value).sum();
-        let output_value: u64 = outputs.values().map(|output| output.value).sum();
-        Ok(input_value - output_value)
+        Ok(miner_fees)
     }
 }
 
@@ -145,8 +165,9 @@ pub struct BlockHeader {
     pub prev_block_hash: Hash,
     /// Merkle root of the block's transactions
     pub merkle_root: MerkleRoot,
-    /// target
-    pub target: U256,
+    /// Target the block's hash has to meet,
+    /// in the compact form of crate::pow
+    pub bits: u32,
 }
 
 impl BlockHeader {
@@ -155,22 +176,35 @@ impl BlockHeader {
         nonce: u64,
         prev_block_hash: Hash,
         merkle_root: MerkleRoot,
-        target: U256,
+        bits: u32,
     ) -> Self {
         BlockHeader {
             timestamp,
             nonce,
             prev_block_hash,
             merkle_root,
-            target,
+            bits,
         }
     }
     pub fn hash(&self) -> Hash {
         Hash::hash(self)
     }
+    // the target encoded in bits, zero if they don't
+    // encode one, which no hash is going to meet
+    pub fn target(&self) -> U256 {
+        pow::bits_to_target(self.bits).unwrap_or_default()
+    }
+    // amount of work represented by the header's target,
+    // i.e. the expected number of hashes needed to meet it:
+    // 2^256 / (target + 1)
+    pub fn work(&self) -> U256 {
+        let target = self.target();
+        (!target / target.saturating_add(U256::one())) + 1
+    }
     pub fn mine(&mut self, steps: usize) -> bool {
         // if the block already matches target, return early
-        if self.hash().matches_target(self.target) {
+        let target = self.target();
+        if self.hash().matches_target(target) {
             return true;
         }
         for _ in 0..steps {
@@ -182,7 +216,7 @@ impl BlockHeader {
                 self.nonce = 0;
                 self.timestamp = Utc::now()
             }
-                if self.hash().matches_target(self.target) {
+                if self.hash().matches_target(target) {
                 return true;
             }
         }
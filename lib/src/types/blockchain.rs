@@ -1,12 +1,14 @@
-use bigdecimal::BigDecimal;
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, TimeDelta, Utc};
 use serde::{Deserialize, Serialize};
-use super::{Block, Transaction, TransactionOutput};
+use super::{Block, Mempool, OutPoint, Transaction, TransactionOutput, TxIndex, TxLocation};
 use crate::error::{BtcError, Result};
+use crate::params::ChainParams;
+use crate::pow;
 use crate::sha256::Hash;
 use crate::util::MerkleRoot;
+use crate::validation;
 use crate::U256;
-use std::collections::{HashMap, HashSet};
+use std::collections::HashMap;
 // add this to the imports at the top of the file
 use crate::util::Saveable;
 use std::io::{
@@ -14,152 +16,553 @@ use std::io::{
   Result as IoResult, Write,
 };
 
+/// The outputs a block on the active chain spent, in order,
+/// so it can be disconnected without replaying the chain
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+struct BlockUndo {
+    spent: Vec<(OutPoint, TransactionOutput)>,
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Blockchain {
-    utxos: HashMap<Hash, (bool, TransactionOutput)>,
+    utxos: HashMap<OutPoint, (bool, TransactionOutput)>,
     target: U256,
     blocks: Vec<Block>,
-    #[serde(default, skip_serializing)]
-    mempool: Vec<(DateTime<Utc>, Transaction)>,
+    // undo data of the blocks of the active chain
+    #[serde(default)]
+    undo: Vec<BlockUndo>,
+    // blocks on competing branches, by hash
+    #[serde(default)]
+    side_blocks: HashMap<Hash, Block>,
+    // heights of the blocks of the active chain, by hash
+    #[serde(skip)]
+    heights: HashMap<Hash, u64>,
+    #[serde(skip)]
+    mempool: Mempool,
+    #[serde(skip)]
+    params: ChainParams,
+    // locations of confirmed transactions, if enabled
+    #[serde(skip)]
+    txindex: Option<TxIndex>,
+}
+
+impl Default for Blockchain {
+    fn default() -> Self {
+        Self::new(ChainParams::default())
+    }
 }
 
 impl Blockchain {
-    pub fn new() -> Self {
+    pub fn new(params: ChainParams) -> Self {
         Blockchain {
             utxos: HashMap::new(),
             blocks: vec![],
-            target: crate::MIN_TARGET,
-            mempool: vec![]
+            undo: vec![],
+            side_blocks: HashMap::new(),
+            heights: HashMap::new(),
+            target: params.min_target,
+            mempool: Mempool::default(),
+            params,
+            txindex: None,
         }
     }
+    // the same chain under different consensus parameters,
+    // e.g. after loading it from a file
+    pub fn with_params(mut self, params: ChainParams) -> Self {
+        self.params = params;
+        self.update_target();
+        self
+    }
     pub fn add_block(&mut self, block: Block) -> Result<()> {
-        // check if the block is valid
-        if self.blocks.is_empty() {
-            // if this is the first block, check if the
-            // block's prev_block_hash is all zeroes
-            if block.header.prev_block_hash != Hash::zero() {
-                println!("zero hash");
-                return Err(BtcError::InvalidBlock);
-            }
-        } else {
-            // if this is not the first block, check if the
-            // block's prev_block_hash is the hash of the last block
-            let last_block = self.blocks.last().unwrap();
-
+        self.connect_block(block, true)
+    }
+    // Add a block that was fully validated before, e.g. one read
+    // back from the node's own block store. Its header is checked
+    // like any other, its transactions, whose signatures make up
+    // most of the work, are taken as they are
+    pub fn add_validated_block(&mut self, block: Block) -> Result<()> {
+        self.connect_block(block, false)
+    }
+    fn connect_block(&mut self, block: Block, verify: bool) -> Result<()> {
+        let hash = block.hash();
+        if self.side_blocks.contains_key(&hash) {
+            println!("block already known");
+            return Err(BtcError::InvalidBlock);
+        }
+        // blocks that don't extend the current tip
+        // belong on a side branch
+        if let Some(last_block) = self.blocks.last() {
             if block.header.prev_block_hash != last_block.hash() {
-                println!("prev hash is wrong");
-                return Err(BtcError::InvalidBlock);
+                return self.add_side_block(hash, block, verify);
             }
-            // check if the block's hash is less than the target
-            if !block.header.hash().matches_target(block.header.target) {
-                println!("does not match target");
-                return Err(BtcError::InvalidBlock);
-            }
-            // check if the block's merkle root is correct
-            let calculated_merkle_root = MerkleRoot::calculate(&block.transactions);
-            if calculated_merkle_root != block.header.merkle_root {
-                println!("invalid merkle root");
-                return Err(BtcError::InvalidMerkleRoot);
+        }
+        // check if the block is valid
+        match self.blocks.last() {
+            // the chain has to start with the network's
+            // genesis block, which is checked like any other
+            None => {
+                if hash != self.params.genesis_block().hash() {
+                    return Err(BtcError::InvalidGenesisBlock);
+                }
+                Self::check_proof_of_work(&block)?;
             }
-            // check if the block's timestamp is after the
-            // last block's timestamp
-            if block.header.timestamp <= last_block.header.timestamp {
-                return Err(BtcError::InvalidBlock);
+            Some(_) => {
+                self.check_block_header(&block)?;
             }
-            // Verify all transactions in the block
-            block.verify_transactions(self.block_height(), &self.utxos)?;
         }
-        // Remove transactions from mempool that are now in the block
-        let block_transactions: HashSet<_> = block
-            .transactions
+        // Verify all transactions in the block
+        if verify {
+            block.verify_transactions(
+                &self.params,
+                self.block_height(),
+                self.lock_time(),
+                &self.utxos,
+            )?;
+        }
+        // Remove transactions from mempool that are now in the
+        // block, and the ones that conflict with it
+        let conflicts = self.mempool.remove_for_block(&block);
+        // unmark what the conflicting ones spent, and mark the
+        // new outputs that remaining mempool transactions spend
+        let touched: Vec<OutPoint> = conflicts
             .iter()
-            .map(|tx| tx.hash())
+            .flat_map(|tx| tx.inputs.iter())
+            .map(|input| input.prev_output)
+            .chain(
+                block
+                    .transactions
+                    .iter()
+                    .flat_map(|tx| tx.utxo_entries().map(|(key, _)| key)),
+            )
             .collect();
-        self.mempool.retain(|(_, tx)| {
-            !block_transactions.contains(&tx.hash())
-        });
+        self.push_block(block);
+        self.update_marks(&touched);
+        self.update_target();
+        self.prune_side_blocks();
+        Ok(())
+    }
+    // append a block to the active chain, applying its
+    // UTXO changes and keeping what it spent as undo data
+    fn push_block(&mut self, block: Block) {
+        let undo = Self::connect_utxos(&mut self.utxos, &block);
+        if let Some(txindex) = &mut self.txindex {
+            txindex.connect_block(&block, self.blocks.len() as u64);
+        }
+        self.undo.push(undo);
+        self.heights.insert(block.hash(), self.blocks.len() as u64);
         self.blocks.push(block);
-        self.try_adjust_target();
+    }
+    // remove the tip of the active chain, reverting
+    // its UTXO changes with its undo data
+    fn pop_block(&mut self) -> Option<Block> {
+        let block = self.blocks.pop()?;
+        let undo = self.undo.pop().expect("BUG: undo data is missing");
+        self.heights.remove(&block.hash());
+        Self::disconnect_utxos(&mut self.utxos, &block, undo);
+        if let Some(txindex) = &mut self.txindex {
+            txindex.disconnect_block(&block);
+        }
+        Some(block)
+    }
+    // Checks of a block building on a known block: its proof
+    // of work, and its target and timestamp against the
+    // branch it extends
+    fn check_block_header(&self, block: &Block) -> Result<()> {
+        Self::check_proof_of_work(block)?;
+        let (height, ancestors) =
+            self.ancestors(&block.header.prev_block_hash)?;
+        if block.header.bits != self.expected_bits(height, &ancestors) {
+            println!("unexpected target");
+            return Err(BtcError::UnexpectedTarget);
+        }
+        // the timestamp has to be after the median time
+        // of the last blocks, and not too far ahead of ours
+        if Self::median_time(&ancestors)
+            .is_some_and(|median| block.header.timestamp <= median)
+        {
+            println!("timestamp too old");
+            return Err(BtcError::TimestampTooOld);
+        }
+        let max_time = Utc::now()
+            + TimeDelta::seconds(crate::MAX_FUTURE_BLOCK_TIME as i64);
+        if block.header.timestamp > max_time {
+            println!("timestamp too far in the future");
+            return Err(BtcError::TimestampTooFarInFuture);
+        }
         Ok(())
     }
-    // Rebuild UTXO set from the blockchain
-    pub fn rebuild_utxos(&mut self) {
-        for block in &self.blocks {
-            for transaction in &block.transactions {
-                for input in &transaction.inputs {
-                    self.utxos.remove(&input.prev_transaction_output_hash);
+    // checks of a block on its own: its hash meets its
+    // target and its header commits to its transactions
+    fn check_proof_of_work(block: &Block) -> Result<()> {
+        // check if the block's hash is less than the target
+        if !block.header.hash().matches_target(block.header.target()) {
+            println!("does not match target");
+            return Err(BtcError::InvalidBlock);
+        }
+        // check if the block's merkle root is correct
+        let calculated_merkle_root = MerkleRoot::calculate(&block.transactions);
+        if calculated_merkle_root != block.header.merkle_root {
+            println!("invalid merkle root");
+            return Err(BtcError::InvalidMerkleRoot);
+        }
+        Ok(())
+    }
+    // Store a block that does not extend the active chain, and
+    // switch to its branch if it now has the most cumulative work
+    fn add_side_block(&mut self, hash: Hash, block: Block, verify: bool) -> Result<()> {
+        let prev_hash = block.header.prev_block_hash;
+        if !self.side_blocks.contains_key(&prev_hash)
+            && self.main_chain_position(&prev_hash).is_none()
+        {
+            println!("unknown parent");
+            return Err(BtcError::UnknownParent);
+        }
+        if self
+            .blocks
+            .iter()
+            .rev()
+            .take_while(|block| block.hash() != prev_hash)
+            .any(|block| block.hash() == hash)
+        {
+            println!("block already known");
+            return Err(BtcError::InvalidBlock);
+        }
+        self.check_block_header(&block)?;
+        self.side_blocks.insert(hash, block);
+        // walk back to the point where the branch
+        // forks off the active chain
+        let mut branch = vec![hash];
+        let mut branch_work = U256::zero();
+        let mut cursor = hash;
+        let fork_height = loop {
+            let block = &self.side_blocks[&cursor];
+            branch_work += block.header.work();
+            cursor = block.header.prev_block_hash;
+            if !self.side_blocks.contains_key(&cursor) {
+                break self
+                    .main_chain_position(&cursor)
+                    .expect("BUG: side branch is not connected");
+            }
+            branch.push(cursor);
+        };
+        branch.reverse();
+        let main_work = self.blocks[fork_height + 1..]
+            .iter()
+            .fold(U256::zero(), |work, block| {
+                work + block.header.work()
+            });
+        if branch_work > main_work {
+            self.reorganize(fork_height, branch, verify)?;
+        }
+        self.prune_side_blocks();
+        Ok(())
+    }
+    // Forget side blocks forking off the active chain more than
+    // MAX_FORK_DEPTH blocks below the tip. Switching to them
+    // would take a deep reorganization, and anyone can fill
+    // memory with them where blocks are cheap to mine
+    fn prune_side_blocks(&mut self) {
+        let min_fork_height = self
+            .blocks
+            .len()
+            .saturating_sub(1 + crate::MAX_FORK_DEPTH);
+        let stale: Vec<Hash> = self
+            .side_blocks
+            .keys()
+            .filter(|hash| {
+                self.fork_height(hash)
+                    .is_none_or(|height| height < min_fork_height)
+            })
+            .copied()
+            .collect();
+        for hash in stale {
+            self.side_blocks.remove(&hash);
+        }
+    }
+    // height of the active chain block a side block's branch
+    // forks off from, None if the branch isn't connected
+    fn fork_height(&self, hash: &Hash) -> Option<usize> {
+        let mut cursor = *hash;
+        while let Some(block) = self.side_blocks.get(&cursor) {
+            cursor = block.header.prev_block_hash;
+        }
+        self.main_chain_position(&cursor)
+    }
+    // Switch the active chain to the given branch, which forks
+    // off right after the block at fork_height. The blocks of
+    // the branch are expected to be in side_blocks, in order.
+    // Their transactions are only checked if `verify` is set
+    fn reorganize(
+        &mut self,
+        fork_height: usize,
+        branch: Vec<Hash>,
+        verify: bool,
+    ) -> Result<()> {
+        println!(
+            "reorganizing: disconnecting {} blocks, connecting {}",
+            self.blocks.len() - fork_height - 1,
+            branch.len()
+        );
+        // roll back the active chain to the fork point
+        let mut disconnected = vec![];
+        while self.blocks.len() > fork_height + 1 {
+            disconnected.push(self.pop_block().unwrap());
+        }
+        disconnected.reverse();
+        // and apply the new branch on top of it
+        for (idx, hash) in branch.iter().enumerate() {
+            let block = self
+                .side_blocks
+                .remove(hash)
+                .expect("BUG: branch block is missing");
+            let valid = if verify {
+                block.verify_transactions(
+                    &self.params,
+                    self.block_height(),
+                    self.lock_time(),
+                    &self.utxos,
+                )
+            } else {
+                Ok(())
+            };
+            if let Err(e) = valid {
+                println!("branch contains an invalid block, staying on the old chain");
+                // the invalid block and its descendants are dropped,
+                // the valid part of the branch goes back to the side
+                while self.blocks.len() > fork_height + 1 {
+                    let block = self.pop_block().unwrap();
+                    self.side_blocks.insert(block.hash(), block);
                 }
-                for output in transaction.outputs.iter() {
-                    self.utxos.insert(transaction.hash(), (false, output.clone()));
+                for hash in &branch[idx + 1..] {
+                    self.side_blocks.remove(hash);
                 }
+                for block in disconnected {
+                    self.push_block(block);
+                }
+                // outputs come back unmarked, both the ones of the
+                // disconnected blocks and the ones the branch spent,
+                // mark every output pending transactions spend again
+                let pending: Vec<OutPoint> =
+                    self.mempool.spent_outputs().copied().collect();
+                self.update_marks(&pending);
+                self.update_target();
+                return Err(e);
             }
+            self.push_block(block);
         }
+        self.update_target();
+        // transactions from the orphaned blocks go back to the
+        // mempool, together with everything that was pending.
+        // Anything that is now confirmed or conflicts with the
+        // new chain gets rejected on the way in
+        let mut pending: Vec<Transaction> = disconnected
+            .iter()
+            .flat_map(|block| block.transactions.iter().skip(1).cloned())
+            .collect();
+        pending.extend(self.mempool.take_all());
+        for (marked, _) in self.utxos.values_mut() {
+            *marked = false;
+        }
+        for block in disconnected {
+            self.side_blocks.insert(block.hash(), block);
+        }
+        for transaction in pending {
+            let _ = self.add_to_mempool(transaction);
+        }
+        Ok(())
     }
-    // try to adjust the target of the blockchain
-    pub fn try_adjust_target(&mut self) {
-        if self.blocks.is_empty()
-        {
-            return;
+    // hashes of blocks on the active chain, starting at the
+    // tip and getting exponentially sparser towards genesis,
+    // so a peer can find where our chains diverge
+    pub fn block_locator(&self) -> Vec<Hash> {
+        let mut locator = vec![];
+        let mut step = 1;
+        let mut height = self.blocks.len();
+        while height > 0 {
+            locator.push(self.blocks[height - 1].hash());
+            if locator.len() >= 10 {
+                step *= 2;
+            }
+            height = height.saturating_sub(step);
         }
-        if self.blocks.len()
-            % crate::DIFFICULTY_UPDATE_INTERVAL as usize
-            != 0
-        {
-            return;
+        if let Some(genesis) = self.blocks.first() {
+            if locator.last() != Some(&genesis.hash()) {
+                locator.push(genesis.hash());
+            }
+        }
+        locator
+    }
+    // height of the first block of a locator
+    // that is on the active chain
+    pub fn find_fork(&self, locator: &[Hash]) -> Option<u64> {
+        locator.iter().find_map(|hash| {
+            self.main_chain_position(hash).map(|height| height as u64)
+        })
+    }
+    // a block on the active chain or a side branch
+    pub fn get_block(&self, hash: &Hash) -> Option<&Block> {
+        self.main_chain_position(hash)
+            .map(|height| &self.blocks[height])
+            .or_else(|| self.side_blocks.get(hash))
+    }
+    // height of a block on the active chain
+    pub fn main_chain_position(&self, hash: &Hash) -> Option<usize> {
+        self.heights.get(hash).map(|&height| height as usize)
+    }
+    // apply the UTXO changes of a block, returning
+    // the outputs it spent
+    fn connect_utxos(
+        utxos: &mut HashMap<OutPoint, (bool, TransactionOutput)>,
+        block: &Block,
+    ) -> BlockUndo {
+        let mut undo = BlockUndo::default();
+        for transaction in &block.transactions {
+            for input in &transaction.inputs {
+                if let Some((_, output)) = utxos.remove(&input.prev_output) {
+                    undo.spent.push((input.prev_output, output));
+                }
+            }
+            for (outpoint, output) in transaction.utxo_entries() {
+                utxos.insert(outpoint, (false, output.clone()));
+            }
+        }
+        undo
+    }
+    // revert the UTXO changes of a block. Outputs spent within
+    // the block are restored first and removed with the rest
+    // of the block's outputs
+    fn disconnect_utxos(
+        utxos: &mut HashMap<OutPoint, (bool, TransactionOutput)>,
+        block: &Block,
+        undo: BlockUndo,
+    ) {
+        for (outpoint, output) in undo.spent {
+            utxos.insert(outpoint, (false, output));
+        }
+        for transaction in &block.transactions {
+            for (outpoint, _) in transaction.utxo_entries() {
+                utxos.remove(&outpoint);
+            }
+        }
+    }
+    // The UTXO set replayed from genesis. The node keeps its
+    // UTXO set up to date block by block, this is only to check
+    // that against
+    pub fn rebuild_utxos(&self) -> HashMap<OutPoint, TransactionOutput> {
+        let mut utxos = HashMap::new();
+        for block in &self.blocks {
+            Self::connect_utxos(&mut utxos, block);
+        }
+        utxos
+            .into_iter()
+            .map(|(outpoint, (_, output))| (outpoint, output))
+            .collect()
+    }
+    // whether the UTXO set matches the one replayed from genesis
+    pub fn check_utxos(&self) -> bool {
+        let rebuilt = self.rebuild_utxos();
+        rebuilt.len() == self.utxos.len()
+            && rebuilt.iter().all(|(outpoint, output)| {
+                self.utxos
+                    .get(outpoint)
+                    .is_some_and(|(_, ours)| ours.hash() == output.hash())
+            })
+    }
+    // The height of a block building on `parent`, and its
+    // ancestors most recent first, as far back as the
+    // retarget and median time rules look
+    fn ancestors(&self, parent: &Hash) -> Result<(u64, Vec<&Block>)> {
+        let window = (self.params.difficulty_update_interval as usize)
+            .max(crate::MEDIAN_TIME_SPAN);
+        let mut ancestors = vec![];
+        let mut cursor = *parent;
+        // side branch blocks down to the active chain
+        while let Some(block) = self.side_blocks.get(&cursor) {
+            ancestors.push(block);
+            cursor = block.header.prev_block_hash;
+        }
+        let Some(fork) = self.main_chain_position(&cursor) else {
+            return Err(BtcError::UnknownParent);
+        };
+        let height = (fork + ancestors.len() + 1) as u64;
+        let rest = window.saturating_sub(ancestors.len());
+        ancestors.extend(self.blocks[..=fork].iter().rev().take(rest));
+        ancestors.truncate(window);
+        Ok((height, ancestors))
+    }
+    // the median timestamp of the last MEDIAN_TIME_SPAN
+    // blocks out of the given ones, most recent first
+    fn median_time(ancestors: &[&Block]) -> Option<DateTime<Utc>> {
+        let mut times: Vec<DateTime<Utc>> = ancestors
+            .iter()
+            .take(crate::MEDIAN_TIME_SPAN)
+            .map(|block| block.header.timestamp)
+            .collect();
+        times.sort();
+        times.get(times.len() / 2).copied()
+    }
+    // the median time past of the active chain: the next
+    // block's timestamp has to be after it
+    pub fn median_time_past(&self) -> Option<DateTime<Utc>> {
+        let ancestors: Vec<&Block> = self
+            .blocks
+            .iter()
+            .rev()
+            .take(crate::MEDIAN_TIME_SPAN)
+            .collect();
+        Self::median_time(&ancestors)
+    }
+    // the time the time locks of the next block's inputs are
+    // checked against, its median time past. Without blocks
+    // nothing is unlocked by time yet
+    fn lock_time(&self) -> DateTime<Utc> {
+        self.median_time_past().unwrap_or(DateTime::UNIX_EPOCH)
+    }
+    // The target a block at `height` has to have, in compact
+    // form, given its ancestors most recent first. It changes
+    // every difficulty_update_interval blocks, and carries
+    // over from the parent otherwise
+    fn expected_bits(&self, height: u64, ancestors: &[&Block]) -> u32 {
+        let interval = self.params.difficulty_update_interval;
+        let min_bits = pow::target_to_bits(self.params.min_target);
+        let Some(parent) = ancestors.first() else {
+            return min_bits;
+        };
+        if self.params.no_retargeting {
+            return min_bits;
+        }
+        if !height.is_multiple_of(interval) {
+            return parent.header.bits;
         }
         // measure the time it took to mine the last
-        // crate::DIFFICULTY_UPDATE_INTERVAL blocks
-        // with chrono
-        let start_time = self.blocks[self.blocks.len()
-            - crate::DIFFICULTY_UPDATE_INTERVAL as usize]
-            .header
-            .timestamp;
-        let end_time =
-            self.blocks.last().unwrap().header.timestamp;
+        // difficulty_update_interval blocks
+        let start_time = ancestors[interval as usize - 1].header.timestamp;
+        let end_time = parent.header.timestamp;
         let time_diff = end_time - start_time;
-        // convert time_diff to seconds
-        let time_diff_seconds = time_diff.num_seconds();
-        // calculate the ideal number of seconds
-        let target_seconds = crate::IDEAL_BLOCK_TIME
-            * crate::DIFFICULTY_UPDATE_INTERVAL;
-        // multiply the current target by actual time divided byideal time
-        // let new_target = self.target
-        //     * (time_diff_seconds as f64
-        //         / target_seconds as f64)
-        //         as usize;
-        let new_target = BigDecimal::parse_bytes(
-            &self.target.to_string().as_bytes(),
-                10,
-            )
-            .expect("BUG: impossible")
-            * (BigDecimal::from(time_diff_seconds)
-            / BigDecimal::from(target_seconds));
-        // cut off decimal point and everything after
-        // it from string representation of new_target
-        let new_target_str = new_target
-        .to_string()
-        .split('.')
-        .next()
-        .expect("BUG: Expected a decimal point")
-        .to_owned();
-        let new_target: U256 =
-        U256::from_str_radix(&new_target_str, 10)
-        .expect("BUG: impossible");
-        // clamp new_target to be within the range of
-        // 4 * self.target and self.target / 4
-        let new_target = if new_target < self.target / 4 {
-            self.target / 4
-        } else if new_target > self.target * 4 {
-            self.target * 4
-        } else {
-            new_target
+        let new_target = pow::retarget(
+            parent.header.target(),
+            time_diff.num_seconds(),
+            self.params.ideal_block_time * interval,
+            self.params.min_target,
+        );
+        pow::target_to_bits(new_target)
+    }
+    // the target of the next block on the active chain
+    fn update_target(&mut self) {
+        let bits = match self.blocks.last() {
+            Some(tip) => {
+                let (height, ancestors) = self
+                    .ancestors(&tip.hash())
+                    .expect("BUG: tip is not on the active chain");
+                self.expected_bits(height, &ancestors)
+            }
+            None => pow::target_to_bits(self.params.min_target),
         };
-        // if the new target is more than the minimum target,
-        // set it to the minimum target
-        self.target = new_target.min(crate::MIN_TARGET);
+        self.target = pow::bits_to_target(bits)
+            .expect("BUG: expected bits don't encode a target");
     }
     // utxos
-    pub fn utxos(&self) -> &HashMap<Hash, (bool, TransactionOutput)> {
+    pub fn utxos(&self) -> &HashMap<OutPoint, (bool, TransactionOutput)> {
         &self.utxos
     }
     // target
@@ -167,195 +570,128 @@ impl Blockchain {
         self.target
     }
     // blocks
-    pub fn blocks(&self) -> impl Iterator<Item = &Block> {
+    pub fn blocks(&self) -> impl DoubleEndedIterator<Item = &Block> {
         self.blocks.iter()
     }
+    // blocks known to us that are not on the active chain
+    pub fn side_blocks(&self) -> impl Iterator<Item = &Block> {
+        self.side_blocks.values()
+    }
     // block height
     pub fn block_height(&self) -> u64 {
         self.blocks.len() as u64
     }
+    // Keep an index of confirmed transactions from now on,
+    // starting with the ones already on the active chain
+    pub fn enable_txindex(&mut self) {
+        if self.txindex.is_none() {
+            self.txindex = Some(TxIndex::build(self.blocks.iter()));
+        }
+    }
+    pub fn txindex(&self) -> Option<&TxIndex> {
+        self.txindex.as_ref()
+    }
+    // a transaction of the active chain and where it
+    // is, None if it's unknown or there is no txindex
+    pub fn find_transaction(
+        &self,
+        txid: &Hash,
+    ) -> Option<(&Transaction, TxLocation)> {
+        let location = *self.txindex.as_ref()?.get(txid)?;
+        let transaction = self
+            .blocks
+            .get(location.height as usize)?
+            .transactions
+            .get(location.position as usize)?;
+        Some((transaction, location))
+    }
     // mempool
-    pub fn mempool(&self) -> &[(DateTime<Utc>, Transaction)] {// later, we will also need to keep track of time
+    pub fn mempool(&self) -> &Mempool {
         &self.mempool
     }
+    // change the size limits of the mempool
+    pub fn set_mempool_limits(&mut self, max_size: u64, max_count: usize) {
+        let evicted = self.mempool.set_limits(max_size, max_count);
+        self.update_marks(&Self::spent_outputs(&evicted));
+    }
     // add a transaction to mempool
     pub fn add_to_mempool(&mut self, transaction: Transaction) -> Result<()> {
-        // validate transaction before insertion
-        // all inputs must match known UTXOs, and must be unique
-        let mut known_inputs = HashSet::new();
-        for input in &transaction.inputs {
-            if !self.utxos.contains_key(&input.prev_transaction_output_hash) {
-                return Err(BtcError::InvalidTransaction);
-            }
-            if known_inputs.contains(
-                &input.prev_transaction_output_hash
-            ) {
-                println!("duplicate input");
-                return Err(BtcError::InvalidTransaction);
-            }
-            known_inputs
-                .insert(input.prev_transaction_output_hash);
-        }
-        // check if any of the utxos have the bool mark set to true
-        // and if so, find the transaction that references them
-        // in mempool, remove it, and set all the utxos it references
-        // to false
-        for input in &transaction.inputs {
-            if let Some((true, _)) = self
-                .utxos
-                .get(&input.prev_transaction_output_hash)
-            {
-                // find the transaction that references the UTXO
-                // we are trying to reference
-                let referencing_transaction = self.mempool
-                .iter()
-                .enumerate()
-                .find(
-                    |(_, (_, transaction))| {
-                        transaction
-                            .outputs
-                            .iter()
-                            .any(|output| {
-                                output.hash() == input.prev_transaction_output_hash
-                            })
-                    },
-                );
-                // If we found one, unmark all of its UTXOs
-                if let Some((
-                    idx,
-                    (_, referencing_transaction),
-                )) = referencing_transaction
-                {
-                    for input in &referencing_transaction.inputs
-                    {
-                        // set all utxos from this transaction to false
-                        self.utxos
-                            .entry(input.prev_transaction_output_hash)
-                            .and_modify(|(marked, _)| {
-                                *marked = false;
-                            });
-                    }
-                    // remove the transaction from the mempool
-                    self.mempool.remove(idx);
-                } else {
-                    // if, somehow, there is no matching transaction,
-                    // set this utxo to false
-                    self.utxos
-                        .entry(input.prev_transaction_output_hash)
-                        .and_modify(|(marked, _)| {
-                            *marked = false;
-                        });
-                }
-            }
-        }
-
-        // all inputs must be lower than all outputs
-        // all inputs must be lower than all outputs
-        let all_inputs = transaction
-            .inputs
-            .iter()
-            .map(|input| {
+        // validate transaction before insertion, as if it
+        // was included in the next block. It may spend
+        // outputs of other mempool transactions
+        validation::check_transaction(&transaction)?;
+        let fee = validation::check_transaction_inputs(
+            &transaction,
+            |hash| {
                 self.utxos
-                .get(
-                    &input.prev_transaction_output_hash,
-                )
-                .expect("BUG: impossible")
-                .1
-                .value
-            })
-            .sum::<u64>();
-        let all_outputs = transaction
-            .outputs
-            .iter()
-            .map(|output| output.value)
-            .sum();
-        if all_inputs < all_outputs {
-            print!("inputs are lower than outputs");
-            return Err(BtcError::InvalidTransaction);
-        }
-        // Mark the UTXOs as used
-        for input in &transaction.inputs {
-            self.utxos
-                .entry(input.prev_transaction_output_hash)
-                .and_modify(|(marked, _)| {
-                    *marked = true;
-                });
-        }
-        // push the transaction to the mempool
-        self.mempool.push((Utc::now(), transaction));
-        // sort by miner fee
-        self.mempool.sort_by_key(|(_, transaction)| {
-            let all_inputs = transaction
-                .inputs
-                .iter()
-                .map(|input| {
-                    self.utxos
-                    .get(&input.prev_transaction_output_hash)
-                    .expect("BUG: impossible")
-                    .1
-                    .value
-                })
-                .sum::<u64>();
-            let all_outputs: u64 = transaction
-                .outputs
-                .iter()
-                .map(|output| output.value)
-                .sum();
-            let miner_fee = all_inputs - all_outputs;
-            miner_fee
-        });
+                    .get(hash)
+                    .map(|(_, output)| output)
+                    .or_else(|| self.mempool.output(hash))
+            },
+            self.block_height(),
+            self.lock_time(),
+        )?;
+        let mut touched = Self::spent_outputs(std::slice::from_ref(&transaction));
+        let removed = self.mempool.insert(transaction, fee, Utc::now())?;
+        touched.extend(Self::spent_outputs(&removed));
+        self.update_marks(&touched);
         Ok(())
     }
-    
     // Cleanup mempool - remove transactions older than
-    // MAX_MEMPOOL_TRANSACTION_AGE
+    // MAX_MEMPOOL_TRANSACTION_AGE, and everything spending
+    // their outputs
     pub fn cleanup_mempool(&mut self) {
-        let now = Utc::now();
-        let mut utxo_hashes_to_unmark: Vec<Hash> = vec![];
-        self.mempool.retain(|(timestamp, transaction)| {
-            if now - *timestamp
-                > chrono::Duration::seconds(
-                    crate::MAX_MEMPOOL_TRANSACTION_AGE as i64,
-                )
-            {
-                // push all utxos to unmark to the vector
-                // so we can unmark them later
-                utxo_hashes_to_unmark
-                    .extend(transaction.inputs.iter().map(
-                    |input| {
-                        input.prev_transaction_output_hash
-                    },
-                ));
-                false
-            } else {
-                true
+        let expired = self.mempool.remove_expired(
+            Utc::now(),
+            chrono::Duration::seconds(
+                crate::MAX_MEMPOOL_TRANSACTION_AGE as i64,
+            ),
+        );
+        self.update_marks(&Self::spent_outputs(&expired));
+    }
+    // outputs spent by the given transactions
+    fn spent_outputs(transactions: &[Transaction]) -> Vec<OutPoint> {
+        transactions
+            .iter()
+            .flat_map(|tx| tx.inputs.iter())
+            .map(|input| input.prev_output)
+            .collect()
+    }
+    // a UTXO is marked while a mempool transaction spends it
+    fn update_marks(&mut self, outputs: &[OutPoint]) {
+        for output in outputs {
+            if let Some((marked, _)) = self.utxos.get_mut(output) {
+                *marked = self.mempool.spender(output).is_some();
             }
-        });
-        // unmark all of the UTXOs
-        for hash in utxo_hashes_to_unmark {
-            self.utxos.entry(hash).and_modify(
-                |(marked, _)| {
-                    *marked = false;
-                },
-            );
         }
     }
     pub fn calculate_block_reward(&self) -> u64 {
-      let block_height = self.block_height();
-      let halvings = block_height / crate::HALVING_INTERVAL;
-      (crate::INITIAL_REWARD * 10u64.pow(8)) >> halvings
+      self.params.block_reward(self.block_height())
+    }
+    // consensus parameters of the chain
+    pub fn params(&self) -> &ChainParams {
+        &self.params
     }
 }
 
 // save and load expecting CBOR from ciborium as format
 impl Saveable for Blockchain {
   fn load<I: Read>(reader: I) -> IoResult<Self> {
-    ciborium::de::from_reader(reader).map_err(|_| {
-      IoError::new(
-        IoErrorKind::InvalidData,
-        "Failed to deserialize Blockchain",
-      )
-    })
+    let mut blockchain: Self = ciborium::de::from_reader(reader)
+      .map_err(|_| {
+        IoError::new(
+          IoErrorKind::InvalidData,
+          "Failed to deserialize Blockchain",
+        )
+      })?;
+    // the heights are not stored, they follow from the blocks
+    blockchain.heights = blockchain
+      .blocks
+      .iter()
+      .enumerate()
+      .map(|(height, block)| (block.hash(), height as u64))
+      .collect();
+    Ok(blockchain)
   }
   fn save<O: Write>(&self, writer: O) -> IoResult<()> {
     ciborium::ser::into_writer(self, writer).map_err(
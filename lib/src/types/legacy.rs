@@ -0,0 +1,289 @@
+use chrono::{DateTime, TimeDelta, Utc};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use super::{
+    Block, BlockHeader, Blockchain, OutPoint, SigHashType, SpendingCondition,
+    Transaction, TransactionInput, TransactionOutput,
+};
+use crate::crypto::{PublicKey, Signature};
+use crate::error::{BtcError, Result};
+use crate::params::ChainParams;
+use crate::pow;
+use crate::sha256::Hash;
+use crate::util::MerkleRoot;
+use crate::U256;
+use std::collections::{HashMap, HashSet};
+use std::io::Read;
+
+// Blockchain files written by versions of the node from before
+// chain parameters. Blocks were identified by the hash of the
+// whole block rather than of its header, headers stored the
+// full target rather than its compact form and inputs named
+// the output they spend by its hash, signing that hash. The
+// layout is described in full, the hashes of those versions
+// are the hashes of these types.
+//
+// Such a chain is migrated by checking it under the rules it
+// was mined with, and mining its blocks again on top of the
+// genesis block of the network, in compact form and with the
+// current transaction layout. Signatures can't be made again
+// without the keys, so the spends carry the legacy ones over:
+// the migrated chain is only valid to nodes that migrated the
+// same file, other nodes reject its first block spending coins
+
+#[derive(Serialize, Deserialize)]
+struct LegacyBlockchain {
+    blocks: Vec<LegacyBlock>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct LegacyBlock {
+    header: LegacyBlockHeader,
+    transactions: Vec<LegacyTransaction>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct LegacyBlockHeader {
+    timestamp: DateTime<Utc>,
+    nonce: u64,
+    prev_block_hash: Hash,
+    merkle_root: MerkleRoot,
+    // the full target, headers store bits now
+    target: U256,
+}
+
+#[derive(Serialize, Deserialize)]
+struct LegacyTransaction {
+    inputs: Vec<LegacyTransactionInput>,
+    outputs: Vec<LegacyTransactionOutput>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct LegacyTransactionInput {
+    prev_transaction_output_hash: Hash,
+    signature: Signature,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct LegacyTransactionOutput {
+    value: u64,
+    unique_id: Uuid,
+    pubkey: PublicKey,
+}
+
+impl LegacyTransactionOutput {
+    fn convert(&self) -> TransactionOutput {
+        TransactionOutput {
+            value: self.value,
+            unique_id: self.unique_id,
+            pubkey: self.pubkey.clone(),
+            condition: SpendingCondition::Signature,
+            timelock: None,
+        }
+    }
+}
+
+// The unspent outputs of the legacy chain, under the outpoints
+// they are migrated to. Inputs named the output they spend by
+// the hash of the output, but the UTXO set of those versions
+// was keyed by the hash of the transaction, keeping only its
+// last output, and inputs of both kinds made it into blocks
+#[derive(Default)]
+struct LegacyUtxos {
+    outputs: HashMap<OutPoint, LegacyTransactionOutput>,
+    by_output_hash: HashMap<Hash, OutPoint>,
+    by_transaction_hash: HashMap<Hash, OutPoint>,
+}
+
+impl LegacyUtxos {
+    // the unspent output an input names
+    fn resolve(&self, hash: &Hash) -> Option<OutPoint> {
+        [
+            self.by_output_hash.get(hash),
+            self.by_transaction_hash.get(hash),
+        ]
+        .into_iter()
+        .flatten()
+        .copied()
+        .find(|outpoint| self.outputs.contains_key(outpoint))
+    }
+    // Check the transactions of a block at `height` under the
+    // legacy rules and convert them, spending their inputs and
+    // adding their outputs
+    fn connect(
+        &mut self,
+        block: &LegacyBlock,
+        height: u64,
+    ) -> Result<Vec<Transaction>> {
+        let Some((coinbase, spends)) = block.transactions.split_first() else {
+            return Err(BtcError::InvalidBlock);
+        };
+        if !coinbase.inputs.is_empty() || coinbase.outputs.is_empty() {
+            return Err(BtcError::InvalidTransaction);
+        }
+        let mut transactions = vec![Transaction::new(
+            vec![],
+            coinbase.outputs.iter().map(|output| output.convert()).collect(),
+        )];
+        // inputs could only spend outputs of earlier blocks
+        let mut spent = HashSet::new();
+        let mut fees: u64 = 0;
+        for transaction in spends {
+            let mut inputs = vec![];
+            let mut input_value: u64 = 0;
+            for input in &transaction.inputs {
+                let prev_output = self
+                    .resolve(&input.prev_transaction_output_hash)
+                    .ok_or(BtcError::MissingInput)?;
+                if !spent.insert(prev_output) {
+                    return Err(BtcError::DuplicateInput);
+                }
+                let output = &self.outputs[&prev_output];
+                // legacy versions turned every hash into zero
+                // bytes before signing it, so did verification
+                if !input.signature.verify(&Hash::zero(), &output.pubkey) {
+                    return Err(BtcError::InvalidSignature);
+                }
+                input_value = input_value
+                    .checked_add(output.value)
+                    .ok_or(BtcError::ValueOverflow)?;
+                inputs.push(TransactionInput {
+                    prev_output,
+                    signature: input.signature.clone(),
+                    extra_signatures: vec![],
+                    sighash: SigHashType::ALL,
+                    sequence: TransactionInput::SEQUENCE_FINAL,
+                });
+            }
+            let output_value = transaction
+                .outputs
+                .iter()
+                .try_fold(0u64, |sum, output| sum.checked_add(output.value))
+                .ok_or(BtcError::ValueOverflow)?;
+            let fee = input_value
+                .checked_sub(output_value)
+                .ok_or(BtcError::InsufficientInputValue)?;
+            fees = fees.checked_add(fee).ok_or(BtcError::ValueOverflow)?;
+            transactions.push(Transaction::new(
+                inputs,
+                transaction.outputs.iter().map(|output| output.convert()).collect(),
+            ));
+        }
+        // the genesis coinbase went unchecked, the others had to
+        // pay out the reward of the time, which followed the
+        // mainnet schedule, and the fees
+        if height > 0 {
+            let reward = ChainParams::mainnet().block_reward(height);
+            let coinbase_value = coinbase
+                .outputs
+                .iter()
+                .try_fold(0u64, |sum, output| sum.checked_add(output.value));
+            if coinbase_value != reward.checked_add(fees) {
+                return Err(BtcError::InvalidTransaction);
+            }
+        }
+        for outpoint in spent {
+            self.outputs.remove(&outpoint);
+        }
+        for (legacy, transaction) in block.transactions.iter().zip(&transactions) {
+            let txid = transaction.hash();
+            for (vout, output) in legacy.outputs.iter().enumerate() {
+                let outpoint = OutPoint::new(txid, vout as u32);
+                self.by_output_hash.insert(Hash::hash(output), outpoint);
+                self.by_transaction_hash.insert(Hash::hash(legacy), outpoint);
+                self.outputs.insert(outpoint, output.clone());
+            }
+        }
+        Ok(transactions)
+    }
+}
+
+// Check the header of a legacy block against its parent, the
+// way legacy versions did: the genesis block only had to have
+// no parent
+fn check_legacy_header(block: &LegacyBlock, parent: Option<&LegacyBlock>) -> Result<()> {
+    let Some(parent) = parent else {
+        if block.header.prev_block_hash != Hash::zero() {
+            return Err(BtcError::InvalidGenesisBlock);
+        }
+        return Ok(());
+    };
+    if block.header.prev_block_hash != Hash::hash(parent) {
+        return Err(BtcError::UnknownParent);
+    }
+    if !Hash::hash(&block.header).matches_target(block.header.target) {
+        return Err(BtcError::InvalidBlock);
+    }
+    if block.transactions.is_empty()
+        || MerkleRoot::calculate(&block.transactions) != block.header.merkle_root
+    {
+        return Err(BtcError::InvalidMerkleRoot);
+    }
+    if block.header.timestamp <= parent.header.timestamp {
+        return Err(BtcError::TimestampTooOld);
+    }
+    Ok(())
+}
+
+impl Blockchain {
+    // Whether `reader` holds a blockchain file in the legacy
+    // format. Files that can't be read or are not blockchain
+    // files at all are not
+    pub fn is_legacy<I: Read>(reader: I) -> bool {
+        ciborium::de::from_reader::<LegacyBlockchain, _>(reader)
+            .is_ok_and(|legacy| !legacy.blocks.is_empty())
+    }
+    // Migrate the legacy blockchain file in `reader` to a chain
+    // on the network of `params`. Legacy block k becomes the
+    // block at height k + 1, with the target the network asks
+    // for at that height and its timestamp moved past genesis
+    // if it was before it. Migration stops at the first block
+    // that breaks the legacy rules, keeping the ones before it
+    pub fn migrate_legacy<I: Read>(reader: I, params: ChainParams) -> Result<Self> {
+        let legacy: LegacyBlockchain =
+            ciborium::de::from_reader(reader).map_err(|_| BtcError::InvalidBlock)?;
+        let genesis = params.genesis_block();
+        let shift = legacy.blocks.first().map_or(TimeDelta::zero(), |first| {
+            (genesis.header.timestamp + TimeDelta::seconds(1) - first.header.timestamp)
+                .max(TimeDelta::zero())
+        });
+        let mut blockchain = Blockchain::new(params);
+        blockchain.add_block(genesis)?;
+        let mut utxos = LegacyUtxos::default();
+        let mut parent = None;
+        for (height, block) in legacy.blocks.iter().enumerate() {
+            let transactions = match check_legacy_header(block, parent)
+                .and_then(|()| utxos.connect(block, height as u64))
+            {
+                Ok(transactions) => transactions,
+                Err(e) => {
+                    println!("legacy block {height} rejected: {e}");
+                    break;
+                }
+            };
+            let tip = blockchain
+                .blocks()
+                .last()
+                .expect("BUG: genesis block is missing")
+                .hash();
+            let mut header = BlockHeader::new(
+                block.header.timestamp + shift,
+                0,
+                tip,
+                MerkleRoot::calculate(&transactions),
+                pow::target_to_bits(blockchain.target()),
+            );
+            // without a step limit, mining only stops at a match
+            header.mine(usize::MAX);
+            // checked under the legacy rules already, the
+            // legacy signatures don't verify under today's
+            let block = Block::new(header, transactions);
+            if let Err(e) = blockchain.add_validated_block(block) {
+                println!("migrated block {height} rejected: {e}");
+                break;
+            }
+            parent = Some(&legacy.blocks[height]);
+        }
+        Ok(blockchain)
+    }
+}
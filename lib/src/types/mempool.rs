@@ -0,0 +1,450 @@
+use chrono::{DateTime, Duration, Utc};
+use super::{Block, OutPoint, Transaction, TransactionOutput};
+use crate::error::{BtcError, Result};
+use crate::sha256::Hash;
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+// fee per 1000 bytes
+pub fn fee_rate(fee: u64, size: u64) -> u64 {
+    let rate = fee as u128 * 1000 / size.max(1) as u128;
+    rate.min(u64::MAX as u128) as u64
+}
+
+/// A transaction waiting in the mempool
+#[derive(Clone, Debug)]
+pub struct MempoolEntry {
+    pub transaction: Transaction,
+    /// When the transaction entered the mempool
+    pub time: DateTime<Utc>,
+    /// Fee paid by the transaction
+    pub fee: u64,
+    /// Serialized size in bytes
+    pub size: u64,
+    // position in arrival order
+    sequence: u64,
+}
+
+impl MempoolEntry {
+    // fee per 1000 bytes
+    pub fn fee_rate(&self) -> u64 {
+        fee_rate(self.fee, self.size)
+    }
+}
+
+/// Transactions waiting to be included in a block, indexed
+/// by txid, by the outputs they spend and by feerate.
+/// Transactions are kept in arrival order, so parents
+/// always come before their children.
+/// Validation against the UTXO set is left to the caller,
+/// the mempool only enforces its own policy: replace-by-fee,
+/// size limits and the minimum relay fee.
+#[derive(Clone, Debug)]
+pub struct Mempool {
+    entries: HashMap<Hash, MempoolEntry>,
+    // txids by arrival
+    arrival: BTreeMap<u64, Hash>,
+    // spent output -> txid of the mempool transaction spending it
+    spent: HashMap<OutPoint, Hash>,
+    // outputs created by mempool transactions, with their txid
+    outputs: HashMap<OutPoint, (Hash, TransactionOutput)>,
+    // (fee rate, arrival) -> txid, lowest feerate first
+    by_fee_rate: BTreeMap<(u64, u64), Hash>,
+    next_sequence: u64,
+    total_size: u64,
+    max_size: u64,
+    max_count: usize,
+    // raised when transactions are evicted because the
+    // mempool is full, halving every MEMPOOL_MIN_FEE_HALF_LIFE
+    rolling_min_fee_rate: u64,
+    rolling_min_fee_updated: DateTime<Utc>,
+}
+
+impl Default for Mempool {
+    fn default() -> Self {
+        Self::new(crate::MAX_MEMPOOL_SIZE, crate::MAX_MEMPOOL_TRANSACTIONS)
+    }
+}
+
+impl Mempool {
+    /// Create an empty mempool holding at most `max_size`
+    /// bytes and `max_count` transactions
+    pub fn new(max_size: u64, max_count: usize) -> Self {
+        Mempool {
+            entries: HashMap::new(),
+            arrival: BTreeMap::new(),
+            spent: HashMap::new(),
+            outputs: HashMap::new(),
+            by_fee_rate: BTreeMap::new(),
+            next_sequence: 0,
+            total_size: 0,
+            max_size,
+            max_count,
+            rolling_min_fee_rate: 0,
+            rolling_min_fee_updated: DateTime::UNIX_EPOCH,
+        }
+    }
+    // change the limits, returns the transactions
+    // evicted to get below them
+    pub fn set_limits(
+        &mut self,
+        max_size: u64,
+        max_count: usize,
+    ) -> Vec<Transaction> {
+        self.max_size = max_size;
+        self.max_count = max_count;
+        self.trim(&HashSet::new(), Utc::now())
+    }
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+    // total size of all transactions in bytes
+    pub fn size(&self) -> u64 {
+        self.total_size
+    }
+    // size limit in bytes
+    pub fn max_size(&self) -> u64 {
+        self.max_size
+    }
+    pub fn contains(&self, txid: &Hash) -> bool {
+        self.entries.contains_key(txid)
+    }
+    pub fn get(&self, txid: &Hash) -> Option<&MempoolEntry> {
+        self.entries.get(txid)
+    }
+    // entries in arrival order, parents before children
+    pub fn iter(&self) -> impl Iterator<Item = &MempoolEntry> {
+        self.arrival.values().map(|txid| &self.entries[txid])
+    }
+    // txids in arrival order
+    pub fn txids(&self) -> impl Iterator<Item = &Hash> {
+        self.arrival.values()
+    }
+    // txid of the mempool transaction spending an output
+    pub fn spender(&self, output: &OutPoint) -> Option<&Hash> {
+        self.spent.get(output)
+    }
+    // outputs spent by mempool transactions
+    pub fn spent_outputs(&self) -> impl Iterator<Item = &OutPoint> {
+        self.spent.keys()
+    }
+    // output created by a mempool transaction
+    pub fn output(&self, output: &OutPoint) -> Option<&TransactionOutput> {
+        self.outputs.get(output).map(|(_, output)| output)
+    }
+    // outputs created by mempool transactions, and
+    // whether another mempool transaction spends them
+    pub fn outputs(
+        &self,
+    ) -> impl Iterator<Item = (&OutPoint, &TransactionOutput, bool)> {
+        self.outputs.iter().map(|(key, (_, output))| {
+            (key, output, self.spent.contains_key(key))
+        })
+    }
+    // fee rate a new transaction has to pay at least
+    pub fn min_fee_rate(&self, now: DateTime<Utc>) -> u64 {
+        let elapsed = (now - self.rolling_min_fee_updated)
+            .num_seconds()
+            .max(0) as u64;
+        let halvings = elapsed / crate::MEMPOOL_MIN_FEE_HALF_LIFE;
+        let rolling = self
+            .rolling_min_fee_rate
+            .checked_shr(halvings.min(u32::MAX as u64) as u32)
+            .unwrap_or(0);
+        rolling.max(crate::MIN_RELAY_FEE_RATE)
+    }
+    // add a transaction paying `fee`, which the caller has
+    // validated against the UTXO set and this mempool.
+    // Returns the transactions it replaced or that
+    // were evicted to make room for it
+    pub fn insert(
+        &mut self,
+        transaction: Transaction,
+        fee: u64,
+        now: DateTime<Utc>,
+    ) -> Result<Vec<Transaction>> {
+        let txid = transaction.hash();
+        if self.entries.contains_key(&txid) {
+            return Err(BtcError::TransactionAlreadyInMempool);
+        }
+        let size = transaction.size() as u64;
+        let rate = fee_rate(fee, size);
+        if rate < self.min_fee_rate(now) {
+            return Err(BtcError::FeeRateTooLow);
+        }
+        let replaced = self.check_replacement(&transaction, fee, size)?;
+        // when the mempool is full, the new transaction has
+        // to pay more than what would be evicted for it
+        let replaced_size: u64 =
+            replaced.iter().map(|txid| self.entries[txid].size).sum();
+        let full = self.total_size - replaced_size + size > self.max_size
+            || self.entries.len() - replaced.len() + 1 > self.max_count;
+        if full {
+            let lowest = self
+                .by_fee_rate
+                .values()
+                .find(|txid| !replaced.contains(*txid))
+                .map(|txid| self.entries[txid].fee_rate());
+            if lowest.is_none_or(|lowest| rate <= lowest) {
+                return Err(BtcError::MempoolFull);
+            }
+        }
+        let mut removed = self.remove(replaced);
+        let sequence = self.next_sequence;
+        self.next_sequence += 1;
+        for input in &transaction.inputs {
+            self.spent.insert(input.prev_output, txid);
+        }
+        for (key, output) in transaction.utxo_entries() {
+            self.outputs.insert(key, (txid, output.clone()));
+        }
+        self.arrival.insert(sequence, txid);
+        self.by_fee_rate.insert((rate, sequence), txid);
+        self.total_size += size;
+        self.entries.insert(
+            txid,
+            MempoolEntry {
+                transaction,
+                time: now,
+                fee,
+                size,
+                sequence,
+            },
+        );
+        // the new transaction can only be evicted together
+        // with its ancestors, keep them
+        let mut protected = self.ancestors(&txid);
+        protected.insert(txid);
+        removed.extend(self.trim(&protected, now));
+        Ok(removed)
+    }
+    // remove the transactions confirmed by a block and the
+    // ones conflicting with it. Returns the conflicting ones
+    pub fn remove_for_block(&mut self, block: &Block) -> Vec<Transaction> {
+        // children of confirmed transactions stay, they
+        // spend confirmed outputs now
+        let confirmed: HashSet<Hash> = block
+            .transactions
+            .iter()
+            .map(|tx| tx.hash())
+            .filter(|txid| self.entries.contains_key(txid))
+            .collect();
+        self.remove(confirmed);
+        let conflicts = block
+            .transactions
+            .iter()
+            .flat_map(|tx| tx.inputs.iter())
+            .filter_map(|input| {
+                self.spent.get(&input.prev_output)
+            })
+            .copied()
+            .collect();
+        let conflicts = self.with_descendants(conflicts);
+        self.remove(conflicts)
+    }
+    // remove transactions that are older than max_age, and
+    // everything spending their outputs
+    pub fn remove_expired(
+        &mut self,
+        now: DateTime<Utc>,
+        max_age: Duration,
+    ) -> Vec<Transaction> {
+        let expired = self
+            .entries
+            .iter()
+            .filter(|(_, entry)| now - entry.time > max_age)
+            .map(|(txid, _)| *txid)
+            .collect();
+        let expired = self.with_descendants(expired);
+        self.remove(expired)
+    }
+    // empty the mempool, returns all transactions
+    // in arrival order
+    pub fn take_all(&mut self) -> Vec<Transaction> {
+        let transactions = std::mem::take(&mut self.arrival)
+            .into_values()
+            .filter_map(|txid| self.entries.remove(&txid))
+            .map(|entry| entry.transaction)
+            .collect();
+        self.entries.clear();
+        self.spent.clear();
+        self.outputs.clear();
+        self.by_fee_rate.clear();
+        self.total_size = 0;
+        transactions
+    }
+    // find the mempool transactions that spend the same outputs
+    // as a new transaction paying `fee`, and check that the new
+    // one may replace them:
+    // - every conflicting transaction signals replaceability
+    // - the new one pays a higher feerate than each of them
+    // - no more than MAX_REPLACEMENT_EVICTIONS transactions,
+    //   descendants included, get evicted
+    // - the new one doesn't spend outputs of evicted ones
+    // - the new one only spends unconfirmed outputs that the
+    //   ones it replaces spent already
+    // - the new one pays more than all of them together, plus
+    //   INCREMENTAL_RELAY_FEE for its own size
+    // Returns the txids of everything that has to be evicted
+    fn check_replacement(
+        &self,
+        transaction: &Transaction,
+        fee: u64,
+        size: u64,
+    ) -> Result<HashSet<Hash>> {
+        let conflicts: HashSet<Hash> = transaction
+            .inputs
+            .iter()
+            .filter_map(|input| {
+                self.spent.get(&input.prev_output)
+            })
+            .copied()
+            .collect();
+        if conflicts.is_empty() {
+            return Ok(conflicts);
+        }
+        let rate = fee_rate(fee, size);
+        for txid in &conflicts {
+            let conflict = &self.entries[txid];
+            if !conflict.transaction.signals_rbf() {
+                return Err(BtcError::ReplacementNotSignaled);
+            }
+            if rate <= conflict.fee_rate() {
+                return Err(BtcError::ReplacementFeeRateTooLow);
+            }
+        }
+        // transactions spending outputs of evicted ones
+        // have to go as well
+        let evicted = self.with_descendants(conflicts);
+        if evicted.len() > crate::MAX_REPLACEMENT_EVICTIONS {
+            return Err(BtcError::TooManyReplacements);
+        }
+        let spends_evicted = transaction.inputs.iter().any(|input| {
+            self.outputs
+                .get(&input.prev_output)
+                .is_some_and(|(creator, _)| evicted.contains(creator))
+        });
+        if spends_evicted {
+            return Err(BtcError::ReplacementSpendsConflict);
+        }
+        let adds_unconfirmed = transaction.inputs.iter().any(|input| {
+            self.outputs.contains_key(&input.prev_output)
+                && !self.spent.contains_key(&input.prev_output)
+        });
+        if adds_unconfirmed {
+            return Err(BtcError::ReplacementAddsUnconfirmedInput);
+        }
+        let evicted_fees = evicted
+            .iter()
+            .map(|txid| self.entries[txid].fee)
+            .try_fold(0u64, |sum, fee| sum.checked_add(fee))
+            .ok_or(BtcError::ValueOverflow)?;
+        let required_fee = (crate::INCREMENTAL_RELAY_FEE * size / 1000)
+            .checked_add(evicted_fees)
+            .ok_or(BtcError::ValueOverflow)?;
+        if fee < required_fee {
+            return Err(BtcError::ReplacementFeeTooLow);
+        }
+        Ok(evicted)
+    }
+    // in-mempool transactions whose outputs a transaction spends
+    fn parents(&self, txid: &Hash) -> impl Iterator<Item = Hash> + '_ {
+        self.entries[txid]
+            .transaction
+            .inputs
+            .iter()
+            .filter_map(|input| {
+                self.outputs.get(&input.prev_output)
+            })
+            .map(|(creator, _)| *creator)
+    }
+    // in-mempool transactions spending a transaction's outputs
+    fn children(&self, txid: &Hash) -> impl Iterator<Item = Hash> + '_ {
+        self.entries[txid]
+            .transaction
+            .utxo_entries()
+            .filter_map(|(key, _)| self.spent.get(&key))
+            .copied()
+    }
+    // in-mempool transactions a transaction depends on,
+    // directly or indirectly
+    pub fn ancestors(&self, txid: &Hash) -> HashSet<Hash> {
+        let mut ancestors = HashSet::new();
+        let mut queue: Vec<Hash> = self.parents(txid).collect();
+        while let Some(parent) = queue.pop() {
+            if ancestors.insert(parent) {
+                queue.extend(self.parents(&parent));
+            }
+        }
+        ancestors
+    }
+    // the given transactions together with everything that
+    // spends their outputs, directly or indirectly
+    fn with_descendants(&self, txids: HashSet<Hash>) -> HashSet<Hash> {
+        let mut descendants = HashSet::new();
+        let mut queue: Vec<Hash> = txids.into_iter().collect();
+        while let Some(txid) = queue.pop() {
+            if descendants.insert(txid) {
+                queue.extend(self.children(&txid));
+            }
+        }
+        descendants
+    }
+    // evict the lowest feerate transactions and their
+    // descendants until the mempool fits its limits, and
+    // raise the minimum fee rate above what was evicted
+    fn trim(
+        &mut self,
+        protected: &HashSet<Hash>,
+        now: DateTime<Utc>,
+    ) -> Vec<Transaction> {
+        let mut removed = vec![];
+        while self.total_size > self.max_size
+            || self.entries.len() > self.max_count
+        {
+            let Some(lowest) = self
+                .by_fee_rate
+                .values()
+                .find(|txid| !protected.contains(*txid))
+                .copied()
+            else {
+                break;
+            };
+            let evicted_rate = self.entries[&lowest].fee_rate();
+            self.rolling_min_fee_rate = self
+                .min_fee_rate(now)
+                .max(evicted_rate.saturating_add(crate::INCREMENTAL_RELAY_FEE));
+            self.rolling_min_fee_updated = now;
+            let evicted = self.with_descendants(HashSet::from([lowest]));
+            removed.extend(self.remove(evicted));
+        }
+        removed
+    }
+    // drop transactions from all indexes,
+    // returns them in arrival order
+    fn remove(&mut self, txids: HashSet<Hash>) -> Vec<Transaction> {
+        let mut entries: Vec<MempoolEntry> = txids
+            .iter()
+            .filter_map(|txid| self.entries.remove(txid))
+            .collect();
+        entries.sort_unstable_by_key(|entry| entry.sequence);
+        for entry in &entries {
+            let txid = entry.transaction.hash();
+            self.arrival.remove(&entry.sequence);
+            self.by_fee_rate.remove(&(entry.fee_rate(), entry.sequence));
+            self.total_size -= entry.size;
+            for input in &entry.transaction.inputs {
+                let output = &input.prev_output;
+                if self.spent.get(output) == Some(&txid) {
+                    self.spent.remove(output);
+                }
+            }
+            for (key, _) in entry.transaction.utxo_entries() {
+                self.outputs.remove(&key);
+            }
+        }
+        entries.into_iter().map(|entry| entry.transaction).collect()
+    }
+}
@@ -1,8 +1,11 @@
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 use crate::crypto::{PublicKey, Signature};
+use crate::error::{BtcError, Result};
 use crate::sha256::Hash;
 use crate::util::Saveable;
+use std::fmt;
 use std::io::{
   Error as IoError, ErrorKind as IoErrorKind, Read,
   Result as IoResult, Write,
@@ -21,12 +24,196 @@ impl Transaction {
     pub fn hash(&self) -> Hash {
         Hash::hash(self)
     }
+    // the outputs of the transaction with their outpoints,
+    // which is how the UTXO set stores them
+    pub fn utxo_entries(
+        &self,
+    ) -> impl Iterator<Item = (OutPoint, &TransactionOutput)> {
+        let txid = self.hash();
+        self.outputs
+            .iter()
+            .enumerate()
+            .map(move |(vout, output)| (OutPoint::new(txid, vout as u32), output))
+    }
+    // a transaction may be replaced in the mempool if
+    // any of its inputs opts in
+    pub fn signals_rbf(&self) -> bool {
+        self.inputs.iter().any(TransactionInput::signals_rbf)
+    }
+    // size of the transaction when serialized, in bytes
+    pub fn size(&self) -> usize {
+        let mut bytes = vec![];
+        ciborium::into_writer(self, &mut bytes)
+            .expect("BUG: transaction can't be serialized");
+        bytes.len()
+    }
+    // hash that the signatures of an input have to sign,
+    // according to the input's signature hash type
+    pub fn signature_hash(&self, input_index: usize) -> Result<Hash> {
+        let input = self
+            .inputs
+            .get(input_index)
+            .ok_or(BtcError::InvalidTransactionInput)?;
+        let prev_outputs: Vec<OutPoint> = self
+            .inputs
+            .iter()
+            .map(|input| input.prev_output)
+            .collect();
+        let sequences: Vec<u32> =
+            self.inputs.iter().map(|input| input.sequence).collect();
+        Self::signature_hash_for(
+            &prev_outputs,
+            &sequences,
+            &self.outputs,
+            input_index,
+            input.sighash,
+        )
+    }
+    // signature hash computed from the parts of a transaction,
+    // so inputs can be signed before the transaction exists
+    pub fn signature_hash_for(
+        prev_outputs: &[OutPoint],
+        sequences: &[u32],
+        outputs: &[TransactionOutput],
+        input_index: usize,
+        sighash: SigHashType,
+    ) -> Result<Hash> {
+        if input_index >= prev_outputs.len()
+            || sequences.len() != prev_outputs.len()
+        {
+            return Err(BtcError::InvalidTransactionInput);
+        }
+        let (prev_outputs, sequences) = if sighash.anyone_can_pay {
+            (
+                &prev_outputs[input_index..=input_index],
+                &sequences[input_index..=input_index],
+            )
+        } else {
+            (prev_outputs, sequences)
+        };
+        let outputs = match sighash.outputs {
+            SigHashOutputs::All => outputs,
+            SigHashOutputs::None => &[],
+            SigHashOutputs::Single => {
+                // there has to be an output to commit to
+                if input_index >= outputs.len() {
+                    return Err(BtcError::InvalidSignature);
+                }
+                &outputs[input_index..=input_index]
+            }
+        };
+        Ok(Hash::hash(&SignatureHashPreimage {
+            prev_outputs,
+            sequences,
+            outputs,
+            input_index,
+            sighash,
+        }))
+    }
+}
+
+// what a signature hash commits to
+#[derive(Serialize)]
+struct SignatureHashPreimage<'a> {
+    prev_outputs: &'a [OutPoint],
+    sequences: &'a [u32],
+    outputs: &'a [TransactionOutput],
+    input_index: usize,
+    sighash: SigHashType,
+}
+
+/// Which outputs an input's signatures commit to
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SigHashOutputs {
+    /// All of them
+    #[default]
+    All,
+    /// None, anyone may change the outputs
+    None,
+    /// Only the output with the same index as the input
+    Single,
+}
+
+/// Parts of a transaction an input's signatures commit to.
+/// Besides the outputs, signatures commit to all inputs,
+/// or only to their own input if `anyone_can_pay` is set
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SigHashType {
+    pub outputs: SigHashOutputs,
+    pub anyone_can_pay: bool,
+}
+
+impl SigHashType {
+    pub const ALL: Self = SigHashType {
+        outputs: SigHashOutputs::All,
+        anyone_can_pay: false,
+    };
+    pub const NONE: Self = SigHashType {
+        outputs: SigHashOutputs::None,
+        anyone_can_pay: false,
+    };
+    pub const SINGLE: Self = SigHashType {
+        outputs: SigHashOutputs::Single,
+        anyone_can_pay: false,
+    };
+    // commit only to the input being signed
+    pub fn anyone_can_pay(self) -> Self {
+        SigHashType {
+            anyone_can_pay: true,
+            ..self
+        }
+    }
+}
+
+/// Identifies an output: the transaction that created
+/// it and its position among that transaction's outputs
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct OutPoint {
+    pub txid: Hash,
+    pub vout: u32,
+}
+
+impl OutPoint {
+    pub fn new(txid: Hash, vout: u32) -> Self {
+        OutPoint { txid, vout }
+    }
+}
+
+impl fmt::Display for OutPoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.txid, self.vout)
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct TransactionInput {
-    pub prev_transaction_output_hash: Hash,
+    /// The output this input spends
+    pub prev_output: OutPoint,
     pub signature: Signature, // dummy types, will be replaced later
+    /// Signatures following `signature` when spending
+    /// a multisig output
+    #[serde(default)]
+    pub extra_signatures: Vec<Signature>,
+    /// What the signatures of this input commit to
+    #[serde(default)]
+    pub sighash: SigHashType,
+    /// Sequence number, anything up to `SEQUENCE_RBF`
+    /// signals that the transaction may be replaced
+    #[serde(default = "TransactionInput::default_sequence")]
+    pub sequence: u32,
+}
+
+impl TransactionInput {
+    /// Sequence of inputs that opt out of replace-by-fee
+    pub const SEQUENCE_FINAL: u32 = u32::MAX;
+    /// Highest sequence that opts into replace-by-fee
+    pub const SEQUENCE_RBF: u32 = u32::MAX - 2;
+    fn default_sequence() -> u32 {
+        Self::SEQUENCE_FINAL
+    }
+    pub fn signals_rbf(&self) -> bool {
+        self.sequence <= Self::SEQUENCE_RBF
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -34,12 +221,110 @@ pub struct TransactionOutput {
     pub value: u64,
     pub unique_id: Uuid,
     pub pubkey: PublicKey, // dummy types, will be replaced later
+    /// What it takes to spend this output
+    #[serde(default)]
+    pub condition: SpendingCondition,
+    /// The output can't be spent before this point
+    #[serde(default)]
+    pub timelock: Option<Timelock>,
+}
+
+/// Conditions an input has to satisfy to spend an output
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub enum SpendingCondition {
+    /// A signature from the output's pubkey
+    #[default]
+    Signature,
+    /// Signatures from at least `required` of the `pubkeys`,
+    /// given in the same order as the keys. The output's
+    /// pubkey only identifies who it was sent to
+    MultiSig {
+        required: usize,
+        pubkeys: Vec<PublicKey>,
+    },
+}
+
+/// Earliest block an output can be spent in
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Timelock {
+    /// Block height
+    Height(u64),
+    /// Median time past of the chain the block extends
+    Time(DateTime<Utc>),
+}
+
+impl Timelock {
+    // check if an output with this lock can be spent in a
+    // block with the given height, extending a chain with
+    // the given median time past
+    pub fn is_unlocked(&self, height: u64, median_time_past: DateTime<Utc>) -> bool {
+        match *self {
+            Timelock::Height(lock_height) => height >= lock_height,
+            Timelock::Time(lock_time) => median_time_past >= lock_time,
+        }
+    }
 }
 
 impl TransactionOutput {
     pub fn hash(&self) -> Hash {
         Hash::hash(self)
     }
+    // reject spending conditions that can never be
+    // satisfied or don't need a signature at all
+    pub fn verify_condition(&self) -> Result<()> {
+        if let SpendingCondition::MultiSig { required, pubkeys } = &self.condition {
+            if *required == 0 || *required > pubkeys.len() {
+                return Err(BtcError::InvalidSpendingCondition);
+            }
+        }
+        Ok(())
+    }
+    // check that an input satisfies this output's spending
+    // conditions, when spent in a block with the given
+    // height, extending a chain with the given median time
+    // past. The input's signatures have to sign the given
+    // signature hash
+    pub fn verify_spend(
+        &self,
+        input: &TransactionInput,
+        signature_hash: &Hash,
+        height: u64,
+        median_time_past: DateTime<Utc>,
+    ) -> Result<()> {
+        if let Some(timelock) = &self.timelock {
+            if !timelock.is_unlocked(height, median_time_past) {
+                return Err(BtcError::OutputLocked);
+            }
+        }
+        let message = signature_hash;
+        match &self.condition {
+            SpendingCondition::Signature => {
+                if !input.extra_signatures.is_empty()
+                    || !input.signature.verify(message, &self.pubkey)
+                {
+                    return Err(BtcError::InvalidSignature);
+                }
+            }
+            SpendingCondition::MultiSig { required, pubkeys } => {
+                let signatures = std::iter::once(&input.signature)
+                    .chain(input.extra_signatures.iter());
+                // every signature has to match one of the
+                // keys that follow the previously matched one
+                let mut keys = pubkeys.iter();
+                let mut valid = 0;
+                for signature in signatures {
+                    if !keys.any(|key| signature.verify(message, key)) {
+                        return Err(BtcError::InvalidSignature);
+                    }
+                    valid += 1;
+                }
+                if valid < *required {
+                    return Err(BtcError::InvalidSignature);
+                }
+            }
+        }
+        Ok(())
+    }
 }
 
 // save and load expecting CBOR from ciborium as format
@@ -0,0 +1,72 @@
+use serde::{Deserialize, Serialize};
+use super::Block;
+use crate::sha256::Hash;
+use std::collections::HashMap;
+
+/// Where a transaction of the active chain is
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TxLocation {
+    /// Hash of the block containing the transaction
+    pub block: Hash,
+    /// Height of that block
+    pub height: u64,
+    /// Position of the transaction in the block
+    pub position: u32,
+}
+
+/// Locations of the transactions of the active chain, by txid.
+/// Kept up to date as blocks are connected and disconnected
+#[derive(Clone, Debug, Default)]
+pub struct TxIndex {
+    locations: HashMap<Hash, TxLocation>,
+}
+
+impl TxIndex {
+    pub fn new() -> Self {
+        TxIndex::default()
+    }
+    // index the blocks of an active chain, in order
+    pub fn build<'a>(blocks: impl Iterator<Item = &'a Block>) -> Self {
+        let mut index = TxIndex::new();
+        for (height, block) in blocks.enumerate() {
+            index.connect_block(block, height as u64);
+        }
+        index
+    }
+    pub fn len(&self) -> usize {
+        self.locations.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.locations.is_empty()
+    }
+    pub fn get(&self, txid: &Hash) -> Option<&TxLocation> {
+        self.locations.get(txid)
+    }
+    // add the transactions of a block connected at `height`
+    pub fn connect_block(&mut self, block: &Block, height: u64) {
+        let hash = block.hash();
+        for (position, transaction) in block.transactions.iter().enumerate() {
+            self.locations.insert(
+                transaction.hash(),
+                TxLocation {
+                    block: hash,
+                    height,
+                    position: position as u32,
+                },
+            );
+        }
+    }
+    // remove the transactions of a block that
+    // was disconnected from the active chain
+    pub fn disconnect_block(&mut self, block: &Block) {
+        let hash = block.hash();
+        for transaction in &block.transactions {
+            let txid = transaction.hash();
+            if self.locations.get(&txid).map(|location| location.block)
+                == Some(hash)
+            {
+                self.locations.remove(&txid);
+            }
+        }
+    }
+}
@@ -2,13 +2,13 @@ use std::{fs::File, io::{Read, Result as IoResult, Write}, path::Path};
 
 use serde::{Deserialize, Serialize};
 
-use crate::{sha256::Hash, types::Transaction};
+use crate::sha256::Hash;
 
 #[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
 pub struct MerkleRoot(Hash);
 impl MerkleRoot {
     // calculate the merkle root of a block's transactions
-    pub fn calculate(transactions: &[Transaction]) -> MerkleRoot {
+    pub fn calculate<T: Serialize>(transactions: &[T]) -> MerkleRoot {
         let mut layer: Vec<Hash> = vec![];
         for transaction in transactions {
             layer.push(Hash::hash(transaction));
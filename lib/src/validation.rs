@@ -0,0 +1,80 @@
+use std::collections::HashSet;
+
+use chrono::{DateTime, Utc};
+
+use crate::error::{BtcError, Result};
+use crate::types::{OutPoint, Transaction, TransactionOutput};
+
+// Transaction checks shared by mempool admission and block
+// validation, so a transaction that gets relayed would also
+// be accepted in a block
+
+// check everything about a regular (non-coinbase) transaction
+// that does not depend on the chain state
+pub fn check_transaction(transaction: &Transaction) -> Result<()> {
+    if transaction.inputs.is_empty() {
+        return Err(BtcError::EmptyInputs);
+    }
+    if transaction.outputs.is_empty() {
+        return Err(BtcError::EmptyOutputs);
+    }
+    if transaction.size() > crate::MAX_TRANSACTION_SIZE {
+        return Err(BtcError::TransactionTooLarge);
+    }
+    let mut spent = HashSet::new();
+    for input in &transaction.inputs {
+        if !spent.insert(input.prev_output) {
+            return Err(BtcError::DuplicateInput);
+        }
+    }
+    for output in &transaction.outputs {
+        if output.value == 0 {
+            return Err(BtcError::ZeroValueOutput);
+        }
+        output.verify_condition()?;
+    }
+    output_value(transaction)?;
+    Ok(())
+}
+
+// check a transaction against the outputs it spends, for
+// inclusion in a block with the given height, extending a
+// chain with the given median time past. Returns the fee
+// it pays
+pub fn check_transaction_inputs<'a>(
+    transaction: &Transaction,
+    prev_output: impl Fn(&OutPoint) -> Option<&'a TransactionOutput>,
+    height: u64,
+    median_time_past: DateTime<Utc>,
+) -> Result<u64> {
+    let mut input_value: u64 = 0;
+    for (input_index, input) in transaction.inputs.iter().enumerate() {
+        let prev_output = prev_output(&input.prev_output)
+            .ok_or(BtcError::MissingInput)?;
+        // check if the input satisfies the output's
+        // spending conditions
+        prev_output.verify_spend(
+            input,
+            &transaction.signature_hash(input_index)?,
+            height,
+            median_time_past,
+        )?;
+        input_value = input_value
+            .checked_add(prev_output.value)
+            .ok_or(BtcError::ValueOverflow)?;
+    }
+    // It is fine for output value to be less than input value
+    // as the difference is the fee for the miner
+    input_value
+        .checked_sub(output_value(transaction)?)
+        .ok_or(BtcError::InsufficientInputValue)
+}
+
+// total value of a transaction's outputs
+pub fn output_value(transaction: &Transaction) -> Result<u64> {
+    transaction
+        .outputs
+        .iter()
+        .try_fold(0u64, |sum, output| sum.checked_add(output.value))
+        .ok_or(BtcError::ValueOverflow)
+}
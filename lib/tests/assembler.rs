@@ -0,0 +1,86 @@
+// Transaction selection for block templates: packages of a
+// transaction and its unconfirmed ancestors go in by feerate,
+// within the size limit, parents before children
+
+mod common;
+
+use btclib::assembler::BlockAssembler;
+use btclib::crypto::PrivateKey;
+use btclib::sha256::Hash;
+use btclib::types::{Mempool, OutPoint, Transaction};
+use chrono::Utc;
+use common::spend;
+
+// a transaction spending an output we don't know about
+fn independent(vout: u32, key: &PrivateKey) -> Transaction {
+    spend(OutPoint::new(Hash::zero(), vout), 100_000, key, 1)
+}
+
+// a transaction spending the first output of `parent`
+fn child(parent: &Transaction, key: &PrivateKey) -> Transaction {
+    spend(OutPoint::new(parent.hash(), 0), parent.outputs[0].value, key, 1)
+}
+
+// insert `transaction` paying `rate` satoshis per byte
+fn insert(mempool: &mut Mempool, transaction: &Transaction, rate: u64) -> u64 {
+    let size = transaction.size() as u64;
+    mempool.insert(transaction.clone(), size * rate, Utc::now()).unwrap();
+    size
+}
+
+fn selected(mempool: &Mempool, max_size: u64) -> Vec<Hash> {
+    BlockAssembler::new(max_size)
+        .select_transactions(mempool, max_size)
+        .into_iter()
+        .map(|entry| entry.transaction.hash())
+        .collect()
+}
+
+#[test]
+fn children_pay_for_their_parents() {
+    let key = PrivateKey::new_key();
+    let mut mempool = Mempool::default();
+    let other = independent(0, &key);
+    let parent = independent(1, &key);
+    let child = child(&parent, &key);
+    insert(&mut mempool, &other, 3);
+    let parent_size = insert(&mut mempool, &parent, 1);
+    let child_size = insert(&mut mempool, &child, 10);
+    // room for one package: the parent, paying less than the
+    // other transaction, goes in for its child
+    let package_size = parent_size + child_size;
+    assert_eq!(selected(&mempool, package_size), vec![parent.hash(), child.hash()]);
+    // the child never goes in without its parent
+    let without_room = selected(&mempool, package_size - 1);
+    assert_eq!(without_room[0], other.hash());
+    assert!(!without_room.contains(&child.hash()));
+    // everything, parents before children
+    assert_eq!(
+        selected(&mempool, u64::MAX),
+        vec![other.hash(), parent.hash(), child.hash()]
+    );
+}
+
+#[test]
+fn selected_ancestors_leave_the_packages_of_their_descendants() {
+    let key = PrivateKey::new_key();
+    let mut mempool = Mempool::default();
+    // a parent with two children, one paying a lot
+    let parent = spend(OutPoint::new(Hash::zero(), 0), 200_000, &key, 2);
+    let rich = spend(OutPoint::new(parent.hash(), 0), parent.outputs[0].value, &key, 1);
+    let poor = spend(OutPoint::new(parent.hash(), 1), parent.outputs[1].value, &key, 1);
+    let other = independent(1, &key);
+    let parent_size = insert(&mut mempool, &parent, 1);
+    let rich_size = insert(&mut mempool, &rich, 20);
+    let poor_size = insert(&mut mempool, &poor, 4);
+    let other_size = insert(&mut mempool, &other, 3);
+    // the parent together with the poor child pays less than
+    // the other transaction, the poor child alone more. It goes
+    // in before the other transaction once the parent is in
+    let max_size = parent_size + rich_size + poor_size;
+    assert!(max_size < parent_size + rich_size + other_size + poor_size);
+    assert_eq!(
+        selected(&mempool, max_size),
+        vec![parent.hash(), rich.hash(), poor.hash()]
+    );
+}
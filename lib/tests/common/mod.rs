@@ -0,0 +1,106 @@
+// Helpers shared by the integration tests, each
+// test crate only uses some of them
+#![allow(dead_code)]
+
+use btclib::crypto::{PrivateKey, Signature};
+use btclib::params::ChainParams;
+use btclib::pow::target_to_bits;
+use btclib::types::{
+    Block, BlockHeader, OutPoint, SigHashType, SpendingCondition, Transaction,
+    TransactionInput, TransactionOutput,
+};
+use btclib::util::MerkleRoot;
+use chrono::{DateTime, Utc};
+use uuid::Uuid;
+
+// fee every output of a `spend` costs its inputs
+pub const FEE_PER_OUTPUT: u64 = 1000;
+
+pub fn output(value: u64, key: &PrivateKey) -> TransactionOutput {
+    TransactionOutput {
+        value,
+        unique_id: Uuid::new_v4(),
+        pubkey: key.public_key(),
+        condition: SpendingCondition::Signature,
+        timelock: None,
+    }
+}
+
+pub fn coinbase(
+    params: &ChainParams,
+    height: u64,
+    fees: u64,
+    key: &PrivateKey,
+) -> Transaction {
+    Transaction::new(
+        vec![],
+        vec![output(params.block_reward(height) + fees, key)],
+    )
+}
+
+// Spend `value` held by `key` at `prev` into
+// `outputs` equal outputs back to the key
+pub fn spend(
+    prev: OutPoint,
+    value: u64,
+    key: &PrivateKey,
+    outputs: usize,
+) -> Transaction {
+    let outputs: Vec<TransactionOutput> = (0..outputs)
+        .map(|_| output(value / outputs as u64 - FEE_PER_OUTPUT, key))
+        .collect();
+    let sequences = [TransactionInput::SEQUENCE_FINAL];
+    let hash = Transaction::signature_hash_for(
+        &[prev],
+        &sequences,
+        &outputs,
+        0,
+        SigHashType::ALL,
+    )
+    .unwrap();
+    let input = TransactionInput {
+        prev_output: prev,
+        signature: Signature::sign(&hash, key),
+        extra_signatures: vec![],
+        sighash: SigHashType::ALL,
+        sequence: TransactionInput::SEQUENCE_FINAL,
+    };
+    Transaction::new(vec![input], outputs)
+}
+
+// Mine a block on `parent` with the given
+// transactions, coinbase first
+pub fn mine_block_with(
+    parent: &Block,
+    bits: u32,
+    timestamp: DateTime<Utc>,
+    transactions: Vec<Transaction>,
+) -> Block {
+    let mut header = BlockHeader::new(
+        timestamp,
+        0,
+        parent.hash(),
+        MerkleRoot::calculate(&transactions),
+        bits,
+    );
+    assert!(header.mine(usize::MAX));
+    Block::new(header, transactions)
+}
+
+// Mine a block with only a coinbase on `parent`
+pub fn mine_block(
+    params: &ChainParams,
+    parent: &Block,
+    height: u64,
+    bits: u32,
+    timestamp: DateTime<Utc>,
+    key: &PrivateKey,
+) -> Block {
+    let coinbase = coinbase(params, height, 0, key);
+    mine_block_with(parent, bits, timestamp, vec![coinbase])
+}
+
+// the bits of blocks on a chain that doesn't retarget
+pub fn min_bits(params: &ChainParams) -> u32 {
+    target_to_bits(params.min_target)
+}
@@ -0,0 +1,111 @@
+// Compact blocks rebuilt from the receiver's mempool, with
+// the missing transactions filled in from the sender
+
+mod common;
+
+use btclib::compact::{CompactBlock, PartialBlock, PrefilledTransaction};
+use btclib::crypto::PrivateKey;
+use btclib::error::BtcError;
+use btclib::params::ChainParams;
+use btclib::types::{Block, Blockchain, OutPoint, Transaction};
+use btclib::MIN_TRANSACTION_SIZE;
+use chrono::TimeDelta;
+use common::{coinbase, min_bits, mine_block, mine_block_with, spend, FEE_PER_OUTPUT};
+
+// A node whose mempool has two of the three transactions of
+// a new block on its tip, and that block
+fn node_and_block() -> (Blockchain, Block, Transaction) {
+    let params = ChainParams::regtest();
+    let key = PrivateKey::new_key();
+    let mut blockchain = Blockchain::new(params.clone());
+    let genesis = params.genesis_block();
+    let a1 = mine_block(
+        &params,
+        &genesis,
+        1,
+        min_bits(&params),
+        genesis.header.timestamp + TimeDelta::seconds(1),
+        &key,
+    );
+    blockchain.add_block(genesis).unwrap();
+    blockchain.add_block(a1.clone()).unwrap();
+    let funding = OutPoint::new(a1.transactions[0].hash(), 0);
+    let split = spend(funding, a1.transactions[0].outputs[0].value, &key, 3);
+    let first = spend(OutPoint::new(split.hash(), 0), split.outputs[0].value, &key, 1);
+    let unknown = spend(OutPoint::new(split.hash(), 1), split.outputs[1].value, &key, 1);
+    blockchain.add_to_mempool(split.clone()).unwrap();
+    blockchain.add_to_mempool(first.clone()).unwrap();
+    let block = mine_block_with(
+        &a1,
+        min_bits(&params),
+        a1.header.timestamp + TimeDelta::seconds(1),
+        vec![
+            coinbase(&params, 2, 5 * FEE_PER_OUTPUT, &key),
+            split,
+            first,
+            unknown.clone(),
+        ],
+    );
+    (blockchain, block, unknown)
+}
+
+fn partial(blockchain: &Blockchain, compact: CompactBlock) -> Result<PartialBlock, BtcError> {
+    PartialBlock::new(compact, blockchain.mempool(), blockchain.params())
+}
+
+#[test]
+fn compact_block_round_trips() {
+    let (blockchain, block, unknown) = node_and_block();
+    let compact = CompactBlock::new(&block, 7);
+    assert_eq!(compact.hash(), block.hash());
+    assert_eq!(compact.transaction_count(), 4);
+    let partial = partial(&blockchain, compact).unwrap();
+    assert_eq!(partial.hash(), block.hash());
+    // the coinbase is prefilled, two are in the mempool
+    assert_eq!(partial.missing(), vec![3]);
+    let rebuilt = partial.fill(vec![unknown]).unwrap();
+    assert_eq!(rebuilt.hash(), block.hash());
+    let txids = |block: &Block| block.transactions.iter().map(Transaction::hash).collect::<Vec<_>>();
+    assert_eq!(txids(&rebuilt), txids(&block));
+}
+
+#[test]
+fn fill_rejects_wrong_transactions() {
+    let (blockchain, block, unknown) = node_and_block();
+    let compact = CompactBlock::new(&block, 7);
+    // too few and too many
+    let missing = partial(&blockchain, compact.clone()).unwrap();
+    assert!(matches!(missing.fill(vec![]), Err(BtcError::InvalidCompactBlock)));
+    let missing = partial(&blockchain, compact.clone()).unwrap();
+    assert!(matches!(
+        missing.fill(vec![unknown.clone(), unknown]),
+        Err(BtcError::InvalidCompactBlock)
+    ));
+    // one that doesn't match the merkle root
+    let missing = partial(&blockchain, compact).unwrap();
+    let other = block.transactions[1].clone();
+    assert!(matches!(missing.fill(vec![other]), Err(BtcError::InvalidMerkleRoot)));
+}
+
+#[test]
+fn malformed_compact_blocks_are_rejected() {
+    let (blockchain, block, _) = node_and_block();
+    // prefilled transactions out of range or order
+    let mut compact = CompactBlock::new(&block, 7);
+    compact.prefilled[0].index = 4;
+    assert!(matches!(partial(&blockchain, compact), Err(BtcError::InvalidCompactBlock)));
+    let mut compact = CompactBlock::new(&block, 7);
+    let coinbase = compact.prefilled[0].transaction.clone();
+    compact.prefilled.push(PrefilledTransaction {
+        index: 0,
+        transaction: coinbase,
+    });
+    compact.short_ids.pop();
+    assert!(matches!(partial(&blockchain, compact), Err(BtcError::InvalidCompactBlock)));
+    // more transactions than fit in a block
+    let mut compact = CompactBlock::new(&block, 7);
+    let max_count = blockchain.params().max_block_size as usize / MIN_TRANSACTION_SIZE;
+    let short_id = compact.short_ids[0];
+    compact.short_ids.resize(max_count, short_id);
+    assert!(matches!(partial(&blockchain, compact), Err(BtcError::BlockTooLarge)));
+}
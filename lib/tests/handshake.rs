@@ -0,0 +1,81 @@
+// The Version/VerAck handshake opening every connection:
+// peers on another network or speaking a protocol version
+// we don't support are refused before anything else
+
+use btclib::error::NetworkError;
+use btclib::network::{handshake, Message, Version, MIN_PROTOCOL_VERSION, NODE_NETWORK, PROTOCOL_VERSION};
+use btclib::params::ChainParams;
+use tokio::io::{duplex, DuplexStream};
+
+// both ends of a connection
+fn connection() -> (DuplexStream, DuplexStream) {
+    duplex(64 * 1024)
+}
+
+// Run the handshake on both ends, each dropping its end of
+// the connection when done, like a node refusing a peer would
+async fn handshake_both(ours: Version, theirs: Version) -> (Result<Version, NetworkError>, Result<Version, NetworkError>) {
+    let (mut a, mut b) = connection();
+    tokio::join!(
+        async move { handshake(&mut a, &ours).await },
+        async move { handshake(&mut b, &theirs).await },
+    )
+}
+
+#[tokio::test]
+async fn peers_on_the_same_network_learn_about_each_other() {
+    let params = ChainParams::regtest();
+    let node = Version::new(&params, NODE_NETWORK, 42, "/node:test/");
+    let wallet = Version::new(&params, 0, 0, "/wallet:test/");
+    let (node_learned, wallet_learned) = handshake_both(node.clone(), wallet.clone()).await;
+    let node_learned = node_learned.unwrap();
+    assert_eq!(node_learned.user_agent, wallet.user_agent);
+    assert_eq!(node_learned.services, 0);
+    let wallet_learned = wallet_learned.unwrap();
+    assert_eq!(wallet_learned.user_agent, node.user_agent);
+    assert_eq!(wallet_learned.best_height, 42);
+    assert_eq!(wallet_learned.services & NODE_NETWORK, NODE_NETWORK);
+    assert_eq!(wallet_learned.version, PROTOCOL_VERSION);
+}
+
+#[tokio::test]
+async fn peers_on_other_networks_are_refused() {
+    let ours = Version::new(&ChainParams::regtest(), NODE_NETWORK, 0, "/node:test/");
+    let theirs = Version::new(&ChainParams::mainnet(), NODE_NETWORK, 0, "/node:test/");
+    let (ours_refused, theirs_refused) = handshake_both(ours.clone(), theirs.clone()).await;
+    assert!(matches!(
+        ours_refused,
+        Err(NetworkError::WrongNetwork { theirs: magic, .. }) if magic == theirs.magic
+    ));
+    assert!(theirs_refused.is_err());
+}
+
+#[test]
+fn old_protocol_versions_and_connections_to_ourselves_are_refused() {
+    let params = ChainParams::regtest();
+    let ours = Version::new(&params, NODE_NETWORK, 0, "/node:test/");
+    let mut theirs = Version::new(&params, NODE_NETWORK, 0, "/node:old/");
+    theirs.version = MIN_PROTOCOL_VERSION;
+    theirs.check_compatible(&ours).unwrap();
+    theirs.version = MIN_PROTOCOL_VERSION - 1;
+    assert!(matches!(
+        theirs.check_compatible(&ours),
+        Err(NetworkError::UnsupportedVersion(version)) if version == MIN_PROTOCOL_VERSION - 1
+    ));
+    // our own Version coming back
+    assert!(matches!(ours.check_compatible(&ours), Err(NetworkError::SelfConnection)));
+}
+
+#[tokio::test]
+async fn other_messages_before_the_handshake_are_refused() {
+    let ours = Version::new(&ChainParams::regtest(), NODE_NETWORK, 0, "/node:test/");
+    let (mut a, mut b) = connection();
+    let peer = async move {
+        Message::DiscoverNodes.send_async(&mut b).await.unwrap();
+        // the Version sent on opening, then the connection closes
+        assert!(matches!(Message::receive_async(&mut b).await, Ok(Message::Version(_))));
+        assert!(Message::receive_async(&mut b).await.is_err());
+    };
+    let (refused, ()) = tokio::join!(async move { handshake(&mut a, &ours).await }, peer);
+    assert!(matches!(refused, Err(NetworkError::UnexpectedMessage(_))));
+}
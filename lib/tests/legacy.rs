@@ -0,0 +1,96 @@
+// Blockchain files written before chain parameters have to be
+// told apart from current and corrupt files, and migrated. The
+// fixture is a chain of three blocks, the last spending the
+// genesis coinbase, saved by such a version
+
+use btclib::params::ChainParams;
+use btclib::pow::target_to_bits;
+use btclib::types::{Blockchain, OutPoint};
+use btclib::util::Saveable;
+use ciborium::Value;
+use std::fs::File;
+use std::io::ErrorKind;
+
+const LEGACY_FIXTURE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/legacy_blockchain.cbor");
+
+#[test]
+fn legacy_files_are_recognized() {
+    let error = Blockchain::load_from_file(LEGACY_FIXTURE).unwrap_err();
+    assert_eq!(error.kind(), ErrorKind::InvalidData);
+    assert!(Blockchain::is_legacy(File::open(LEGACY_FIXTURE).unwrap()));
+}
+
+#[test]
+fn current_and_corrupt_files_are_not_legacy() {
+    let params = ChainParams::regtest();
+    let mut blockchain = Blockchain::new(params.clone());
+    blockchain.add_block(params.genesis_block()).unwrap();
+    let mut current = vec![];
+    blockchain.save(&mut current).unwrap();
+    assert!(Blockchain::load(current.as_slice()).is_ok());
+    assert!(!Blockchain::is_legacy(current.as_slice()));
+    // a legacy file cut short is just corrupt
+    let legacy = std::fs::read(LEGACY_FIXTURE).unwrap();
+    assert!(!Blockchain::is_legacy(&legacy[..legacy.len() / 2]));
+    assert!(!Blockchain::is_legacy(&b"not a blockchain"[..]));
+}
+
+#[test]
+fn legacy_chains_are_migrated() {
+    let params = ChainParams::regtest();
+    let file = File::open(LEGACY_FIXTURE).unwrap();
+    let migrated = Blockchain::migrate_legacy(file, params.clone()).unwrap();
+    // the network's genesis block and the three legacy blocks
+    assert_eq!(migrated.block_height(), 4);
+    let blocks: Vec<_> = migrated.blocks().collect();
+    assert_eq!(blocks[0].hash(), params.genesis_block().hash());
+    for pair in blocks.windows(2) {
+        let (parent, block) = (pair[0], pair[1]);
+        assert_eq!(block.header.prev_block_hash, parent.hash());
+        assert_eq!(block.header.bits, target_to_bits(params.min_target));
+        assert!(block.hash().matches_target(block.header.target()));
+        assert!(block.header.timestamp > parent.header.timestamp);
+    }
+    // the spend of the legacy genesis coinbase is carried over
+    let spend = &blocks[3].transactions[1];
+    let legacy_coinbase = blocks[1].transactions[0].hash();
+    assert_eq!(spend.inputs[0].prev_output, OutPoint::new(legacy_coinbase, 0));
+    assert!(!migrated.utxos().contains_key(&spend.inputs[0].prev_output));
+    assert!(migrated.utxos().contains_key(&OutPoint::new(spend.hash(), 0)));
+    assert!(migrated.check_utxos());
+    // and the migrated blocks load again the way stored ones do
+    let mut reloaded = Blockchain::new(params);
+    for block in migrated.blocks() {
+        reloaded.add_validated_block(block.clone()).unwrap();
+    }
+    assert_eq!(reloaded.utxos().len(), migrated.utxos().len());
+}
+
+#[test]
+fn legacy_chains_are_migrated_up_to_the_first_invalid_block() {
+    // raise an output of the spend in the last block, which
+    // no longer matches its merkle root then
+    let file = File::open(LEGACY_FIXTURE).unwrap();
+    let mut legacy: Value = ciborium::from_reader(file).unwrap();
+    let block = &mut field(&mut legacy, "blocks").as_array_mut().unwrap()[2];
+    let transaction = &mut field(block, "transactions").as_array_mut().unwrap()[1];
+    let output = &mut field(transaction, "outputs").as_array_mut().unwrap()[0];
+    let amount = field(output, "value");
+    *amount = Value::from(u64::try_from(amount.as_integer().unwrap()).unwrap() + 1);
+    let mut tampered = vec![];
+    ciborium::into_writer(&legacy, &mut tampered).unwrap();
+    let migrated = Blockchain::migrate_legacy(tampered.as_slice(), ChainParams::regtest()).unwrap();
+    assert_eq!(migrated.block_height(), 3);
+    assert!(migrated.check_utxos());
+    assert!(Blockchain::migrate_legacy(&b"not a blockchain"[..], ChainParams::regtest()).is_err());
+}
+
+// the value of a field of a CBOR map
+fn field<'a>(map: &'a mut Value, name: &str) -> &'a mut Value {
+    map.as_map_mut()
+        .unwrap()
+        .iter_mut()
+        .find(|(key, _)| key.as_text() == Some(name))
+        .map(|(_, value)| value)
+        .unwrap()
+}
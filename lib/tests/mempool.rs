@@ -0,0 +1,238 @@
+// Mempool policy: the replace-by-fee rules a conflicting
+// transaction has to meet, and the limits that evict the
+// lowest feerate transactions and raise the minimum fee.
+// The mempool leaves validation against the UTXO set to the
+// caller, so the transactions here spend made up outputs
+
+mod common;
+
+use btclib::crypto::{PrivateKey, Signature};
+use btclib::error::BtcError;
+use btclib::sha256::Hash;
+use btclib::types::{fee_rate, Mempool, OutPoint, SigHashType, Transaction, TransactionInput};
+use btclib::{INCREMENTAL_RELAY_FEE, MAX_REPLACEMENT_EVICTIONS, MEMPOOL_MIN_FEE_HALF_LIFE, MIN_RELAY_FEE_RATE};
+use chrono::{DateTime, TimeDelta, Utc};
+use common::output;
+
+// an output of a confirmed transaction
+fn confirmed(vout: u32) -> OutPoint {
+    OutPoint::new(Hash::zero(), vout)
+}
+
+// A transaction spending `inputs` into `outputs` outputs,
+// opting into replacement if `rbf`. Signatures are not
+// checked by the mempool, any will do
+fn transaction(key: &PrivateKey, inputs: &[OutPoint], outputs: usize, rbf: bool) -> Transaction {
+    let sequence = if rbf {
+        TransactionInput::SEQUENCE_RBF
+    } else {
+        TransactionInput::SEQUENCE_FINAL
+    };
+    let inputs = inputs
+        .iter()
+        .map(|prev_output| TransactionInput {
+            prev_output: *prev_output,
+            signature: Signature::sign(&Hash::zero(), key),
+            extra_signatures: vec![],
+            sighash: SigHashType::ALL,
+            sequence,
+        })
+        .collect();
+    Transaction::new(inputs, (0..outputs).map(|_| output(1000, key)).collect())
+}
+
+// the fee paying `rate` per 1000 bytes for a transaction
+fn fee_at(transaction: &Transaction, rate: u64) -> u64 {
+    transaction.size() as u64 * rate / 1000
+}
+
+// the lowest fee at which a transaction pays more than `rate`
+fn fee_above(transaction: &Transaction, rate: u64) -> u64 {
+    ((rate + 1) * transaction.size() as u64).div_ceil(1000)
+}
+
+fn now() -> DateTime<Utc> {
+    Utc::now()
+}
+
+#[test]
+fn replaced_transactions_have_to_signal() {
+    let key = PrivateKey::new_key();
+    let mut mempool = Mempool::default();
+    let original = transaction(&key, &[confirmed(0)], 1, false);
+    mempool.insert(original.clone(), fee_at(&original, 2000), now()).unwrap();
+    let replacement = transaction(&key, &[confirmed(0)], 1, true);
+    let error = mempool.insert(replacement.clone(), 1_000_000, now()).unwrap_err();
+    assert!(matches!(error, BtcError::ReplacementNotSignaled));
+    assert!(mempool.contains(&original.hash()));
+}
+
+#[test]
+fn replacements_pay_a_higher_feerate() {
+    let key = PrivateKey::new_key();
+    let mut mempool = Mempool::default();
+    let original = transaction(&key, &[confirmed(0)], 1, true);
+    let original_fee = fee_at(&original, 20_000);
+    let original_rate = fee_rate(original_fee, original.size() as u64);
+    mempool.insert(original.clone(), original_fee, now()).unwrap();
+    // a bigger replacement, so paying the same feerate
+    // is more than enough in absolute terms
+    let replacement = transaction(&key, &[confirmed(0)], 10, true);
+    let fee = fee_above(&replacement, original_rate);
+    assert!(fee > original_fee + INCREMENTAL_RELAY_FEE * replacement.size() as u64 / 1000);
+    let error = mempool.insert(replacement.clone(), fee - 1, now()).unwrap_err();
+    assert!(matches!(error, BtcError::ReplacementFeeRateTooLow));
+    let replaced = mempool.insert(replacement.clone(), fee, now()).unwrap();
+    assert_eq!(replaced.iter().map(|tx| tx.hash()).collect::<Vec<_>>(), vec![original.hash()]);
+    assert!(mempool.contains(&replacement.hash()));
+    assert!(!mempool.contains(&original.hash()));
+}
+
+#[test]
+fn replacements_pay_for_what_they_evict_and_their_own_size() {
+    let key = PrivateKey::new_key();
+    let mut mempool = Mempool::default();
+    let original = transaction(&key, &[confirmed(0)], 1, true);
+    let original_fee = fee_at(&original, 2000);
+    mempool.insert(original.clone(), original_fee, now()).unwrap();
+    let child = transaction(&key, &[OutPoint::new(original.hash(), 0)], 1, true);
+    let child_fee = fee_at(&child, 2000);
+    mempool.insert(child.clone(), child_fee, now()).unwrap();
+    // the child goes as well, its fee has to be paid too
+    let replacement = transaction(&key, &[confirmed(0)], 1, true);
+    let required = original_fee
+        + child_fee
+        + INCREMENTAL_RELAY_FEE * replacement.size() as u64 / 1000;
+    let error = mempool.insert(replacement.clone(), required - 1, now()).unwrap_err();
+    assert!(matches!(error, BtcError::ReplacementFeeTooLow));
+    assert_eq!(mempool.len(), 2);
+    let replaced = mempool.insert(replacement.clone(), required, now()).unwrap();
+    assert_eq!(
+        replaced.iter().map(|tx| tx.hash()).collect::<Vec<_>>(),
+        vec![original.hash(), child.hash()]
+    );
+    assert_eq!(mempool.len(), 1);
+}
+
+// a chain of `length` transactions, the first spending `start`
+fn insert_chain(mempool: &mut Mempool, key: &PrivateKey, start: OutPoint, length: usize) {
+    let mut prev_output = start;
+    for _ in 0..length {
+        let transaction = transaction(key, &[prev_output], 1, true);
+        prev_output = OutPoint::new(transaction.hash(), 0);
+        mempool.insert(transaction.clone(), fee_at(&transaction, 2000), now()).unwrap();
+    }
+}
+
+#[test]
+fn replacements_evict_a_limited_number_of_transactions() {
+    let key = PrivateKey::new_key();
+    let mut mempool = Mempool::default();
+    insert_chain(&mut mempool, &key, confirmed(0), MAX_REPLACEMENT_EVICTIONS);
+    insert_chain(&mut mempool, &key, confirmed(1), MAX_REPLACEMENT_EVICTIONS + 1);
+    let replacement = transaction(&key, &[confirmed(1)], 1, true);
+    let error = mempool.insert(replacement, 1_000_000, now()).unwrap_err();
+    assert!(matches!(error, BtcError::TooManyReplacements));
+    let replacement = transaction(&key, &[confirmed(0)], 1, true);
+    let replaced = mempool.insert(replacement, 1_000_000, now()).unwrap();
+    assert_eq!(replaced.len(), MAX_REPLACEMENT_EVICTIONS);
+    assert_eq!(mempool.len(), MAX_REPLACEMENT_EVICTIONS + 2);
+}
+
+#[test]
+fn replacements_only_spend_unconfirmed_outputs_already_spent() {
+    let key = PrivateKey::new_key();
+    let mut mempool = Mempool::default();
+    let parent = transaction(&key, &[confirmed(1)], 2, true);
+    mempool.insert(parent.clone(), fee_at(&parent, 2000), now()).unwrap();
+    let original = transaction(&key, &[confirmed(0), OutPoint::new(parent.hash(), 0)], 1, true);
+    mempool.insert(original.clone(), fee_at(&original, 2000), now()).unwrap();
+    // the other output of the parent is unconfirmed and
+    // not spent by the transaction being replaced
+    let inputs = [confirmed(0), OutPoint::new(parent.hash(), 0), OutPoint::new(parent.hash(), 1)];
+    let replacement = transaction(&key, &inputs, 1, true);
+    let error = mempool.insert(replacement, 1_000_000, now()).unwrap_err();
+    assert!(matches!(error, BtcError::ReplacementAddsUnconfirmedInput));
+    // spending an output of the transaction being replaced
+    let inputs = [confirmed(0), OutPoint::new(original.hash(), 0)];
+    let replacement = transaction(&key, &inputs, 1, true);
+    let error = mempool.insert(replacement, 1_000_000, now()).unwrap_err();
+    assert!(matches!(error, BtcError::ReplacementSpendsConflict));
+    // the unconfirmed output the original spent is fine
+    let replacement = transaction(&key, &[confirmed(0), OutPoint::new(parent.hash(), 0)], 1, true);
+    let replaced = mempool.insert(replacement, 1_000_000, now()).unwrap();
+    assert_eq!(replaced.iter().map(|tx| tx.hash()).collect::<Vec<_>>(), vec![original.hash()]);
+    assert!(mempool.contains(&parent.hash()));
+}
+
+#[test]
+fn full_mempools_evict_the_lowest_feerate_first() {
+    let key = PrivateKey::new_key();
+    let transactions: Vec<Transaction> =
+        (0..4).map(|vout| transaction(&key, &[confirmed(vout)], 1, false)).collect();
+    let size = transactions[0].size() as u64;
+    assert!(transactions.iter().all(|tx| tx.size() as u64 == size));
+    let mut mempool = Mempool::new(3 * size, usize::MAX);
+    let now = now();
+    for (transaction, rate) in transactions[..3].iter().zip([2000, 4000, 6000]) {
+        let removed = mempool.insert(transaction.clone(), fee_at(transaction, rate), now).unwrap();
+        assert!(removed.is_empty());
+    }
+    assert_eq!(mempool.size(), 3 * size);
+    assert_eq!(mempool.min_fee_rate(now), MIN_RELAY_FEE_RATE);
+    // paying no more than the lowest isn't enough
+    let error = mempool.insert(transactions[3].clone(), fee_at(&transactions[3], 2000), now).unwrap_err();
+    assert!(matches!(error, BtcError::MempoolFull));
+    let removed = mempool.insert(transactions[3].clone(), fee_above(&transactions[3], 2000), now).unwrap();
+    assert_eq!(removed.iter().map(|tx| tx.hash()).collect::<Vec<_>>(), vec![transactions[0].hash()]);
+    assert_eq!(mempool.size(), 3 * size);
+    // the minimum fee rate rises above what was evicted
+    assert_eq!(mempool.min_fee_rate(now), 2000 + INCREMENTAL_RELAY_FEE);
+}
+
+#[test]
+fn descendants_are_evicted_with_their_ancestors() {
+    let key = PrivateKey::new_key();
+    let mut mempool = Mempool::new(u64::MAX, 3);
+    let now = now();
+    let parent = transaction(&key, &[confirmed(0)], 1, false);
+    mempool.insert(parent.clone(), fee_at(&parent, 2000), now).unwrap();
+    let child = transaction(&key, &[OutPoint::new(parent.hash(), 0)], 1, false);
+    mempool.insert(child.clone(), fee_at(&child, 10_000), now).unwrap();
+    let other = transaction(&key, &[confirmed(1)], 1, false);
+    mempool.insert(other.clone(), fee_at(&other, 4000), now).unwrap();
+    let last = transaction(&key, &[confirmed(2)], 1, false);
+    let removed = mempool.insert(last.clone(), fee_at(&last, 5000), now).unwrap();
+    assert_eq!(
+        removed.iter().map(|tx| tx.hash()).collect::<Vec<_>>(),
+        vec![parent.hash(), child.hash()]
+    );
+    assert_eq!(mempool.len(), 2);
+    assert!(mempool.contains(&other.hash()) && mempool.contains(&last.hash()));
+}
+
+#[test]
+fn the_minimum_fee_rate_rejects_and_then_decays() {
+    let key = PrivateKey::new_key();
+    let mut mempool = Mempool::new(u64::MAX, 1);
+    let now = now();
+    let first = transaction(&key, &[confirmed(0)], 1, false);
+    mempool.insert(first.clone(), fee_at(&first, 5000), now).unwrap();
+    let second = transaction(&key, &[confirmed(1)], 1, false);
+    mempool.insert(second.clone(), fee_at(&second, 8000), now).unwrap();
+    let min_fee_rate = 5000 + INCREMENTAL_RELAY_FEE;
+    assert_eq!(mempool.min_fee_rate(now), min_fee_rate);
+    // below the raised minimum, the mempool isn't even asked
+    // whether there is room
+    let third = transaction(&key, &[confirmed(2)], 1, false);
+    let error = mempool.insert(third.clone(), fee_above(&third, min_fee_rate - 1) - 1, now).unwrap_err();
+    assert!(matches!(error, BtcError::FeeRateTooLow));
+    let error = mempool.insert(third.clone(), fee_above(&third, min_fee_rate - 1), now).unwrap_err();
+    assert!(matches!(error, BtcError::MempoolFull));
+    // halving every half-life, down to the relay minimum
+    let half_life = TimeDelta::seconds(MEMPOOL_MIN_FEE_HALF_LIFE as i64);
+    let later = now + half_life - TimeDelta::seconds(1);
+    assert_eq!(mempool.min_fee_rate(later), min_fee_rate);
+    assert_eq!(mempool.min_fee_rate(now + half_life), min_fee_rate / 2);
+    assert_eq!(mempool.min_fee_rate(now + half_life * 3), MIN_RELAY_FEE_RATE);
+}
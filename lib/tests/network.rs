@@ -0,0 +1,57 @@
+// Size limits of wire protocol messages: messages carrying
+// blocks follow the block size limit of the network
+
+use btclib::crypto::PrivateKey;
+use btclib::error::NetworkError;
+use btclib::network::{set_max_block_size, Message, MAX_MESSAGE_SIZE, MAX_UTXO_MESSAGE_SIZE};
+use btclib::params::ChainParams;
+use btclib::types::{Block, SpendingCondition, Transaction, TransactionOutput};
+use uuid::Uuid;
+
+// a block of about `size` bytes, a coinbase with many outputs
+fn block_of_size(params: &ChainParams, size: usize) -> Block {
+    let key = PrivateKey::new_key();
+    let mut block = params.genesis_block();
+    let mut outputs = vec![];
+    while block.size() < size {
+        outputs.extend((0..10).map(|_| TransactionOutput {
+            value: 1,
+            unique_id: Uuid::new_v4(),
+            pubkey: key.public_key(),
+            condition: SpendingCondition::Signature,
+            timelock: None,
+        }));
+        block.transactions = vec![Transaction::new(vec![], outputs.clone())];
+    }
+    block
+}
+
+#[test]
+fn block_messages_follow_the_block_size_limit() {
+    let mut params = ChainParams::regtest();
+    params.max_block_size = 50_000;
+    set_max_block_size(&params);
+    let fitting = Message::NewBlock(block_of_size(&params, 50_000));
+    fitting.send(&mut vec![]).unwrap();
+    let too_large = Message::NewBlock(block_of_size(&params, 70_000));
+    assert!(matches!(
+        too_large.send(&mut vec![]),
+        Err(NetworkError::MessageTooLarge { kind: 16, .. })
+    ));
+    // a larger limit lets it through
+    params.max_block_size = 100_000;
+    set_max_block_size(&params);
+    too_large.send(&mut vec![]).unwrap();
+    // and the default one as well
+    set_max_block_size(&ChainParams::mainnet());
+    too_large.send(&mut vec![]).unwrap();
+}
+
+#[test]
+fn utxo_messages_have_their_own_limit() {
+    for kind in [3, 31] {
+        let max = Message::max_size(kind).unwrap();
+        assert_eq!(max, MAX_UTXO_MESSAGE_SIZE);
+        assert!(max < MAX_MESSAGE_SIZE);
+    }
+}
@@ -0,0 +1,89 @@
+// Chain parameters: the predefined networks, custom ones
+// loaded from TOML, and chains following the rules of the
+// parameters they were created with
+
+mod common;
+
+use btclib::crypto::PrivateKey;
+use btclib::error::BtcError;
+use btclib::params::ChainParams;
+use btclib::types::{Blockchain, Transaction};
+use btclib::util::Saveable;
+use chrono::TimeDelta;
+use common::{coinbase, min_bits, mine_block, mine_block_with, output};
+
+#[test]
+fn predefined_networks_have_their_own_genesis_block() {
+    let networks = [ChainParams::mainnet(), ChainParams::testnet(), ChainParams::regtest()];
+    for params in &networks {
+        assert_eq!(ChainParams::from_name(&params.name).as_ref(), Some(params));
+        let genesis = params.genesis_block();
+        assert!(genesis.header.hash().matches_target(params.min_target));
+        let mut blockchain = Blockchain::new(params.clone());
+        for other in networks.iter().filter(|other| other.name != params.name) {
+            assert_ne!(other.magic, params.magic);
+            let error = blockchain.add_block(other.genesis_block()).unwrap_err();
+            assert!(matches!(error, BtcError::InvalidGenesisBlock));
+        }
+        blockchain.add_block(genesis).unwrap();
+    }
+    assert!(ChainParams::from_name("simnet").is_none());
+}
+
+#[test]
+fn block_rewards_halve_every_halving_interval() {
+    let params = ChainParams::regtest();
+    let initial = params.initial_reward * 100_000_000;
+    assert_eq!(params.block_reward(0), initial);
+    assert_eq!(params.block_reward(params.halving_interval - 1), initial);
+    assert_eq!(params.block_reward(params.halving_interval), initial / 2);
+    assert_eq!(params.block_reward(3 * params.halving_interval), initial / 8);
+    assert_eq!(params.block_reward(64 * params.halving_interval), 0);
+    assert_eq!(params.block_reward(u64::MAX), 0);
+}
+
+#[test]
+fn custom_networks_load_from_toml() {
+    let mut params = ChainParams::regtest();
+    params.name = "devnet".to_string();
+    params.magic = 0xDE5E_7000;
+    params.halving_interval = 2;
+    let path = std::env::temp_dir().join(format!("devnet-{}.toml", std::process::id()));
+    params.save_to_file(&path).unwrap();
+    let loaded = ChainParams::load(path.to_str().unwrap());
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(loaded.unwrap(), params);
+    // zero for a parameter the chain divides by, or a
+    // genesis block paying a key that isn't one
+    let mut invalid = params.clone();
+    invalid.difficulty_update_interval = 0;
+    let mut toml = vec![];
+    invalid.save(&mut toml).unwrap();
+    assert!(<ChainParams as Saveable>::load(toml.as_slice()).is_err());
+    let mut invalid = params.clone();
+    invalid.genesis.pubkey = "02".to_string();
+    let mut toml = vec![];
+    invalid.save(&mut toml).unwrap();
+    assert!(<ChainParams as Saveable>::load(toml.as_slice()).is_err());
+}
+
+#[test]
+fn coinbases_pay_the_reward_of_the_chain() {
+    let mut params = ChainParams::regtest();
+    params.halving_interval = 2;
+    let key = PrivateKey::new_key();
+    let mut blockchain = Blockchain::new(params.clone());
+    let genesis = params.genesis_block();
+    let b1 = mine_block(&params, &genesis, 1, min_bits(&params), genesis.header.timestamp + TimeDelta::seconds(1), &key);
+    blockchain.add_block(genesis).unwrap();
+    blockchain.add_block(b1.clone()).unwrap();
+    // the reward of the predefined regtest network,
+    // which only halves later
+    let timestamp = b1.header.timestamp + TimeDelta::seconds(1);
+    let full_reward = Transaction::new(vec![], vec![output(ChainParams::regtest().block_reward(2), &key)]);
+    let block = mine_block_with(&b1, min_bits(&params), timestamp, vec![full_reward]);
+    assert!(matches!(blockchain.add_block(block), Err(BtcError::InvalidTransaction)));
+    let block = mine_block_with(&b1, min_bits(&params), timestamp, vec![coinbase(&params, 2, 0, &key)]);
+    assert_eq!(block.transactions[0].outputs[0].value, ChainParams::regtest().block_reward(2) / 2);
+    blockchain.add_block(block).unwrap();
+}
@@ -0,0 +1,216 @@
+// Property tests of the proof of work target math. The
+// inputs are drawn from seeded generators, so a failure
+// reproduces on every run
+
+mod common;
+
+use btclib::crypto::PrivateKey;
+use btclib::params::ChainParams;
+use btclib::pow::{bits_to_target, difficulty, retarget, target_to_bits};
+use btclib::types::{Block, Blockchain};
+use btclib::U256;
+use chrono::TimeDelta;
+use common::mine_block;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+const CASES: usize = 10_000;
+
+// a target of random bit length, so small and
+// large targets are drawn equally often
+fn random_target(rng: &mut StdRng) -> U256 {
+    let words: [u64; 4] = rng.gen();
+    let target = U256(words);
+    target >> rng.gen_range(0..256)
+}
+
+#[test]
+fn compact_encoding_matches_bitcoin() {
+    let bitcoin_min = U256::from(0xffff) << 208;
+    assert_eq!(target_to_bits(bitcoin_min), 0x1d00_ffff);
+    assert_eq!(bits_to_target(0x1d00_ffff), Some(bitcoin_min));
+    let regtest_min = U256::MAX >> 1;
+    assert_eq!(target_to_bits(regtest_min), 0x207f_ffff);
+    assert_eq!(target_to_bits(U256::zero()), 0);
+    assert_eq!(target_to_bits(U256::from(0x80)), 0x0200_8000);
+    assert_eq!(bits_to_target(0x0200_8000), Some(U256::from(0x80)));
+    // negative and overflowing bits don't encode a target
+    assert_eq!(bits_to_target(0x0492_3456), None);
+    assert_eq!(bits_to_target(0xff12_3456), None);
+    assert_eq!(bits_to_target(0x2101_0000), None);
+}
+
+#[test]
+fn compact_encoding_round_trips() {
+    let mut rng = StdRng::seed_from_u64(1);
+    for _ in 0..CASES {
+        let target = random_target(&mut rng);
+        let bits = target_to_bits(target);
+        let decoded = bits_to_target(bits).expect("encoded target decodes");
+        // rounded down to the 23 bit mantissa, and
+        // encoding that again gives the same bits
+        assert!(decoded <= target);
+        assert!(target - decoded < U256::one() << target.bits().saturating_sub(15));
+        assert_eq!(target_to_bits(decoded), bits);
+    }
+}
+
+#[test]
+fn retarget_is_exact_for_small_targets() {
+    let mut rng = StdRng::seed_from_u64(2);
+    for _ in 0..CASES {
+        let target = rng.gen::<u64>() >> rng.gen_range(0..64);
+        let ideal = rng.gen_range(1..100_000u64);
+        let actual = rng.gen_range(-1_000_000..1_000_000i64);
+        let min_target = rng.gen::<u64>();
+        let clamped = actual.clamp((ideal / 4).max(1) as i64, ideal as i64 * 4) as u128;
+        let expected = (target as u128 * clamped / ideal as u128).min(min_target as u128);
+        assert_eq!(
+            retarget(U256::from(target), actual, ideal, U256::from(min_target)),
+            U256::from(expected),
+        );
+    }
+}
+
+#[test]
+fn retarget_stays_in_bounds() {
+    let mut rng = StdRng::seed_from_u64(3);
+    for _ in 0..CASES {
+        let min_target = random_target(&mut rng);
+        let target = random_target(&mut rng).min(min_target);
+        // a multiple of 4, so the bounds are exact
+        let ideal = rng.gen_range(1..250_000u64) * 4;
+        let actual = rng.gen_range(-10_000_000..10_000_000i64);
+        let new_target = retarget(target, actual, ideal, min_target);
+        assert!(new_target <= min_target);
+        assert!(new_target >= (target / 4).min(min_target));
+        assert!(new_target / 4 <= target);
+        // a longer interval never makes it harder
+        let later = retarget(target, actual.saturating_add(1), ideal, min_target);
+        assert!(later >= new_target);
+        // and an interval of the ideal length changes nothing
+        assert_eq!(retarget(target, ideal as i64, ideal, min_target), target);
+    }
+}
+
+#[test]
+fn difficulty_is_relative_to_min_target() {
+    let min_target = U256::from(0xffff) << 208;
+    assert_eq!(difficulty(min_target, min_target), 1.0);
+    assert_eq!(difficulty(min_target / 4, min_target), 4.0);
+    assert!(difficulty(U256::zero(), min_target).is_finite());
+    // the easiest target headers can encode is difficulty 1
+    let min_target = ChainParams::regtest().min_target;
+    let easiest = bits_to_target(target_to_bits(min_target)).unwrap();
+    assert!(easiest < min_target);
+    assert_eq!(difficulty(easiest, min_target), 1.0);
+}
+
+// regtest, adjusting the target every few blocks
+fn retargeting_params() -> ChainParams {
+    let mut params = ChainParams::regtest();
+    params.no_retargeting = false;
+    params.difficulty_update_interval = 5;
+    params.ideal_block_time = 2;
+    params
+}
+
+// A chain of `length` blocks after genesis, each mined at the
+// target the miner's node expects, at random intervals
+fn random_chain(
+    params: &ChainParams,
+    rng: &mut StdRng,
+    length: u64,
+    key: &PrivateKey,
+) -> Vec<Block> {
+    let mut miner = Blockchain::new(params.clone());
+    let genesis = params.genesis_block();
+    miner.add_block(genesis.clone()).unwrap();
+    let mut blocks = vec![genesis];
+    for height in 1..=length {
+        let parent = blocks.last().unwrap();
+        let gap = TimeDelta::seconds(rng.gen_range(1..=3));
+        let block = mine_block(
+            params,
+            parent,
+            height,
+            target_to_bits(miner.target()),
+            parent.header.timestamp + gap,
+            key,
+        );
+        miner.add_block(block.clone()).unwrap();
+        blocks.push(block);
+    }
+    blocks
+}
+
+#[test]
+fn retargets_are_deterministic_across_nodes() {
+    let params = retargeting_params();
+    let key = PrivateKey::new_key();
+    let mut rng = StdRng::seed_from_u64(4);
+    let blocks = random_chain(&params, &mut rng, 60, &key);
+    let min_bits = target_to_bits(params.min_target);
+    assert!(blocks.iter().any(|block| block.header.bits != min_bits));
+    // a node getting the blocks over the wire
+    // agrees on the target after every one
+    let mut node = Blockchain::new(params.clone());
+    let mut replay = Blockchain::new(params.clone());
+    for block in &blocks {
+        let mut bytes = vec![];
+        ciborium::into_writer(block, &mut bytes).unwrap();
+        let received: Block = ciborium::from_reader(bytes.as_slice()).unwrap();
+        node.add_block(received).unwrap();
+        replay.add_block(block.clone()).unwrap();
+        assert_eq!(node.target(), replay.target());
+    }
+    // a node that first followed a shorter branch
+    // reorganizes to the same chain and target
+    let mut forked = Blockchain::new(params.clone());
+    for block in &blocks[..=20] {
+        forked.add_block(block.clone()).unwrap();
+    }
+    let mut parent = blocks[20].clone();
+    for height in 21..=25 {
+        let timestamp = parent.header.timestamp + TimeDelta::seconds(7);
+        let block = mine_block(
+            &params,
+            &parent,
+            height,
+            target_to_bits(forked.target()),
+            timestamp,
+            &key,
+        );
+        forked.add_block(block.clone()).unwrap();
+        parent = block;
+    }
+    for block in &blocks[21..] {
+        forked.add_block(block.clone()).unwrap();
+    }
+    assert_eq!(forked.blocks().last().unwrap().hash(), blocks.last().unwrap().hash());
+    assert_eq!(forked.target(), node.target());
+}
+
+#[test]
+fn blocks_with_another_target_are_rejected() {
+    let params = retargeting_params();
+    let key = PrivateKey::new_key();
+    let mut rng = StdRng::seed_from_u64(5);
+    let blocks = random_chain(&params, &mut rng, 12, &key);
+    let mut node = Blockchain::new(params.clone());
+    for block in &blocks {
+        node.add_block(block.clone()).unwrap();
+    }
+    let parent = blocks.last().unwrap();
+    let timestamp = parent.header.timestamp + TimeDelta::seconds(2);
+    let expected = target_to_bits(node.target());
+    let min_bits = target_to_bits(params.min_target);
+    assert_ne!(expected, min_bits);
+    // an easier target, and a slightly harder one
+    for bits in [min_bits, expected - 1] {
+        let block = mine_block(&params, parent, 13, bits, timestamp, &key);
+        assert!(node.add_block(block).is_err());
+    }
+    let block = mine_block(&params, parent, 13, expected, timestamp, &key);
+    node.add_block(block).unwrap();
+}
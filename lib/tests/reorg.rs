@@ -0,0 +1,280 @@
+// Reorganizations across forks: the UTXO set kept up to date
+// with undo data has to match a replay of the active chain,
+// and the mempool has to get the transactions of the orphaned
+// blocks back
+
+mod common;
+
+use btclib::crypto::PrivateKey;
+use btclib::params::ChainParams;
+use btclib::sha256::Hash;
+use btclib::types::{Block, Blockchain, OutPoint, Transaction};
+use btclib::util::Saveable;
+use btclib::MAX_FORK_DEPTH;
+use chrono::TimeDelta;
+use common::{coinbase, min_bits, mine_block, mine_block_with, output, spend, FEE_PER_OUTPUT};
+
+// A regtest chain of genesis, a block paying `key` in two
+// outputs, and a block spending the first of them: one
+// transaction splitting it in two and one spending the second
+// half again. The third transaction spends the first half and
+// is left out, for the mempool. The second coinbase output
+// stays unspent
+struct Chain {
+    params: ChainParams,
+    key: PrivateKey,
+    blockchain: Blockchain,
+    blocks: Vec<Block>,
+    spare: OutPoint,
+    split: Transaction,
+    respend: Transaction,
+    pending: Transaction,
+}
+
+impl Chain {
+    fn new() -> Self {
+        let params = ChainParams::regtest();
+        let key = PrivateKey::new_key();
+        let mut blockchain = Blockchain::new(params.clone());
+        let genesis = params.genesis_block();
+        let reward = params.block_reward(1);
+        let a1_coinbase = Transaction::new(
+            vec![],
+            vec![output(reward / 2, &key), output(reward - reward / 2, &key)],
+        );
+        let a1 = mine_block_with(&genesis, min_bits(&params), at(&genesis, 1), vec![a1_coinbase]);
+        let funding = OutPoint::new(a1.transactions[0].hash(), 0);
+        let spare = OutPoint::new(a1.transactions[0].hash(), 1);
+        let split = spend(funding, a1.transactions[0].outputs[0].value, &key, 2);
+        let respend = spend(OutPoint::new(split.hash(), 1), split.outputs[1].value, &key, 1);
+        let pending = spend(OutPoint::new(split.hash(), 0), split.outputs[0].value, &key, 1);
+        let a2 = mine_block_with(
+            &a1,
+            min_bits(&params),
+            at(&genesis, 2),
+            vec![
+                coinbase(&params, 2, 3 * FEE_PER_OUTPUT, &key),
+                split.clone(),
+                respend.clone(),
+            ],
+        );
+        let blocks = vec![genesis, a1, a2];
+        for block in &blocks {
+            blockchain.add_block(block.clone()).unwrap();
+        }
+        blockchain.add_to_mempool(pending.clone()).unwrap();
+        Chain {
+            params,
+            key,
+            blockchain,
+            blocks,
+            spare,
+            split,
+            respend,
+            pending,
+        }
+    }
+    // mine `count` empty blocks on `parent`, which is at `height`
+    fn branch(&self, parent: &Block, height: u64, count: u64) -> Vec<Block> {
+        let mut branch: Vec<Block> = vec![];
+        for offset in 1..=count {
+            let parent = branch.last().unwrap_or(parent);
+            let block = mine_block(
+                &self.params,
+                parent,
+                height + offset,
+                min_bits(&self.params),
+                at(parent, 1),
+                &self.key,
+            );
+            branch.push(block);
+        }
+        branch
+    }
+    fn tip(&self) -> Hash {
+        self.blockchain.blocks().last().unwrap().hash()
+    }
+}
+
+// a timestamp `seconds` after the one of `block`
+fn at(block: &Block, seconds: i64) -> chrono::DateTime<chrono::Utc> {
+    block.header.timestamp + TimeDelta::seconds(seconds)
+}
+
+#[test]
+fn reorganization_reverts_and_restores_utxos() {
+    let mut chain = Chain::new();
+    assert!(chain.blockchain.check_utxos());
+    // a longer branch forking off before the spends
+    let branch = chain.branch(&chain.blocks[1], 1, 2);
+    chain.blockchain.add_block(branch[0].clone()).unwrap();
+    assert_eq!(chain.tip(), chain.blocks[2].hash());
+    chain.blockchain.add_block(branch[1].clone()).unwrap();
+    assert_eq!(chain.tip(), branch[1].hash());
+    assert_eq!(chain.blockchain.block_height(), 4);
+    assert!(chain.blockchain.check_utxos());
+    // the spends are unconfirmed again, and the
+    // coinbase they spend is marked in the mempool
+    let mempool = chain.blockchain.mempool();
+    assert_eq!(mempool.len(), 3);
+    for transaction in [&chain.split, &chain.respend, &chain.pending] {
+        assert!(mempool.contains(&transaction.hash()));
+    }
+    let funding = OutPoint::new(chain.blocks[1].transactions[0].hash(), 0);
+    assert_eq!(chain.blockchain.utxos().get(&funding).map(|(marked, _)| *marked), Some(true));
+    assert!(chain.blockchain.utxos().keys().all(|outpoint| outpoint.txid != chain.split.hash()));
+    assert!(chain
+        .blockchain
+        .side_blocks()
+        .any(|block| block.hash() == chain.blocks[2].hash()));
+    // and back to the first branch once it's longer again
+    let extension = chain.branch(&chain.blocks[2], 2, 2);
+    for block in &extension {
+        chain.blockchain.add_block(block.clone()).unwrap();
+    }
+    assert_eq!(chain.tip(), extension[1].hash());
+    assert!(chain.blockchain.check_utxos());
+    let mempool = chain.blockchain.mempool();
+    assert_eq!(mempool.len(), 1);
+    assert!(mempool.contains(&chain.pending.hash()));
+    let pending_input = chain.pending.inputs[0].prev_output;
+    assert_eq!(chain.blockchain.utxos().get(&pending_input).map(|(marked, _)| *marked), Some(true));
+}
+
+#[test]
+fn invalid_branch_leaves_the_active_chain_alone() {
+    let mut chain = Chain::new();
+    // an output from before the fork, spent by a pending
+    // transaction and by the branch
+    let spare_value = chain.blocks[1].transactions[0].outputs[1].value;
+    let pending_spare = spend(chain.spare, spare_value, &chain.key, 1);
+    chain.blockchain.add_to_mempool(pending_spare.clone()).unwrap();
+    let utxos = chain.blockchain.utxos().clone();
+    // a branch with more work, whose second block spends an
+    // output that only exists on the active chain
+    let valid = mine_block_with(
+        &chain.blocks[1],
+        min_bits(&chain.params),
+        at(&chain.blocks[1], 1),
+        vec![
+            coinbase(&chain.params, 2, FEE_PER_OUTPUT, &chain.key),
+            spend(chain.spare, spare_value, &chain.key, 1),
+        ],
+    );
+    let missing = OutPoint::new(chain.respend.hash(), 0);
+    let invalid = mine_block_with(
+        &valid,
+        min_bits(&chain.params),
+        at(&valid, 1),
+        vec![
+            coinbase(&chain.params, 3, FEE_PER_OUTPUT, &chain.key),
+            spend(missing, chain.respend.outputs[0].value, &chain.key, 1),
+        ],
+    );
+    chain.blockchain.add_block(valid.clone()).unwrap();
+    assert!(chain.blockchain.add_block(invalid.clone()).is_err());
+    assert_eq!(chain.tip(), chain.blocks[2].hash());
+    assert!(chain.blockchain.check_utxos());
+    assert_eq!(chain.blockchain.utxos().len(), utxos.len());
+    for (outpoint, (marked, output)) in &utxos {
+        let (now_marked, now_output) = &chain.blockchain.utxos()[outpoint];
+        assert_eq!(now_marked, marked);
+        assert_eq!(now_output.hash(), output.hash());
+    }
+    let mempool = chain.blockchain.mempool();
+    assert_eq!(mempool.len(), 2);
+    assert!(mempool.contains(&chain.pending.hash()));
+    assert!(mempool.contains(&pending_spare.hash()));
+    // the output the branch spent is marked again, so
+    // the pending spend of it can't be double spent
+    assert!(chain.blockchain.utxos()[&chain.spare].0);
+    let double_spend = spend(chain.spare, spare_value, &chain.key, 1);
+    assert!(chain.blockchain.add_to_mempool(double_spend).is_err());
+    // the valid part of the branch stays around, the
+    // invalid block is forgotten
+    let side: Vec<Hash> = chain.blockchain.side_blocks().map(Block::hash).collect();
+    assert!(side.contains(&valid.hash()));
+    assert!(!side.contains(&invalid.hash()));
+    // and the active chain can still be extended
+    let next = chain.branch(&chain.blocks[2], 2, 1).remove(0);
+    chain.blockchain.add_block(next.clone()).unwrap();
+    assert_eq!(chain.tip(), next.hash());
+    assert!(chain.blockchain.check_utxos());
+}
+
+#[test]
+fn deep_side_branches_are_pruned() {
+    let mut chain = Chain::new();
+    let deep = chain.branch(&chain.blocks[1], 1, 1).remove(0);
+    chain.blockchain.add_block(deep.clone()).unwrap();
+    // the side block forks off at height 1, it stays until
+    // that is more than MAX_FORK_DEPTH below the tip
+    let extension = chain.branch(&chain.blocks[2], 2, MAX_FORK_DEPTH as u64 - 1);
+    for block in &extension {
+        chain.blockchain.add_block(block.clone()).unwrap();
+    }
+    assert!(chain.blockchain.side_blocks().any(|block| block.hash() == deep.hash()));
+    let recent = chain.branch(&extension[90], 92, 1).remove(0);
+    chain.blockchain.add_block(recent.clone()).unwrap();
+    let next = chain.branch(extension.last().unwrap(), 1 + MAX_FORK_DEPTH as u64, 1);
+    chain.blockchain.add_block(next[0].clone()).unwrap();
+    let side: Vec<Hash> = chain.blockchain.side_blocks().map(Block::hash).collect();
+    assert_eq!(side, vec![recent.hash()]);
+    // and can't come back through its children either
+    let child = chain.branch(&deep, 2, 1).remove(0);
+    assert!(chain.blockchain.add_block(child).is_err());
+}
+
+#[test]
+fn validated_blocks_skip_only_transaction_checks() {
+    let mut chain = Chain::new();
+    // a spend whose signature no longer covers its output
+    let mut tampered = chain.pending.clone();
+    tampered.outputs[0].value -= 1;
+    let a3 = mine_block_with(
+        &chain.blocks[2],
+        min_bits(&chain.params),
+        at(&chain.blocks[2], 1),
+        vec![coinbase(&chain.params, 3, FEE_PER_OUTPUT + 1, &chain.key), tampered],
+    );
+    assert!(chain.blockchain.add_block(a3.clone()).is_err());
+    // taken as it is where it was checked before,
+    // e.g. when loading blocks from the block store
+    let mut reloaded = Blockchain::new(chain.params.clone());
+    for block in &chain.blocks {
+        reloaded.add_validated_block(block.clone()).unwrap();
+    }
+    // but not with a header that doesn't match
+    let mut mismatched = a3.clone();
+    mismatched.transactions.pop();
+    assert!(reloaded.add_validated_block(mismatched).is_err());
+    reloaded.add_validated_block(a3.clone()).unwrap();
+    assert_eq!(reloaded.blocks().last().unwrap().hash(), a3.hash());
+    assert!(reloaded.check_utxos());
+}
+
+#[test]
+fn block_heights_follow_the_active_chain() {
+    let mut chain = Chain::new();
+    let branch = chain.branch(&chain.blocks[1], 1, 2);
+    for block in &branch {
+        chain.blockchain.add_block(block.clone()).unwrap();
+    }
+    let position = |blockchain: &Blockchain, block: &Block| {
+        blockchain.main_chain_position(&block.hash())
+    };
+    // blocks of the orphaned branch are only side blocks now
+    assert_eq!(position(&chain.blockchain, &chain.blocks[1]), Some(1));
+    assert_eq!(position(&chain.blockchain, &chain.blocks[2]), None);
+    assert!(chain.blockchain.get_block(&chain.blocks[2].hash()).is_some());
+    assert_eq!(position(&chain.blockchain, &branch[0]), Some(2));
+    assert_eq!(position(&chain.blockchain, &branch[1]), Some(3));
+    // and the heights are there again after a round trip
+    let mut file = vec![];
+    chain.blockchain.save(&mut file).unwrap();
+    let loaded = Blockchain::load(file.as_slice()).unwrap();
+    for (height, block) in chain.blockchain.blocks().enumerate() {
+        assert_eq!(position(&loaded, block), Some(height));
+    }
+    assert_eq!(position(&loaded, &chain.blocks[2]), None);
+}
@@ -0,0 +1,123 @@
+// Signatures committing to the whole transaction: with
+// SIGHASH_ALL, changing any output or any input after signing
+// invalidates the signatures of every input
+
+mod common;
+
+use btclib::crypto::{PrivateKey, Signature};
+use btclib::error::{BtcError, Result};
+use btclib::sha256::Hash;
+use btclib::types::{OutPoint, SigHashType, Transaction, TransactionInput, TransactionOutput};
+use btclib::validation::check_transaction_inputs;
+use chrono::Utc;
+use common::output;
+use std::collections::HashMap;
+
+// Two outputs held by `key` and a transaction spending both
+// of them into two outputs, signed with SIGHASH_ALL
+struct Spend {
+    key: PrivateKey,
+    prev_outputs: HashMap<OutPoint, TransactionOutput>,
+    transaction: Transaction,
+}
+
+impl Spend {
+    fn new() -> Self {
+        let key = PrivateKey::new_key();
+        let prev_outputs: HashMap<OutPoint, TransactionOutput> = (0..3)
+            .map(|vout| (OutPoint::new(Hash::zero(), vout), output(5000, &key)))
+            .collect();
+        let inputs = [0, 1].map(|vout| OutPoint::new(Hash::zero(), vout));
+        let outputs = vec![output(4000, &key), output(4000, &key)];
+        let transaction = sign(&key, &inputs, outputs);
+        Spend {
+            key,
+            prev_outputs,
+            transaction,
+        }
+    }
+    // check the transaction as it is now, returning its fee
+    fn verify(&self, transaction: &Transaction) -> Result<u64> {
+        check_transaction_inputs(
+            transaction,
+            |outpoint| self.prev_outputs.get(outpoint),
+            1,
+            Utc::now(),
+        )
+    }
+}
+
+fn sign(key: &PrivateKey, prev_outputs: &[OutPoint], outputs: Vec<TransactionOutput>) -> Transaction {
+    let sequences = vec![TransactionInput::SEQUENCE_FINAL; prev_outputs.len()];
+    let inputs = (0..prev_outputs.len())
+        .map(|index| {
+            let hash = Transaction::signature_hash_for(
+                prev_outputs,
+                &sequences,
+                &outputs,
+                index,
+                SigHashType::ALL,
+            )
+            .unwrap();
+            TransactionInput {
+                prev_output: prev_outputs[index],
+                signature: Signature::sign(&hash, key),
+                extra_signatures: vec![],
+                sighash: SigHashType::ALL,
+                sequence: sequences[index],
+            }
+        })
+        .collect();
+    Transaction::new(inputs, outputs)
+}
+
+#[test]
+fn signed_transactions_verify() {
+    let spend = Spend::new();
+    assert_eq!(spend.verify(&spend.transaction).unwrap(), 2000);
+}
+
+#[test]
+fn changing_an_output_invalidates_the_signatures() {
+    let spend = Spend::new();
+    let mut tampered = spend.transaction.clone();
+    tampered.outputs[1].value += 1;
+    assert!(matches!(spend.verify(&tampered), Err(BtcError::InvalidSignature)));
+    // redirecting an output to another key
+    let mut tampered = spend.transaction.clone();
+    tampered.outputs[0].pubkey = PrivateKey::new_key().public_key();
+    assert!(matches!(spend.verify(&tampered), Err(BtcError::InvalidSignature)));
+    // or adding one, paid for by a lower fee
+    let mut tampered = spend.transaction.clone();
+    tampered.outputs.push(output(1000, &spend.key));
+    assert!(matches!(spend.verify(&tampered), Err(BtcError::InvalidSignature)));
+}
+
+#[test]
+fn changing_another_input_invalidates_the_signatures() {
+    let spend = Spend::new();
+    // the first input's signature commits to what
+    // the second input spends and to its sequence
+    let mut tampered = spend.transaction.clone();
+    tampered.inputs[1].prev_output = OutPoint::new(Hash::zero(), 2);
+    assert!(matches!(spend.verify(&tampered), Err(BtcError::InvalidSignature)));
+    let mut tampered = spend.transaction.clone();
+    tampered.inputs[1].sequence = TransactionInput::SEQUENCE_RBF;
+    assert!(matches!(spend.verify(&tampered), Err(BtcError::InvalidSignature)));
+    // dropping it leaves the first one signing a
+    // different transaction as well
+    let mut tampered = spend.transaction.clone();
+    tampered.inputs.pop();
+    tampered.outputs[0].value = 1000;
+    assert!(matches!(spend.verify(&tampered), Err(BtcError::InvalidSignature)));
+}
+
+#[test]
+fn signatures_are_not_reusable_across_inputs() {
+    let spend = Spend::new();
+    // a valid signature over the same transaction,
+    // but made for the other input
+    let mut tampered = spend.transaction.clone();
+    tampered.inputs[0].signature = tampered.inputs[1].signature.clone();
+    assert!(matches!(spend.verify(&tampered), Err(BtcError::InvalidSignature)));
+}
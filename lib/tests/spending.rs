@@ -0,0 +1,200 @@
+// Spending conditions: m-of-n multisig outputs, and outputs
+// locked until a height or until the median time past of the
+// chain passes a point in time
+
+mod common;
+
+use btclib::crypto::{PrivateKey, Signature};
+use btclib::error::{BtcError, Result};
+use btclib::params::ChainParams;
+use btclib::sha256::Hash;
+use btclib::types::{
+    Block, Blockchain, OutPoint, SigHashType, SpendingCondition, Timelock, Transaction,
+    TransactionInput, TransactionOutput,
+};
+use btclib::validation::{check_transaction, check_transaction_inputs};
+use chrono::{DateTime, TimeDelta, Utc};
+use common::{coinbase, min_bits, mine_block, mine_block_with, output, FEE_PER_OUTPUT};
+
+// Spend `prev_output`, holding `value`, back to `keys[0]`,
+// signing with all of `keys` in order
+fn spend(prev_output: OutPoint, value: u64, keys: &[&PrivateKey]) -> Transaction {
+    let outputs = vec![output(value - FEE_PER_OUTPUT, keys[0])];
+    let hash = Transaction::signature_hash_for(
+        &[prev_output],
+        &[TransactionInput::SEQUENCE_FINAL],
+        &outputs,
+        0,
+        SigHashType::ALL,
+    )
+    .unwrap();
+    let mut signatures = keys.iter().map(|key| Signature::sign(&hash, key));
+    let input = TransactionInput {
+        prev_output,
+        signature: signatures.next().unwrap(),
+        extra_signatures: signatures.collect(),
+        sighash: SigHashType::ALL,
+        sequence: TransactionInput::SEQUENCE_FINAL,
+    };
+    Transaction::new(vec![input], outputs)
+}
+
+// a 2-of-3 multisig output of `value`
+fn multisig(value: u64, keys: &[PrivateKey; 3]) -> TransactionOutput {
+    TransactionOutput {
+        condition: SpendingCondition::MultiSig {
+            required: 2,
+            pubkeys: keys.iter().map(|key| key.public_key()).collect(),
+        },
+        ..output(value, &keys[0])
+    }
+}
+
+// check spending the multisig output with signatures of `signers`
+fn spend_multisig(keys: &[PrivateKey; 3], signers: &[usize]) -> Result<u64> {
+    let prev_output = OutPoint::new(Hash::zero(), 0);
+    let locked = multisig(10_000, keys);
+    let signers: Vec<&PrivateKey> = signers.iter().map(|index| &keys[*index]).collect();
+    let transaction = spend(prev_output, locked.value, &signers);
+    check_transaction_inputs(
+        &transaction,
+        |outpoint| (*outpoint == prev_output).then_some(&locked),
+        1,
+        Utc::now(),
+    )
+}
+
+#[test]
+fn multisig_outputs_need_enough_signatures_in_key_order() {
+    let keys = [PrivateKey::new_key(), PrivateKey::new_key(), PrivateKey::new_key()];
+    assert_eq!(spend_multisig(&keys, &[0, 2]).unwrap(), FEE_PER_OUTPUT);
+    assert_eq!(spend_multisig(&keys, &[1, 2]).unwrap(), FEE_PER_OUTPUT);
+    assert_eq!(spend_multisig(&keys, &[0, 1, 2]).unwrap(), FEE_PER_OUTPUT);
+    // too few, out of order, or the same key twice
+    for signers in [&[0][..], &[2, 0], &[1, 1]] {
+        let error = spend_multisig(&keys, signers).unwrap_err();
+        assert!(matches!(error, BtcError::InvalidSignature), "{signers:?}");
+    }
+    // or a signature from a key that isn't part of it
+    let locked = multisig(10_000, &keys);
+    let other = PrivateKey::new_key();
+    let transaction = spend(OutPoint::new(Hash::zero(), 0), locked.value, &[&keys[0], &other]);
+    let error = check_transaction_inputs(&transaction, |_| Some(&locked), 1, Utc::now()).unwrap_err();
+    assert!(matches!(error, BtcError::InvalidSignature));
+}
+
+#[test]
+fn unsatisfiable_multisig_outputs_are_rejected() {
+    let keys = [PrivateKey::new_key(), PrivateKey::new_key(), PrivateKey::new_key()];
+    for required in [0, 4] {
+        let mut locked = multisig(10_000, &keys);
+        locked.condition = SpendingCondition::MultiSig {
+            required,
+            pubkeys: keys.iter().map(|key| key.public_key()).collect(),
+        };
+        let mut transaction = spend(OutPoint::new(Hash::zero(), 0), 20_000, &[&keys[0]]);
+        transaction.outputs = vec![locked];
+        let error = check_transaction(&transaction).unwrap_err();
+        assert!(matches!(error, BtcError::InvalidSpendingCondition));
+    }
+}
+
+// A regtest chain of genesis and a block paying `key`
+// an output locked with `timelock`
+struct LockedChain {
+    params: ChainParams,
+    key: PrivateKey,
+    blockchain: Blockchain,
+    tip: Block,
+    locked: OutPoint,
+    value: u64,
+}
+
+impl LockedChain {
+    fn new(timelock: Timelock) -> Self {
+        let params = ChainParams::regtest();
+        let key = PrivateKey::new_key();
+        let mut blockchain = Blockchain::new(params.clone());
+        let genesis = params.genesis_block();
+        let mut coinbase = coinbase(&params, 1, 0, &key);
+        coinbase.outputs[0].timelock = Some(timelock);
+        let b1 = mine_block_with(
+            &genesis,
+            min_bits(&params),
+            genesis.header.timestamp + TimeDelta::seconds(1),
+            vec![coinbase],
+        );
+        let locked = OutPoint::new(b1.transactions[0].hash(), 0);
+        let value = b1.transactions[0].outputs[0].value;
+        blockchain.add_block(genesis).unwrap();
+        blockchain.add_block(b1.clone()).unwrap();
+        LockedChain {
+            params,
+            key,
+            blockchain,
+            tip: b1,
+            locked,
+            value,
+        }
+    }
+    // mine an empty block at `timestamp` on the tip
+    fn extend(&mut self, timestamp: DateTime<Utc>) {
+        let height = self.blockchain.block_height();
+        let block = mine_block(&self.params, &self.tip, height, min_bits(&self.params), timestamp, &self.key);
+        self.blockchain.add_block(block.clone()).unwrap();
+        self.tip = block;
+    }
+    // try to confirm a spend of the locked output in a block
+    // at `timestamp` on the tip
+    fn spend_in_block(&mut self, timestamp: DateTime<Utc>) -> Result<()> {
+        let height = self.blockchain.block_height();
+        let spend = spend(self.locked, self.value, &[&self.key]);
+        let block = mine_block_with(
+            &self.tip,
+            min_bits(&self.params),
+            timestamp,
+            vec![coinbase(&self.params, height, FEE_PER_OUTPUT, &self.key), spend],
+        );
+        self.blockchain.add_block(block.clone())?;
+        self.tip = block;
+        Ok(())
+    }
+    fn spend_in_mempool(&mut self) -> Result<()> {
+        self.blockchain.add_to_mempool(spend(self.locked, self.value, &[&self.key]))
+    }
+}
+
+#[test]
+fn height_locked_outputs_unlock_at_their_height() {
+    let mut chain = LockedChain::new(Timelock::Height(4));
+    let start = chain.tip.header.timestamp;
+    chain.extend(start + TimeDelta::seconds(1));
+    // the next block is at height 3
+    assert!(matches!(chain.spend_in_mempool(), Err(BtcError::OutputLocked)));
+    let error = chain.spend_in_block(start + TimeDelta::seconds(2)).unwrap_err();
+    assert!(matches!(error, BtcError::OutputLocked));
+    chain.extend(start + TimeDelta::seconds(2));
+    chain.spend_in_mempool().unwrap();
+    chain.spend_in_block(start + TimeDelta::seconds(3)).unwrap();
+    assert_eq!(chain.blockchain.block_height(), 5);
+}
+
+#[test]
+fn time_locked_outputs_unlock_by_median_time_past() {
+    let genesis_time = ChainParams::regtest().genesis_block().header.timestamp;
+    let lock_time = genesis_time + TimeDelta::hours(1);
+    let mut chain = LockedChain::new(Timelock::Time(lock_time));
+    // a block at a time after the lock doesn't unlock it,
+    // the chain it extends has to be past it
+    chain.extend(lock_time + TimeDelta::seconds(1));
+    let error = chain.spend_in_block(lock_time + TimeDelta::seconds(2)).unwrap_err();
+    assert!(matches!(error, BtcError::OutputLocked));
+    assert!(matches!(chain.spend_in_mempool(), Err(BtcError::OutputLocked)));
+    assert!(chain.blockchain.median_time_past().unwrap() < lock_time);
+    // with one more block after the lock, the median of the
+    // chain's timestamps is too
+    chain.extend(lock_time + TimeDelta::seconds(2));
+    assert_eq!(chain.blockchain.median_time_past().unwrap(), lock_time + TimeDelta::seconds(1));
+    chain.spend_in_mempool().unwrap();
+    chain.spend_in_block(lock_time + TimeDelta::seconds(3)).unwrap();
+}
@@ -0,0 +1,137 @@
+// The transaction index: confirmed transactions found by
+// txid, following the active chain across reorganizations
+// and rebuilt when enabled on a loaded chain
+
+mod common;
+
+use btclib::crypto::PrivateKey;
+use btclib::params::ChainParams;
+use btclib::sha256::Hash;
+use btclib::types::{Block, Blockchain, OutPoint, Transaction, TxLocation};
+use btclib::util::Saveable;
+use chrono::TimeDelta;
+use common::{coinbase, min_bits, mine_block, mine_block_with, spend, FEE_PER_OUTPUT};
+
+// A regtest chain of genesis, a block paying `key` and a
+// block confirming a spend of that payment
+struct Chain {
+    params: ChainParams,
+    key: PrivateKey,
+    blocks: Vec<Block>,
+    spend: Transaction,
+}
+
+impl Chain {
+    fn new() -> Self {
+        let params = ChainParams::regtest();
+        let key = PrivateKey::new_key();
+        let genesis = params.genesis_block();
+        let b1 = mine_block(&params, &genesis, 1, min_bits(&params), at(&genesis), &key);
+        let spend = spend(
+            OutPoint::new(b1.transactions[0].hash(), 0),
+            b1.transactions[0].outputs[0].value,
+            &key,
+            1,
+        );
+        let b2 = mine_block_with(
+            &b1,
+            min_bits(&params),
+            at(&b1),
+            vec![coinbase(&params, 2, FEE_PER_OUTPUT, &key), spend.clone()],
+        );
+        Chain {
+            params,
+            key,
+            blocks: vec![genesis, b1, b2],
+            spend,
+        }
+    }
+    fn blockchain(&self, txindex: bool) -> Blockchain {
+        let mut blockchain = Blockchain::new(self.params.clone());
+        if txindex {
+            blockchain.enable_txindex();
+        }
+        for block in &self.blocks {
+            blockchain.add_block(block.clone()).unwrap();
+        }
+        blockchain
+    }
+}
+
+// a second after `block`
+fn at(block: &Block) -> chrono::DateTime<chrono::Utc> {
+    block.header.timestamp + TimeDelta::seconds(1)
+}
+
+fn location(block: &Block, height: u64, position: u32) -> TxLocation {
+    TxLocation {
+        block: block.hash(),
+        height,
+        position,
+    }
+}
+
+#[test]
+fn confirmed_transactions_are_found_by_txid() {
+    let chain = Chain::new();
+    let blockchain = chain.blockchain(true);
+    let (transaction, found) = blockchain.find_transaction(&chain.spend.hash()).unwrap();
+    assert_eq!(transaction.hash(), chain.spend.hash());
+    assert_eq!(found, location(&chain.blocks[2], 2, 1));
+    // every transaction of the chain, coinbases included
+    assert_eq!(blockchain.txindex().unwrap().len(), 4);
+    let coinbase = chain.blocks[1].transactions[0].hash();
+    assert_eq!(blockchain.find_transaction(&coinbase).unwrap().1, location(&chain.blocks[1], 1, 0));
+    assert!(blockchain.find_transaction(&Hash::zero()).is_none());
+    // and nothing without the index
+    let blockchain = chain.blockchain(false);
+    assert!(blockchain.txindex().is_none());
+    assert!(blockchain.find_transaction(&chain.spend.hash()).is_none());
+}
+
+#[test]
+fn the_index_follows_reorganizations() {
+    let chain = Chain::new();
+    let mut blockchain = chain.blockchain(true);
+    // a longer branch off the first block, without the spend
+    let c2 = mine_block(&chain.params, &chain.blocks[1], 2, min_bits(&chain.params), at(&chain.blocks[2]), &chain.key);
+    let c3 = mine_block(&chain.params, &c2, 3, min_bits(&chain.params), at(&c2), &chain.key);
+    blockchain.add_block(c2.clone()).unwrap();
+    blockchain.add_block(c3.clone()).unwrap();
+    assert_eq!(blockchain.blocks().last().unwrap().hash(), c3.hash());
+    assert!(blockchain.find_transaction(&chain.spend.hash()).is_none());
+    let orphaned_coinbase = chain.blocks[2].transactions[0].hash();
+    assert!(blockchain.find_transaction(&orphaned_coinbase).is_none());
+    assert_eq!(blockchain.txindex().unwrap().len(), 4);
+    // the spend confirmed again on the new branch
+    let c4 = mine_block_with(
+        &c3,
+        min_bits(&chain.params),
+        at(&c3),
+        vec![coinbase(&chain.params, 4, FEE_PER_OUTPUT, &chain.key), chain.spend.clone()],
+    );
+    blockchain.add_block(c4.clone()).unwrap();
+    assert_eq!(blockchain.find_transaction(&chain.spend.hash()).unwrap().1, location(&c4, 4, 1));
+}
+
+#[test]
+fn the_index_is_rebuilt_for_loaded_chains() {
+    let chain = Chain::new();
+    let indexed = chain.blockchain(true);
+    let mut file = vec![];
+    indexed.save(&mut file).unwrap();
+    // the index isn't stored, enabling it builds it
+    let mut loaded = Blockchain::load(file.as_slice()).unwrap().with_params(chain.params.clone());
+    assert!(loaded.txindex().is_none());
+    loaded.enable_txindex();
+    for block in &chain.blocks {
+        for transaction in &block.transactions {
+            let txid = transaction.hash();
+            assert_eq!(
+                loaded.find_transaction(&txid).map(|(_, location)| location),
+                indexed.find_transaction(&txid).map(|(_, location)| location),
+            );
+        }
+    }
+    assert_eq!(loaded.txindex().unwrap().len(), indexed.txindex().unwrap().len());
+}
@@ -0,0 +1,133 @@
+// Chains of unconfirmed transactions: the mempool accepts
+// spends of outputs created by other mempool transactions,
+// and templates confirm them together, parents first
+
+mod common;
+
+use btclib::assembler::BlockAssembler;
+use btclib::crypto::PrivateKey;
+use btclib::error::BtcError;
+use btclib::params::ChainParams;
+use btclib::types::{Block, Blockchain, OutPoint, Transaction};
+use chrono::TimeDelta;
+use common::{coinbase, min_bits, mine_block, mine_block_with, spend, FEE_PER_OUTPUT};
+
+// A regtest chain of genesis and a block paying `key`, and
+// a chain of three transactions spending that payment
+struct Chain {
+    params: ChainParams,
+    key: PrivateKey,
+    blockchain: Blockchain,
+    tip: Block,
+    transactions: Vec<Transaction>,
+}
+
+impl Chain {
+    fn new() -> Self {
+        let params = ChainParams::regtest();
+        let key = PrivateKey::new_key();
+        let mut blockchain = Blockchain::new(params.clone());
+        let genesis = params.genesis_block();
+        let b1 = mine_block(
+            &params,
+            &genesis,
+            1,
+            min_bits(&params),
+            genesis.header.timestamp + TimeDelta::seconds(1),
+            &key,
+        );
+        blockchain.add_block(genesis).unwrap();
+        blockchain.add_block(b1.clone()).unwrap();
+        let mut transactions: Vec<Transaction> = vec![];
+        let mut prev = (OutPoint::new(b1.transactions[0].hash(), 0), b1.transactions[0].outputs[0].value);
+        for _ in 0..3 {
+            let transaction = spend(prev.0, prev.1, &key, 1);
+            prev = (OutPoint::new(transaction.hash(), 0), transaction.outputs[0].value);
+            transactions.push(transaction);
+        }
+        Chain {
+            params,
+            key,
+            blockchain,
+            tip: b1,
+            transactions,
+        }
+    }
+    // mine a block on the tip with `transactions`
+    fn block_with(&self, transactions: &[Transaction]) -> Block {
+        let fees = transactions.len() as u64 * FEE_PER_OUTPUT;
+        let mut block = vec![coinbase(&self.params, 2, fees, &self.key)];
+        block.extend(transactions.iter().cloned());
+        mine_block_with(&self.tip, min_bits(&self.params), self.tip.header.timestamp + TimeDelta::seconds(1), block)
+    }
+}
+
+#[test]
+fn mempool_transactions_spend_unconfirmed_outputs() {
+    let mut chain = Chain::new();
+    for transaction in &chain.transactions {
+        chain.blockchain.add_to_mempool(transaction.clone()).unwrap();
+    }
+    let mempool = chain.blockchain.mempool();
+    assert_eq!(mempool.len(), 3);
+    // the outputs of the last one are the only ones unspent
+    let last = &chain.transactions[2];
+    for transaction in &chain.transactions {
+        let output = OutPoint::new(transaction.hash(), 0);
+        assert!(mempool.output(&output).is_some());
+        assert_eq!(mempool.spender(&output).is_none(), transaction.hash() == last.hash());
+    }
+    assert_eq!(mempool.ancestors(&last.hash()).len(), 2);
+}
+
+#[test]
+fn children_of_unknown_or_spent_outputs_are_rejected() {
+    let mut chain = Chain::new();
+    // the child before its parent
+    let error = chain.blockchain.add_to_mempool(chain.transactions[1].clone()).unwrap_err();
+    assert!(matches!(error, BtcError::MissingInput));
+    chain.blockchain.add_to_mempool(chain.transactions[0].clone()).unwrap();
+    chain.blockchain.add_to_mempool(chain.transactions[1].clone()).unwrap();
+    // a second spend of the parent's output, which the
+    // first spend didn't allow to be replaced
+    let parent = &chain.transactions[0];
+    let double_spend = spend(OutPoint::new(parent.hash(), 0), parent.outputs[0].value, &chain.key, 2);
+    let error = chain.blockchain.add_to_mempool(double_spend).unwrap_err();
+    assert!(matches!(error, BtcError::ReplacementNotSignaled));
+}
+
+#[test]
+fn confirming_a_parent_keeps_its_children() {
+    let mut chain = Chain::new();
+    for transaction in &chain.transactions {
+        chain.blockchain.add_to_mempool(transaction.clone()).unwrap();
+    }
+    // children can't be confirmed before their parents
+    let block = chain.block_with(&chain.transactions[1..2]);
+    assert!(matches!(chain.blockchain.add_block(block), Err(BtcError::MissingInput)));
+    let block = chain.block_with(&chain.transactions[..1]);
+    chain.blockchain.add_block(block).unwrap();
+    let mempool = chain.blockchain.mempool();
+    assert_eq!(mempool.len(), 2);
+    assert!(!mempool.contains(&chain.transactions[0].hash()));
+    // the child spends a confirmed output now
+    let spent = OutPoint::new(chain.transactions[0].hash(), 0);
+    assert_eq!(chain.blockchain.utxos().get(&spent).map(|(marked, _)| *marked), Some(true));
+    assert!(mempool.ancestors(&chain.transactions[1].hash()).is_empty());
+}
+
+#[test]
+fn templates_confirm_chains_parents_first() {
+    let mut chain = Chain::new();
+    for transaction in &chain.transactions {
+        chain.blockchain.add_to_mempool(transaction.clone()).unwrap();
+    }
+    let template = BlockAssembler::for_chain(&chain.params).assemble(&chain.blockchain, chain.key.public_key());
+    let txids: Vec<_> = template.block.transactions[1..].iter().map(|tx| tx.hash()).collect();
+    let expected: Vec<_> = chain.transactions.iter().map(|tx| tx.hash()).collect();
+    assert_eq!(txids, expected);
+    let mut block = template.block;
+    assert!(block.header.mine(usize::MAX));
+    chain.blockchain.add_block(block).unwrap();
+    assert!(chain.blockchain.mempool().is_empty());
+}
@@ -0,0 +1,139 @@
+// Transaction validation shared by mempool admission and
+// block validation: a transaction the mempool rejects is
+// rejected in a block for the same reason
+
+mod common;
+
+use btclib::crypto::PrivateKey;
+use btclib::error::BtcError;
+use btclib::params::ChainParams;
+use btclib::sha256::Hash;
+use btclib::types::{Block, Blockchain, OutPoint, Transaction};
+use btclib::validation::check_transaction;
+use btclib::MAX_TRANSACTION_SIZE;
+use chrono::TimeDelta;
+use common::{coinbase, min_bits, mine_block, mine_block_with, output, spend, FEE_PER_OUTPUT};
+
+// A regtest chain of genesis and a block paying `key`
+struct Funded {
+    params: ChainParams,
+    key: PrivateKey,
+    blockchain: Blockchain,
+    tip: Block,
+    funding: OutPoint,
+    value: u64,
+}
+
+impl Funded {
+    fn new() -> Self {
+        let params = ChainParams::regtest();
+        let key = PrivateKey::new_key();
+        let mut blockchain = Blockchain::new(params.clone());
+        let genesis = params.genesis_block();
+        let b1 = mine_block(
+            &params,
+            &genesis,
+            1,
+            min_bits(&params),
+            genesis.header.timestamp + TimeDelta::seconds(1),
+            &key,
+        );
+        blockchain.add_block(genesis).unwrap();
+        blockchain.add_block(b1.clone()).unwrap();
+        Funded {
+            funding: OutPoint::new(b1.transactions[0].hash(), 0),
+            value: b1.transactions[0].outputs[0].value,
+            params,
+            key,
+            blockchain,
+            tip: b1,
+        }
+    }
+    // a block on the tip confirming `transaction`
+    fn block_with(&self, transaction: Transaction) -> Block {
+        mine_block_with(
+            &self.tip,
+            min_bits(&self.params),
+            self.tip.header.timestamp + TimeDelta::seconds(1),
+            vec![coinbase(&self.params, 2, FEE_PER_OUTPUT, &self.key), transaction],
+        )
+    }
+}
+
+// `transaction` is rejected with `expected` by the mempool
+// and in a block
+fn assert_rejected(funded: &mut Funded, transaction: Transaction, expected: fn(&BtcError) -> bool) {
+    let error = funded.blockchain.add_to_mempool(transaction.clone()).unwrap_err();
+    assert!(expected(&error), "mempool: {error}");
+    assert!(funded.blockchain.mempool().is_empty());
+    let error = funded.blockchain.add_block(funded.block_with(transaction)).unwrap_err();
+    assert!(expected(&error), "block: {error}");
+    assert_eq!(funded.blockchain.block_height(), 2);
+}
+
+#[test]
+fn valid_transactions_are_accepted() {
+    let mut funded = Funded::new();
+    let transaction = spend(funded.funding, funded.value, &funded.key, 1);
+    funded.blockchain.add_to_mempool(transaction.clone()).unwrap();
+    assert!(funded.blockchain.mempool().contains(&transaction.hash()));
+    funded.blockchain.add_block(funded.block_with(transaction)).unwrap();
+}
+
+#[test]
+fn signatures_are_checked() {
+    let mut funded = Funded::new();
+    // signed by a key the output doesn't belong to
+    let thief = PrivateKey::new_key();
+    let mut transaction = spend(funded.funding, funded.value, &thief, 1);
+    assert_rejected(&mut funded, transaction.clone(), |e| matches!(e, BtcError::InvalidSignature));
+    // or signed for other outputs
+    transaction.inputs[0].signature = spend(funded.funding, funded.value, &funded.key, 2).inputs[0].signature.clone();
+    assert_rejected(&mut funded, transaction, |e| matches!(e, BtcError::InvalidSignature));
+}
+
+#[test]
+fn inputs_have_to_exist_and_cover_the_outputs() {
+    let mut funded = Funded::new();
+    let missing = spend(OutPoint::new(Hash::zero(), 0), funded.value, &funded.key, 1);
+    assert_rejected(&mut funded, missing, |e| matches!(e, BtcError::MissingInput));
+    let overspending = spend(funded.funding, funded.value + 2 * FEE_PER_OUTPUT, &funded.key, 1);
+    assert_rejected(&mut funded, overspending, |e| matches!(e, BtcError::InsufficientInputValue));
+}
+
+#[test]
+fn malformed_transactions_are_rejected_before_looking_up_inputs() {
+    let key = PrivateKey::new_key();
+    let valid = spend(OutPoint::new(Hash::zero(), 0), 10_000, &key, 1);
+    let mut transaction = valid.clone();
+    transaction.inputs.clear();
+    assert!(matches!(check_transaction(&transaction), Err(BtcError::EmptyInputs)));
+    let mut transaction = valid.clone();
+    transaction.outputs.clear();
+    assert!(matches!(check_transaction(&transaction), Err(BtcError::EmptyOutputs)));
+    let mut transaction = valid.clone();
+    transaction.outputs[0].value = 0;
+    assert!(matches!(check_transaction(&transaction), Err(BtcError::ZeroValueOutput)));
+    let mut transaction = valid.clone();
+    transaction.inputs.push(transaction.inputs[0].clone());
+    assert!(matches!(check_transaction(&transaction), Err(BtcError::DuplicateInput)));
+    let mut transaction = valid.clone();
+    transaction.outputs = vec![output(u64::MAX, &key), output(1, &key)];
+    assert!(matches!(check_transaction(&transaction), Err(BtcError::ValueOverflow)));
+    // as many outputs as fit, and one more
+    let mut transaction = valid.clone();
+    while transaction.size() <= MAX_TRANSACTION_SIZE {
+        transaction.outputs.push(output(1, &key));
+    }
+    assert!(matches!(check_transaction(&transaction), Err(BtcError::TransactionTooLarge)));
+    transaction.outputs.pop();
+    check_transaction(&transaction).unwrap();
+}
+
+#[test]
+fn malformed_transactions_are_rejected_by_the_mempool_and_in_blocks() {
+    let mut funded = Funded::new();
+    let mut transaction = spend(funded.funding, funded.value, &funded.key, 1);
+    transaction.outputs.push(output(0, &funded.key));
+    assert_rejected(&mut funded, transaction, |e| matches!(e, BtcError::ZeroValueOutput));
+}
@@ -10,7 +10,10 @@ use std::sync::{
 };
 use std::thread;
 
-use btclib::{crypto::PublicKey, network::Message, types::Block, util::Saveable};
+use btclib::{crypto::PublicKey, network::{handshake, Message, Version, RESPONSE_TIMEOUT}, params::ChainParams, types::Block, util::Saveable};
+
+// announced to the node in the handshake
+const USER_AGENT: &str = concat!("/miner:", env!("CARGO_PKG_VERSION"), "/");
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
@@ -19,10 +22,15 @@ struct Cli {
     address: String,
     #[arg(short, long)]
     public_key_file: String,
+    /// Network to mine on: mainnet, testnet, regtest or the
+    /// path of a TOML file with custom chain parameters
+    #[arg(short, long, default_value = "mainnet")]
+    network: String,
 }
 
 struct Miner {
     public_key: PublicKey,
+    params: ChainParams,
     stream: Mutex<TcpStream>,
     current_template: Arc<std::sync::Mutex<Option<Block>>>,
     mining: Arc<AtomicBool>,
@@ -32,13 +40,22 @@ struct Miner {
 impl Miner {
     async fn new(
         address: String,
-        public_key: PublicKey
+        public_key: PublicKey,
+        params: ChainParams,
     ) -> Result<Self> {
-        let stream = TcpStream::connect(&address).await?;
+        let mut stream = TcpStream::connect(&address).await?;
+        // a miner offers no services and keeps no chain
+        let version = Version::new(&params, 0, 0, USER_AGENT);
+        let node = handshake(&mut stream, &version).await?;
+        println!(
+            "Connected to {} at height {}",
+            node.user_agent, node.best_height
+        );
         let (mined_block_sender, mined_block_receiver) =
             flume::unbounded();
         Ok(Self {
             public_key,
+            params,
             stream: Mutex::new(stream),
             current_template: Arc::new(std::sync::Mutex::new(
                 None,
@@ -76,7 +93,7 @@ impl Miner {
                 {
                     println!(
                         "Mining block with target: {}",
-                        block.header.target
+                        block.header.target()
                     );
                     if block.header.mine(2_000_000) {
                         println!(
@@ -109,11 +126,36 @@ impl Miner {
         message.send_async(&mut *stream_lock).await?;
         drop(stream_lock);
         let mut stream_lock = self.stream.lock().await;
-        match Message::receive_async(&mut *stream_lock).await? {
+        match Message::receive_async_timeout(&mut *stream_lock, RESPONSE_TIMEOUT)
+            .await?
+        {
             Message::Template(template) => {
                 drop(stream_lock);
-                println!("Received new template with target: {}", template.header.target);
-                *self.current_template.lock().unwrap() = Some(template);
+                println!("Received new template with target: {}", template.block.header.target());
+                // a node on another network would hand out
+                // blocks that are too easy for ours
+                if template.block.header.target() > self.params.min_target {
+                    return Err(anyhow!(
+                        "template target is below the {} minimum difficulty",
+                        self.params.name
+                    ));
+                }
+                println!(
+                    "{} transactions, {} bytes, {} sat in fees",
+                    template.transactions.len(),
+                    template.size,
+                    template.total_fees
+                );
+                for tx in &template.transactions {
+                    println!(
+                        "  {}: {} sat fee, {} bytes, {} sat/kB",
+                        tx.txid,
+                        tx.fee,
+                        tx.size,
+                        tx.fee_rate()
+                    );
+                }
+                *self.current_template.lock().unwrap() = Some(template.block);
                 self.mining.store(true, Ordering::Relaxed);
                 Ok(())
             }
@@ -129,7 +171,9 @@ impl Miner {
             message.send_async(&mut *stream_lock).await?;
             drop(stream_lock);
             let mut stream_lock = self.stream.lock().await;
-            match Message::receive_async(&mut *stream_lock).await? {
+            match Message::receive_async_timeout(&mut *stream_lock, RESPONSE_TIMEOUT)
+            .await?
+        {
                 Message::TemplateValidity(valid) => {
                     drop(stream_lock);
                     if !valid {
@@ -164,6 +208,10 @@ async fn main() -> Result<()> {
             .map_err(|e| {
                 anyhow!("Error reading public key: {}", e)
             })?;
-    let miner = Miner::new(cli.address, public_key).await?;
+    let params = ChainParams::load(&cli.network)
+        .map_err(|e| anyhow!("Error loading chain parameters: {}", e))?;
+    println!("Mining on {}", params.name);
+    btclib::network::set_max_block_size(&params);
+    let miner = Miner::new(cli.address, public_key, params).await?;
     miner.run().await
 }
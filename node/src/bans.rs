@@ -0,0 +1,69 @@
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+use btclib::error::NetworkError;
+use btclib::network::BAN_SCORE;
+use dashmap::DashMap;
+
+// how long a peer that reached BAN_SCORE is refused
+const BAN_DURATION: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Misbehavior of a peer so far
+#[derive(Default)]
+struct PeerConduct {
+  score: u32,
+  /// set once the score reached BAN_SCORE
+  banned_until: Option<Instant>,
+}
+
+/// Misbehavior scores of peer addresses. Peers reaching
+/// BAN_SCORE are refused for BAN_DURATION, after that they
+/// start over with a clean record
+#[derive(Default)]
+pub struct BanList {
+  peers: DashMap<IpAddr, PeerConduct>,
+}
+
+impl BanList {
+  pub fn new() -> Self {
+    BanList::default()
+  }
+  // whether connections with `ip` are refused right now
+  pub fn is_banned(&self, ip: IpAddr) -> bool {
+    let expired = match self.peers.get(&ip) {
+      Some(conduct) => match conduct.banned_until {
+        Some(until) if until > Instant::now() => return true,
+        Some(_) => true,
+        None => false,
+      },
+      None => return false,
+    };
+    if expired {
+      self.peers.remove(&ip);
+    }
+    false
+  }
+  // count the misbehavior score of an error caused
+  // by a peer, returns true if that got it banned
+  pub fn misbehaving(&self, ip: IpAddr, error: &NetworkError) -> bool {
+    let score = error.misbehavior_score();
+    if score == 0 {
+      return false;
+    }
+    let mut conduct = self.peers.entry(ip).or_default();
+    if conduct.banned_until.is_some() {
+      return false;
+    }
+    conduct.score = conduct.score.saturating_add(score);
+    println!(
+      "peer {ip} misbehaved ({error}), score {}",
+      conduct.score
+    );
+    if conduct.score < BAN_SCORE {
+      return false;
+    }
+    println!("banning peer {ip} for {} hours", BAN_DURATION.as_secs() / 3600);
+    conduct.banned_until = Some(Instant::now() + BAN_DURATION);
+    true
+  }
+}
@@ -1,15 +1,87 @@
+use btclib::assembler::BlockAssembler;
 use btclib::sha256::Hash;
-use chrono::Utc;
-use uuid::Uuid;
+use std::net::SocketAddr;
 use tokio::net::TcpStream;
-use btclib::network::Message;
-use btclib::types::{
-  Block, BlockHeader, Transaction, TransactionOutput,
+use tokio::sync::mpsc::UnboundedReceiver;
+use btclib::error::NetworkError;
+use btclib::network::{
+  handshake, InventoryItem, Message, MAX_ADDR, MAX_HEADERS,
+  MAX_HISTORY, MAX_INV, NODE_NETWORK,
 };
-use btclib::util::MerkleRoot;
+use btclib::network::Message::*;
+use crate::{history, orphans, relay};
 
-pub async fn handle_connection(mut socket: TcpStream) {
+pub async fn handle_connection(mut socket: TcpStream, addr: SocketAddr) {
+  let ip = addr.ip();
+  if crate::BANS.is_banned(ip) {
+    println!("refusing connection from banned peer {ip}");
+    return;
+  }
+  let Some(_slot) = crate::PEERS.inbound_slot() else {
+    println!("too many inbound connections, refusing {addr}");
+    return;
+  };
+  // nothing else is handled until the peer proved it is
+  // on our network and speaks our protocol
+  let version = crate::peers::local_version().await;
+  let peer = match handshake(&mut socket, &version).await {
+    Ok(peer) => peer,
+    Err(e) => {
+      println!("handshake with {addr} failed: {e}, closing that connection");
+      crate::BANS.misbehaving(ip, &e);
+      return;
+    }
+  };
+  println!(
+    "accepted peer {addr}: {}, height {}",
+    peer.user_agent, peer.best_height
+  );
+  // the address of a node accepting connections itself, it goes
+  // into the address book and other nodes get to know it
+  let peer_address = peer
+    .listen_port
+    .filter(|_| peer.services & NODE_NETWORK != 0)
+    .map(|port| SocketAddr::new(ip, port).to_string());
+  if let Some(address) = &peer_address {
+    if crate::PEERS.add_address(address) {
+      crate::peers::broadcast(&Addr(vec![address.clone()])).await;
+    }
+  }
+  // other nodes get the items we relay announced over this
+  // connection, wallets and miners have no use for them
+  let (_registration, mut announcements) = match &peer_address {
+    Some(address) => {
+      let (registration, announcements) = crate::PEERS.register_inbound(
+        addr,
+        address.clone(),
+        peer.user_agent.clone(),
+      );
+      (Some(registration), Some(announcements))
+    }
+    None => (None, None),
+  };
   loop {
+    // announce what was relayed while waiting for a message,
+    // which is only read once it starts to arrive so that
+    // no partly read message is lost
+    let mut first_byte = [0u8; 1];
+    tokio::select! {
+      peeked = socket.peek(&mut first_byte) => {
+        if let Err(e) = peeked {
+          println!("connection to peer failed: {e}, closing it");
+          return;
+        }
+      }
+      Some(items) = next_announcement(&mut announcements) => {
+        for chunk in items.chunks(MAX_INV) {
+          if let Err(e) = Inv(chunk.to_vec()).send_async(&mut socket).await {
+            println!("failed to announce to peer: {e}, closing that connection");
+            return;
+          }
+        }
+        continue;
+      }
+    }
     // read a message from the socket
     let message = match Message::receive_async(&mut socket)
       .await
@@ -19,44 +91,85 @@ pub async fn handle_connection(mut socket: TcpStream) {
         println!(
           "invalid message from peer: {e}, closing that connection"
         );
+        crate::BANS.misbehaving(ip, &e);
         return;
       }
     };
-    use btclib::network::Message::*;
     match message {
+      Version(_) | VerAck => {
+        println!("peer repeated the handshake, closing that connection");
+        crate::BANS.misbehaving(
+          ip,
+          &NetworkError::UnexpectedMessage("repeated handshake"),
+        );
+        return;
+      }
       UTXOs(_) | Template(_) | Difference(_)
-      | TemplateValidity(_) | NodeList(_) => {
+      | TemplateValidity(_) | NodeList(_) | Headers(_)
+      | CompactBlock(_) | BlockTxn { .. } | TransactionInfo { .. }
+      | History { .. } | UTXOUpdate(_) => {
         println!(
         "I am neither a miner nor a \
         wallet! Goodbye"
         );
+        crate::BANS.misbehaving(
+          ip,
+          &NetworkError::UnexpectedMessage("unsolicited reply"),
+        );
         return;
       }
+      Addr(addresses) => {
+        if addresses.len() > MAX_ADDR {
+          println!("peer sent too many addresses, closing that connection");
+          crate::BANS.misbehaving(
+            ip,
+            &NetworkError::UnexpectedMessage("too many addresses"),
+          );
+          return;
+        }
+        let new_addresses = crate::PEERS.add_heard(addresses);
+        if !new_addresses.is_empty() {
+          println!("learned {} new node addresses", new_addresses.len());
+        }
+      }
       FetchBlock(height) => {
         let blockchain = crate::BLOCKCHAIN.read().await;
-        let Some(block) = blockchain
-          .blocks()
-          .nth(height as usize)
-          .cloned()
-        else {
-          return ;
+        // the items of a NotFound are hashes, there
+        // is none to name for a height
+        let message = match blockchain.blocks().nth(height) {
+          Some(block) => NewBlock(block.clone()),
+          None => NotFound(vec![]),
         };
-        let message = NewBlock(block);
-        message
-          .send_async(&mut socket)
-          .await
-          .unwrap();
+        drop(blockchain);
+        if let Err(e) = message.send_async(&mut socket).await {
+          println!("failed to reply to peer: {e}, closing that connection");
+          return;
+        }
+      }
+      GetHeaders(locator) => {
+        let blockchain = crate::BLOCKCHAIN.read().await;
+        let start = blockchain
+          .find_fork(&locator)
+          .map(|height| height as usize + 1)
+          .unwrap_or(0);
+        let headers = blockchain
+          .blocks()
+          .skip(start)
+          .take(MAX_HEADERS)
+          .map(|block| block.header.clone())
+          .collect();
+        let message = Headers(headers);
+        if let Err(e) = message.send_async(&mut socket).await {
+          println!("failed to reply to peer: {e}, closing that connection");
+          return;
+        }
       }
       DiscoverNodes => {
-        let nodes = crate::NODES
-          .iter()
-          .map(|x| x.key().clone())
-          .collect::<Vec<_>>();
-        let message = NodeList(nodes);
-        message
-          .send_async(&mut socket)
-          .await
-          .unwrap();
+        let message = NodeList(crate::PEERS.advertised());
+        if let Err(e) = message.send_async(&mut socket).await {
+          println!("failed to reply to peer: {e}, closing that connection");
+          return;
+        }
       }
       AskDifference(height) => {
         let blockchain =
@@ -65,46 +178,137 @@ pub async fn handle_connection(mut socket: TcpStream) {
           as i32
           - height as i32;
         let message = Difference(count);
-        message
-          .send_async(&mut socket)
-          .await
-          .unwrap();
+        if let Err(e) = message.send_async(&mut socket).await {
+          println!("failed to reply to peer: {e}, closing that connection");
+          return;
+        }
       }
       FetchUTXOs(key) => {
         println!("received request to fetch UTXOs");
-        let blockchain =
-          crate::BLOCKCHAIN.read().await;
-        let utxos = blockchain
-          .utxos()
-          .iter()
-          .filter(|(_, (_, txout))| {
-            txout.pubkey == key
-          })
-          .map(|(_, (marked, txout))| {
-            (txout.clone(), *marked)
-          })
-          .collect::<Vec<_>>();
+        // unconfirmed outputs are included,
+        // so the wallet can spend its change
+        let utxos = history::query(|index, blockchain| {
+          index.utxos(blockchain, &key)
+        })
+        .await;
         let message = UTXOs(utxos);
-        message
-          .send_async(&mut socket)
-          .await
-          .unwrap();
+        if let Err(e) = message.send_async(&mut socket).await {
+          println!("failed to reply to peer: {e}, closing that connection");
+          return;
+        }
+      }
+      FetchUTXOsSince { pubkey, since } => {
+        let update = history::query(|index, blockchain| {
+          index.utxo_update(blockchain, &pubkey, since)
+        })
+        .await;
+        let message = UTXOUpdate(update);
+        if let Err(e) = message.send_async(&mut socket).await {
+          println!("failed to reply to peer: {e}, closing that connection");
+          return;
+        }
+      }
+      FetchHistory { pubkey, offset, limit } => {
+        let limit = (limit as usize).min(MAX_HISTORY);
+        let (entries, total) = history::query(|index, _| {
+          index.history(&pubkey, offset, limit)
+        })
+        .await;
+        let message = History { entries, total };
+        if let Err(e) = message.send_async(&mut socket).await {
+          println!("failed to reply to peer: {e}, closing that connection");
+          return;
+        }
+      }
+      Inv(items) => {
+        if let Err(e) =
+          relay::handle_inv(&mut socket, items, peer_address.as_deref(), false)
+            .await
+        {
+          println!("failed to get announced data: {e}, closing that connection");
+          crate::BANS.misbehaving(ip, &e);
+          return;
+        }
+      }
+      GetData(items) => {
+        if items.len() > MAX_INV {
+          crate::BANS.misbehaving(
+            ip,
+            &NetworkError::UnexpectedMessage("too many inventory items"),
+          );
+          return;
+        }
+        let responses =
+          relay::get_data(&*crate::BLOCKCHAIN.read().await, &items);
+        for message in responses {
+          if let Err(e) = message.send_async(&mut socket).await {
+            println!("failed to reply to peer: {e}, closing that connection");
+            return;
+          }
+        }
+      }
+      FetchTransaction(txid) => {
+        let message = relay::transaction_info(
+          &*crate::BLOCKCHAIN.read().await,
+          &txid,
+        );
+        if let Err(e) = message.send_async(&mut socket).await {
+          println!("failed to reply to peer: {e}, closing that connection");
+          return;
+        }
+      }
+      GetBlockTxn { block, indexes } => {
+        if indexes.is_empty() {
+          continue;
+        }
+        let response = relay::block_transactions(
+          &*crate::BLOCKCHAIN.read().await,
+          block,
+          &indexes,
+        );
+        let message = match response {
+          Ok(message) => message,
+          Err(e) => {
+            println!("invalid request from peer: {e}, closing that connection");
+            crate::BANS.misbehaving(ip, &e);
+            return;
+          }
+        };
+        if let Err(e) = message.send_async(&mut socket).await {
+          println!("failed to reply to peer: {e}, closing that connection");
+          return;
+        }
+      }
+      NotFound(_) => {
+        println!("peer sent NotFound without being asked");
       }
       NewBlock(block) => {
-        let mut blockchain =
-          crate::BLOCKCHAIN.write().await;
         println!("received new block");
-        if blockchain.add_block(block).is_err() {
-          println!("block rejected");
+        let item = InventoryItem::Block(block.hash());
+        if let Some(address) = &peer_address {
+          crate::PEERS.mark_known(address, &[item]);
+        }
+        match orphans::process_block(block, peer_address.as_deref()).await {
+          Ok(added) => {
+            tokio::spawn(relay::relay(added));
+          }
+          Err(e) => println!("block rejected: {e}"),
         }
       }
       NewTransaction(tx) => {
-        let mut blockchain =
-          crate::BLOCKCHAIN.write().await;
         println!("received transaction from friend");
-        if blockchain.add_to_mempool(tx).is_err() {
-          println!("transaction rejected, closing connection");
-          return;
+        let item = InventoryItem::Transaction(tx.hash());
+        if let Some(address) = &peer_address {
+          crate::PEERS.mark_known(address, &[item]);
+        }
+        match orphans::process_transaction(tx).await {
+          Ok(added) => {
+            tokio::spawn(relay::relay(added));
+          }
+          Err(e) => {
+            println!("transaction rejected, closing connection: {e}");
+            return;
+          }
         }
       }
       ValidateTemplate(block_template) => {
@@ -119,10 +323,10 @@ pub async fn handle_connection(mut socket: TcpStream) {
             .map(|last_block| last_block.hash())
             .unwrap_or(Hash::zero());
         let message = TemplateValidity(status);
-        message
-          .send_async(&mut socket)
-          .await
-          .unwrap();
+        if let Err(e) = message.send_async(&mut socket).await {
+          println!("failed to reply to peer: {e}, closing that connection");
+          return;
+        }
       }
       SubmitTemplate(block) => {
         println!("received allegedly mined template");
@@ -136,135 +340,54 @@ pub async fn handle_connection(mut socket: TcpStream) {
           );
           return;
         }
-        blockchain.rebuild_utxos();
         println!("block looks good, broadcasting");
-        // send block to all friend nodes
-        let nodes = crate::NODES
-          .iter()
-          .map(|x| x.key().clone())
-          .collect::<Vec<_>>();
-        for node in nodes {
-          if let Some(mut stream) =
-            crate::NODES.get_mut(&node)
-          {
-            let message = Message::NewBlock(
-              block.clone(),
-            );
-            if message
-              .send_async(&mut *stream)
-              .await
-              .is_err()
-            {
-              println!("failed to send block to {}", node);
-            }
-          }
-        }
+        drop(blockchain);
+        // announce the block to all friend nodes
+        tokio::spawn(relay::relay(vec![InventoryItem::Block(block.hash())]));
       }
       SubmitTransaction(tx) => {
         println!("submit tx");
-        let mut blockchain =
-          crate::BLOCKCHAIN.write().await;
-        if let Err(e) =
-          blockchain.add_to_mempool(tx.clone())
-      {
-        println!("transaction rejected, closing connection: {e}");
-          return;
-        }
-        println!("added transaction to mempool");
-        // send transaction to all friend nodes
-        let nodes = crate::NODES
-          .iter()
-          .map(|x| x.key().clone())
-          .collect::<Vec<_>>();
-        for node in nodes {
-          println!("sending to friend: {node}");
-          if let Some(mut stream) =
-            crate::NODES.get_mut(&node)
-          {
-            let message =
-              Message::NewTransaction(
-                tx.clone(),
-              );
-            if message
-              .send_async(&mut *stream)
-              .await
-              .is_err()
-            {
-          println!("failed to send transaction to {}", node);
-            }
+        // it may spend outputs of a transaction
+        // that is still on its way to us
+        let added = match orphans::process_transaction(tx).await {
+          Ok(added) => added,
+          Err(e) => {
+            println!("transaction rejected, closing connection: {e}");
+            return;
           }
+        };
+        if added.is_empty() {
+          continue;
         }
+        println!("added transaction to mempool");
+        // announce the transaction to all friend nodes
+        tokio::spawn(relay::relay(added));
         println!("transaction sent to friends");
       }
       FetchTemplate(pubkey) => {
         let blockchain =
           crate::BLOCKCHAIN.read().await;
-        let mut transactions = vec![];
-        // insert transactions from mempool
-        transactions.extend(
-          blockchain
-            .mempool()
-            .iter()
-            .take(btclib::BLOCK_TRANSACTION_CAP)
-            .map(|(_, tx)| tx)
-            .cloned()
-            .collect::<Vec<_>>(),
-        );
-        // insert coinbase tx with pubkey
-        transactions.insert(
-          0,
-          Transaction {
-            inputs: vec![],
-            outputs: vec![TransactionOutput {
-              pubkey,
-              unique_id: Uuid::new_v4(),
-              value: 0,
-            }],
-          },
-        );
-        let merkle_root =
-          MerkleRoot::calculate(&transactions);
-        let mut block = Block::new(
-          BlockHeader {
-            timestamp: Utc::now(),
-            prev_block_hash: blockchain
-              .blocks()
-              .last()
-              .map(|last_block| {
-                last_block.hash()
-              })
-              .unwrap_or(Hash::zero()),
-            nonce: 0,
-            target: blockchain.target(),
-            merkle_root,
-          },
-            transactions,
-        );
-        let miner_fees = match block
-            .calculate_miner_fees(blockchain.utxos())
-        {
-            Ok(fees) => fees,
-            Err(e) => {
-              eprintln!("{e}");
-              return;
-            }
-        };
-        let reward =
-          blockchain.calculate_block_reward();
-        // update coinbase tx with reward
-        block.transactions[0].outputs[0].value =
-          reward + miner_fees;
-        // recalculate merkle root
-        block.header.merkle_root =
-          MerkleRoot::calculate(
-            &block.transactions,
-          );
-        let message = Template(block);
-        message
-          .send_async(&mut socket)
-          .await
-          .unwrap();
+        // fill the template with the best
+        // paying mempool transactions
+        let template =
+          BlockAssembler::for_chain(blockchain.params())
+            .assemble(&blockchain, pubkey);
+        let message = Template(template);
+        if let Err(e) = message.send_async(&mut socket).await {
+          println!("failed to reply to peer: {e}, closing that connection");
+          return;
+        }
       }
     }
   }
-}
\ No newline at end of file
+}
+
+// the next items to announce, never if there is no queue
+async fn next_announcement(
+  announcements: &mut Option<UnboundedReceiver<Vec<InventoryItem>>>,
+) -> Option<Vec<InventoryItem>> {
+  match announcements {
+    Some(announcements) => announcements.recv().await,
+    None => std::future::pending().await,
+  }
+}
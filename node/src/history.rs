@@ -0,0 +1,579 @@
+use std::cmp::Reverse;
+use std::collections::HashMap;
+
+use serde::Serialize;
+use btclib::crypto::PublicKey;
+use btclib::network::{HistoryEntry, UTXOUpdate, MAX_UTXO_MESSAGE_SIZE};
+use btclib::sha256::Hash;
+use btclib::types::{Block, Blockchain, OutPoint, TransactionOutput};
+
+// room in a UTXOs or UTXOUpdate message for all but the outputs
+const UTXO_MESSAGE_OVERHEAD: usize = 1_000;
+
+// Run `f` on the history index, synced with the active chain
+pub async fn query<T>(f: impl FnOnce(&HistoryIndex, &Blockchain) -> T) -> T {
+  let blockchain = crate::BLOCKCHAIN.read().await;
+  let mut index = crate::HISTORY.lock().unwrap();
+  index.sync(&blockchain);
+  f(&index, &blockchain)
+}
+
+/// What the index knows about a public key, in chain order
+#[derive(Default)]
+struct KeyHistory {
+  /// Transactions funding or spending the key
+  entries: Vec<HistoryEntry>,
+  /// Outputs paying the key, with the
+  /// height they were created at
+  funded: Vec<(u64, OutPoint)>,
+  /// Outputs of the key that were spent,
+  /// with the height they were spent at
+  spent: Vec<(u64, OutPoint)>,
+}
+
+impl KeyHistory {
+  // forget everything from `height` on
+  fn truncate(&mut self, height: u64) {
+    while self.entries.last().is_some_and(|entry| entry.height >= height) {
+      self.entries.pop();
+    }
+    while self.funded.last().is_some_and(|(at, _)| *at >= height) {
+      self.funded.pop();
+    }
+    while self.spent.last().is_some_and(|(at, _)| *at >= height) {
+      self.spent.pop();
+    }
+  }
+  fn is_empty(&self) -> bool {
+    self.entries.is_empty()
+  }
+}
+
+/// Transactions funding and spending each public key on the
+/// active chain, so wallets can page through their history and
+/// sync their UTXOs incrementally. The index follows the chain
+/// lazily: `sync` catches up with reorganizations and new blocks
+#[derive(Default)]
+pub struct HistoryIndex {
+  /// Hashes of the indexed blocks, the active chain when last synced
+  blocks: Vec<Hash>,
+  /// History by the hash of the public key
+  keys: HashMap<Hash, KeyHistory>,
+  /// Key hash and value of every confirmed output,
+  /// to tell whose outputs an input spends
+  outputs: HashMap<OutPoint, (Hash, u64)>,
+}
+
+impl HistoryIndex {
+  pub fn new() -> Self {
+    HistoryIndex::default()
+  }
+  // number of public keys with a history
+  pub fn len(&self) -> usize {
+    self.keys.len()
+  }
+  // catch up with the active chain of `blockchain`
+  pub fn sync(&mut self, blockchain: &Blockchain) {
+    let blocks: Vec<&Block> = blockchain.blocks().collect();
+    let mut fork = self.blocks.len().min(blocks.len());
+    while fork > 0 && self.blocks[fork - 1] != blocks[fork - 1].hash() {
+      fork -= 1;
+    }
+    if fork < self.blocks.len() {
+      self.disconnect_from(fork as u64);
+    }
+    for (height, block) in blocks.iter().enumerate().skip(fork) {
+      self.connect_block(block, height as u64);
+    }
+  }
+  fn connect_block(&mut self, block: &Block, height: u64) {
+    for transaction in &block.transactions {
+      // received and sent by key
+      let mut amounts: HashMap<Hash, (u64, u64)> = HashMap::new();
+      for input in &transaction.inputs {
+        let spent = input.prev_output;
+        if let Some((key, value)) = self.outputs.get(&spent) {
+          amounts.entry(*key).or_default().1 += value;
+          self.keys.entry(*key).or_default().spent.push((height, spent));
+        }
+      }
+      for (outpoint, output) in transaction.utxo_entries() {
+        let key = Hash::hash(&output.pubkey);
+        amounts.entry(key).or_default().0 += output.value;
+        self.keys.entry(key).or_default().funded.push((height, outpoint));
+        self.outputs.insert(outpoint, (key, output.value));
+      }
+      let txid = transaction.hash();
+      for (key, (received, sent)) in amounts {
+        self.keys.entry(key).or_default().entries.push(HistoryEntry {
+          txid,
+          height,
+          time: block.header.timestamp,
+          received,
+          sent,
+        });
+      }
+    }
+    self.blocks.push(block.hash());
+  }
+  // Forget the blocks from `height` on, after a reorganization.
+  // Their outputs stay known, inputs can only refer to them
+  // once they are confirmed again
+  fn disconnect_from(&mut self, height: u64) {
+    self.blocks.truncate(height as usize);
+    for history in self.keys.values_mut() {
+      history.truncate(height);
+    }
+    self.keys.retain(|_, history| !history.is_empty());
+  }
+  // Page through the history of a key, oldest first.
+  // Returns the entries and how many the key has in total
+  pub fn history(
+    &self,
+    pubkey: &PublicKey,
+    offset: u64,
+    limit: usize,
+  ) -> (Vec<HistoryEntry>, u64) {
+    let Some(history) = self.keys.get(&Hash::hash(pubkey)) else {
+      return (vec![], 0);
+    };
+    let entries = history
+      .entries
+      .iter()
+      .skip(offset.try_into().unwrap_or(usize::MAX))
+      .take(limit)
+      .cloned()
+      .collect();
+    (entries, history.entries.len() as u64)
+  }
+  // all UTXOs of a key, confirmed or not, and
+  // whether a mempool transaction spends them
+  pub fn utxos(
+    &self,
+    blockchain: &Blockchain,
+    pubkey: &PublicKey,
+  ) -> Vec<(OutPoint, TransactionOutput, bool)> {
+    let mempool = blockchain.mempool();
+    let utxos = self
+      .confirmed_utxos(blockchain, pubkey, 0)
+      .map(|(outpoint, output)| {
+        (outpoint, output.clone(), mempool.spender(&outpoint).is_some())
+      })
+      .chain(
+        mempool
+          .outputs()
+          .filter(|(_, output, _)| output.pubkey == *pubkey)
+          .map(|(outpoint, output, marked)| (*outpoint, output.clone(), marked)),
+      )
+      .collect();
+    let mut budget = MAX_UTXO_MESSAGE_SIZE as usize - UTXO_MESSAGE_OVERHEAD;
+    most_valuable(utxos, |(_, output, _)| output.value, &mut budget)
+  }
+  // Changes to the UTXOs of a key after the block `since`, or
+  // all of them if there is no such block on the active chain.
+  // Changes that don't fit a message are sent as all of them
+  // instead, the most valuable ones that fit
+  pub fn utxo_update(
+    &self,
+    blockchain: &Blockchain,
+    pubkey: &PublicKey,
+    since: Option<(u64, Hash)>,
+  ) -> UTXOUpdate {
+    let start = since
+      .filter(|(height, hash)| {
+        usize::try_from(*height)
+          .is_ok_and(|height| self.blocks.get(height) == Some(hash))
+      })
+      .map(|(height, _)| height + 1);
+    let max_size = MAX_UTXO_MESSAGE_SIZE as usize - UTXO_MESSAGE_OVERHEAD;
+    let update = self.changes(blockchain, pubkey, start);
+    if encoded_size(&update) <= max_size {
+      return update;
+    }
+    let mut update = if update.full {
+      update
+    } else {
+      self.changes(blockchain, pubkey, None)
+    };
+    let mut budget = max_size;
+    update.unconfirmed_spent =
+      most_valuable(update.unconfirmed_spent, |_| 0, &mut budget);
+    update.unconfirmed = most_valuable(
+      update.unconfirmed,
+      |(_, output)| output.value,
+      &mut budget,
+    );
+    update.created =
+      most_valuable(update.created, |(_, output)| output.value, &mut budget);
+    update
+  }
+  // changes to the UTXOs of a key from the block at `start`
+  // on, all of them if there is no start
+  fn changes(
+    &self,
+    blockchain: &Blockchain,
+    pubkey: &PublicKey,
+    start: Option<u64>,
+  ) -> UTXOUpdate {
+    let tip = self
+      .blocks
+      .last()
+      .map(|hash| (self.blocks.len() as u64 - 1, *hash));
+    let full = start.is_none();
+    let start = start.unwrap_or(0);
+    let created = self
+      .confirmed_utxos(blockchain, pubkey, start)
+      .map(|(outpoint, output)| (outpoint, output.clone()))
+      .collect();
+    let spent = match self.keys.get(&Hash::hash(pubkey)) {
+      Some(history) if !full => history
+        .spent
+        .iter()
+        .filter(|(height, _)| *height >= start)
+        .map(|(_, outpoint)| *outpoint)
+        .collect(),
+      _ => vec![],
+    };
+    let mempool = blockchain.mempool();
+    let unconfirmed = mempool
+      .outputs()
+      .filter(|(_, output, _)| output.pubkey == *pubkey)
+      .map(|(outpoint, output, _)| (*outpoint, output.clone()))
+      .collect();
+    let key_hash = Hash::hash(pubkey);
+    let unconfirmed_spent = mempool
+      .iter()
+      .flat_map(|entry| &entry.transaction.inputs)
+      .map(|input| input.prev_output)
+      .filter(|spent| {
+        self.outputs.get(spent).is_some_and(|(key, _)| *key == key_hash)
+          || mempool
+            .output(spent)
+            .is_some_and(|output| output.pubkey == *pubkey)
+      })
+      .collect();
+    UTXOUpdate {
+      tip,
+      median_time_past: blockchain.median_time_past(),
+      full,
+      created,
+      spent,
+      unconfirmed,
+      unconfirmed_spent,
+    }
+  }
+  // confirmed unspent outputs of a key created from `start` on
+  fn confirmed_utxos<'a>(
+    &'a self,
+    blockchain: &'a Blockchain,
+    pubkey: &'a PublicKey,
+    start: u64,
+  ) -> impl Iterator<Item = (OutPoint, &'a TransactionOutput)> {
+    let utxos = blockchain.utxos();
+    self
+      .keys
+      .get(&Hash::hash(pubkey))
+      .into_iter()
+      .flat_map(|history| &history.funded)
+      .filter(move |(height, _)| *height >= start)
+      .filter_map(move |(_, outpoint)| {
+        utxos.get(outpoint).map(|(_, output)| (*outpoint, output))
+      })
+  }
+}
+
+// The most valuable of `utxos` whose encodings fit in
+// `budget` bytes, taking their size off it
+fn most_valuable<T: Serialize>(
+  mut utxos: Vec<T>,
+  value: impl Fn(&T) -> u64,
+  budget: &mut usize,
+) -> Vec<T> {
+  let size = encoded_size(&utxos);
+  if size <= *budget {
+    *budget -= size;
+    return utxos;
+  }
+  utxos.sort_by_key(|utxo| Reverse(value(utxo)));
+  utxos.retain(|utxo| {
+    let size = encoded_size(utxo);
+    let fits = size <= *budget;
+    if fits {
+      *budget -= size;
+    }
+    fits
+  });
+  utxos
+}
+
+fn encoded_size(value: &impl Serialize) -> usize {
+  let mut bytes = vec![];
+  ciborium::into_writer(value, &mut bytes)
+    .expect("BUG: outputs can always be encoded");
+  bytes.len()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use btclib::crypto::{PrivateKey, Signature};
+  use btclib::params::ChainParams;
+  use btclib::pow::target_to_bits;
+  use btclib::types::{
+    BlockHeader, SigHashType, SpendingCondition, Transaction, TransactionInput,
+  };
+  use btclib::util::MerkleRoot;
+  use chrono::TimeDelta;
+  use uuid::Uuid;
+
+  const FEE: u64 = 1000;
+
+  // A regtest chain whose first block pays `key`,
+  // the rest paying `miner`
+  struct Chain {
+    params: ChainParams,
+    key: PrivateKey,
+    miner: PrivateKey,
+    blockchain: Blockchain,
+    blocks: Vec<Block>,
+  }
+
+  impl Chain {
+    fn new() -> Self {
+      let params = ChainParams::regtest();
+      let key = PrivateKey::new_key();
+      let genesis = params.genesis_block();
+      let mut blockchain = Blockchain::new(params.clone());
+      blockchain.add_block(genesis.clone()).unwrap();
+      let mut chain = Chain {
+        params,
+        key,
+        miner: PrivateKey::new_key(),
+        blockchain,
+        blocks: vec![genesis],
+      };
+      let coinbase = chain.coinbase(1, 0, &chain.key);
+      chain.extend(vec![coinbase]);
+      chain
+    }
+    fn coinbase(
+      &self,
+      height: u64,
+      fees: u64,
+      key: &PrivateKey,
+    ) -> Transaction {
+      Transaction::new(
+        vec![],
+        vec![output(self.params.block_reward(height) + fees, key)],
+      )
+    }
+    // mine a block with `transactions` on the block at
+    // `parent` and add it to the chain
+    fn mine_on(
+      &mut self,
+      parent: usize,
+      transactions: Vec<Transaction>,
+    ) -> Block {
+      let parent = &self.blocks[parent];
+      let mut header = BlockHeader::new(
+        parent.header.timestamp + TimeDelta::seconds(1),
+        0,
+        parent.hash(),
+        MerkleRoot::calculate(&transactions),
+        target_to_bits(self.params.min_target),
+      );
+      assert!(header.mine(usize::MAX));
+      let block = Block::new(header, transactions);
+      self.blockchain.add_block(block.clone()).unwrap();
+      block
+    }
+    fn extend(&mut self, transactions: Vec<Transaction>) -> Block {
+      let block = self.mine_on(self.blocks.len() - 1, transactions);
+      self.blocks.push(block.clone());
+      block
+    }
+    // an empty block paying the miner
+    fn extend_empty(&mut self) -> Block {
+      let coinbase = self.coinbase(self.blocks.len() as u64, 0, &self.miner);
+      self.extend(vec![coinbase])
+    }
+    // the output the first block pays `key`
+    fn funding(&self) -> (OutPoint, u64) {
+      let coinbase = &self.blocks[1].transactions[0];
+      (OutPoint::new(coinbase.hash(), 0), coinbase.outputs[0].value)
+    }
+    fn tip(&self) -> (u64, Hash) {
+      let tip = self.blocks.last().unwrap();
+      (self.blocks.len() as u64 - 1, tip.hash())
+    }
+  }
+
+  fn output(value: u64, key: &PrivateKey) -> TransactionOutput {
+    TransactionOutput {
+      value,
+      unique_id: Uuid::new_v4(),
+      pubkey: key.public_key(),
+      condition: SpendingCondition::Signature,
+      timelock: None,
+    }
+  }
+
+  // spend `value` held by `key` at `prev` to `to`, less the fee
+  fn spend(
+    prev: OutPoint,
+    value: u64,
+    key: &PrivateKey,
+    to: &PrivateKey,
+  ) -> Transaction {
+    let outputs = vec![output(value - FEE, to)];
+    let hash = Transaction::signature_hash_for(
+      &[prev],
+      &[TransactionInput::SEQUENCE_FINAL],
+      &outputs,
+      0,
+      SigHashType::ALL,
+    )
+    .unwrap();
+    let input = TransactionInput {
+      prev_output: prev,
+      signature: Signature::sign(&hash, key),
+      extra_signatures: vec![],
+      sighash: SigHashType::ALL,
+      sequence: TransactionInput::SEQUENCE_FINAL,
+    };
+    Transaction::new(vec![input], outputs)
+  }
+
+  #[test]
+  fn history_pages_through_the_transactions_of_a_key() {
+    let mut chain = Chain::new();
+    let payee = PrivateKey::new_key();
+    let (funding, value) = chain.funding();
+    let payment = spend(funding, value, &chain.key, &payee);
+    let coinbase = chain.coinbase(2, FEE, &chain.miner);
+    chain.extend(vec![coinbase, payment.clone()]);
+    let mut index = HistoryIndex::new();
+    index.sync(&chain.blockchain);
+    // the genesis key, the miner, the key and the payee
+    assert_eq!(index.len(), 4);
+    let key = chain.key.public_key();
+    let (entries, total) = index.history(&key, 0, 10);
+    assert_eq!(total, 2);
+    assert_eq!(
+      (entries[0].height, entries[0].received, entries[0].sent),
+      (1, value, 0)
+    );
+    assert_eq!(entries[1].txid, payment.hash());
+    assert_eq!(
+      (entries[1].height, entries[1].received, entries[1].sent),
+      (2, 0, value)
+    );
+    assert_eq!(entries[1].time, chain.blocks[2].header.timestamp);
+    // a page at a time
+    let (page, total) = index.history(&key, 1, 1);
+    assert_eq!((page.len(), total), (1, 2));
+    assert_eq!(page[0].txid, payment.hash());
+    let (page, total) = index.history(&key, 0, 1);
+    assert_eq!(page[0].height, 1);
+    assert_eq!(total, 2);
+    assert!(index.history(&key, 2, 10).0.is_empty());
+    let (entries, _) = index.history(&payee.public_key(), 0, 10);
+    assert_eq!((entries[0].received, entries[0].sent), (value - FEE, 0));
+    let (entries, total) =
+      index.history(&PrivateKey::new_key().public_key(), 0, 10);
+    assert!(entries.is_empty() && total == 0);
+  }
+
+  #[test]
+  fn utxos_include_the_mempool() {
+    let mut chain = Chain::new();
+    chain.extend_empty();
+    let (funding, value) = chain.funding();
+    let payment = spend(funding, value, &chain.key, &chain.key);
+    chain.blockchain.add_to_mempool(payment.clone()).unwrap();
+    let mut index = HistoryIndex::new();
+    index.sync(&chain.blockchain);
+    let mut utxos = index.utxos(&chain.blockchain, &chain.key.public_key());
+    utxos.sort_by_key(|(_, output, _)| output.value);
+    // the unconfirmed change, and the confirmed output it
+    // marks as spent
+    assert_eq!(utxos.len(), 2);
+    assert_eq!(
+      (utxos[0].0, utxos[0].2),
+      (OutPoint::new(payment.hash(), 0), false)
+    );
+    assert_eq!((utxos[1].0, utxos[1].2), (funding, true));
+  }
+
+  #[test]
+  fn utxo_updates_are_incremental_since_a_block_on_the_chain() {
+    let mut chain = Chain::new();
+    chain.extend_empty();
+    let key = chain.key.public_key();
+    let mut index = HistoryIndex::new();
+    index.sync(&chain.blockchain);
+    let update = index.utxo_update(&chain.blockchain, &key, None);
+    let (funding, value) = chain.funding();
+    assert!(update.full);
+    assert_eq!(update.tip, Some(chain.tip()));
+    assert_eq!(update.median_time_past, chain.blockchain.median_time_past());
+    assert_eq!(update.created.len(), 1);
+    assert_eq!(update.created[0].0, funding);
+    // a block spending the output back to the key
+    let since = chain.tip();
+    let payment = spend(funding, value, &chain.key, &chain.key);
+    let coinbase = chain.coinbase(3, FEE, &chain.miner);
+    chain.extend(vec![coinbase, payment.clone()]);
+    index.sync(&chain.blockchain);
+    let update = index.utxo_update(&chain.blockchain, &key, Some(since));
+    assert!(!update.full);
+    assert_eq!(update.tip, Some(chain.tip()));
+    assert_eq!(update.spent, vec![funding]);
+    let created: Vec<OutPoint> = update
+      .created
+      .iter()
+      .map(|(outpoint, _)| *outpoint)
+      .collect();
+    assert_eq!(created, vec![OutPoint::new(payment.hash(), 0)]);
+    // nothing new since the tip
+    let update = index.utxo_update(&chain.blockchain, &key, Some(chain.tip()));
+    assert!(
+      !update.full && update.created.is_empty() && update.spent.is_empty()
+    );
+  }
+
+  #[test]
+  fn utxo_updates_are_full_after_a_reorganization() {
+    let mut chain = Chain::new();
+    chain.extend_empty();
+    let (funding, value) = chain.funding();
+    let payment = spend(funding, value, &chain.key, &chain.key);
+    let coinbase = chain.coinbase(3, FEE, &chain.miner);
+    chain.extend(vec![coinbase, payment]);
+    let mut index = HistoryIndex::new();
+    index.sync(&chain.blockchain);
+    let since = chain.tip();
+    // a longer branch off the second block, without the payment
+    let c3 = chain.coinbase(3, 0, &chain.miner);
+    let c3 = chain.mine_on(2, vec![c3]);
+    chain.blocks.truncate(3);
+    chain.blocks.push(c3);
+    chain.extend_empty();
+    index.sync(&chain.blockchain);
+    assert_eq!(index.history(&chain.key.public_key(), 0, 10).1, 1);
+    let update = index.utxo_update(
+      &chain.blockchain,
+      &chain.key.public_key(),
+      Some(since),
+    );
+    assert!(update.full);
+    assert_eq!(update.tip, Some(chain.tip()));
+    assert!(update.spent.is_empty());
+    let created: Vec<OutPoint> = update
+      .created
+      .iter()
+      .map(|(outpoint, _)| *outpoint)
+      .collect();
+    assert_eq!(created, vec![funding]);
+  }
+}
@@ -1,86 +1,206 @@
 use std::path::Path;
 
 use argh::FromArgs;
-use btclib::network::Message;
+use btclib::network;
+use btclib::params::ChainParams;
 use btclib::types::Blockchain;
 use dashmap::DashMap;
 use static_init::dynamic;
-use anyhow::Result;
+use anyhow::{bail, Result};
 use tokio::net::{TcpListener, TcpStream};
 use tokio::sync::RwLock;
+use store::BlockStore;
+use bans::BanList;
+use history::HistoryIndex;
+use orphans::Orphans;
+use peers::{PeerManager, PeerSettings};
 
+mod bans;
 mod handler;
+mod history;
+mod orphans;
+mod peers;
+mod relay;
+mod rpc;
+mod store;
 mod util;
 
 #[derive(FromArgs)]
 /// A toy blockchain node
 struct Args {
-    #[argh(option, default = "9000")]
-    /// port number
-    port: u16,
+    #[argh(option)]
+    /// port number, the network's default port if not given
+    port: Option<u16>,
+    #[argh(
+        option,
+        default = "String::from(\"mainnet\")"
+    )]
+    /// network to join: mainnet, testnet, regtest or the
+    /// path of a TOML file with custom chain parameters
+    network: String,
+    #[argh(
+        option,
+        default = "String::from(\"./data\")"
+    )]
+    /// block store directory
+    data_dir: String,
     #[argh(
         option,
         default = "String::from(\"./blockchain.cbor\")"
     )]
-    /// blockchain file location
+    /// blockchain file to import if the block store is empty
     blockchain_file: String,
+    #[argh(option, default = "300")]
+    /// maximum mempool size in megabytes
+    max_mempool: u64,
+    #[argh(
+        option,
+        default = "btclib::MAX_MEMPOOL_TRANSACTIONS"
+    )]
+    /// maximum number of mempool transactions
+    max_mempool_transactions: usize,
+    #[argh(option, default = "8")]
+    /// number of outbound connections to keep open
+    outbound_peers: usize,
+    #[argh(option, default = "32")]
+    /// maximum number of inbound connections
+    max_inbound: usize,
+    #[argh(switch)]
+    /// keep an index of all confirmed transactions,
+    /// so they can be looked up by txid
+    txindex: bool,
+    #[argh(switch)]
+    /// replay the chain after loading it and check the
+    /// result against the UTXO set kept up to date
+    check_utxos: bool,
+    #[argh(option)]
+    /// address to serve JSON-RPC on, e.g. 127.0.0.1:8332.
+    /// No RPC server is started without it
+    rpc_bind: Option<String>,
+    #[argh(
+        option,
+        default = "String::from(\"rpc\")"
+    )]
+    /// user name of RPC clients
+    rpc_user: String,
+    #[argh(option)]
+    /// password of RPC clients. Without it a random one is
+    /// written to the .cookie file in the data directory
+    rpc_password: Option<String>,
     #[argh(positional)]
     /// addresses of initial nodes
     nodes: Vec<String>,
 }
 
+// announced to peers in the handshake
+pub const USER_AGENT: &str =
+    concat!("/node:", env!("CARGO_PKG_VERSION"), "/");
+
 #[dynamic]
-pub static BLOCKCHAIN: RwLock<Blockchain> = RwLock::new(Blockchain::new());
-// Node pool
+pub static BLOCKCHAIN: RwLock<Blockchain> = RwLock::new(Blockchain::default());
+// Node pool, our outbound connections
 #[dynamic]
 pub static NODES: DashMap<String, TcpStream> = DashMap::new();
+// Blocks and transactions waiting for their parents
+#[dynamic]
+pub static ORPHANS: std::sync::Mutex<Orphans> =
+    std::sync::Mutex::new(Orphans::new());
+// Transactions funding and spending each public key
+#[dynamic]
+pub static HISTORY: std::sync::Mutex<HistoryIndex> =
+    std::sync::Mutex::new(HistoryIndex::new());
+// Address book and connection limits
+#[dynamic]
+pub static PEERS: PeerManager = PeerManager::new();
+// Misbehaving peers
+#[dynamic]
+pub static BANS: BanList = BanList::new();
 
 #[tokio::main]
 async fn main() -> Result<()> {
     // Parse command line arguments
     let args: Args = argh::from_env();
     // Access the parsed arguments
-    let port = args.port;
+    let params = ChainParams::load(&args.network)?;
+    println!("using network: {}", params.name);
+    network::set_max_block_size(&params);
+    let port = args.port.unwrap_or(params.default_port);
     let blockchain_file = args.blockchain_file;
     let nodes = args.nodes;
 
-    // Check if the blockchain_file exists
-    if Path::new(&blockchain_file).exists() {
-        util::load_blockchain(&blockchain_file).await?;
+    *BLOCKCHAIN.write().await = Blockchain::new(params.clone());
+    let store = BlockStore::open(&args.data_dir)?;
+    if !store.is_empty() {
+        util::load_blockchain(&store).await?;
+    } else if Path::new(&blockchain_file).exists() {
+        util::import_blockchain(&blockchain_file, params.clone()).await?;
     } else {
-        println!("blockchain file does not exist!");
-        util::populate_connections(&nodes).await?;
+        println!("no stored blockchain found!");
+    }
+    // every chain of the network starts with the same block
+    if BLOCKCHAIN.read().await.block_height() == 0 {
+        BLOCKCHAIN.write().await.add_block(params.genesis_block())?;
+        println!("starting from the genesis block");
+    }
+    if args.txindex {
+        let mut blockchain = BLOCKCHAIN.write().await;
+        blockchain.enable_txindex();
         println!(
-            "total amount of known nodes: {}",
-            NODES.len()
+            "transaction index built, {} transactions",
+            blockchain.txindex().map_or(0, |txindex| txindex.len())
         );
-        if nodes.is_empty() {
-            println!("no initial nodes provided, starting as a seed node");
+    }
+    if args.check_utxos {
+        if BLOCKCHAIN.read().await.check_utxos() {
+            println!("UTXO set is consistent");
         } else {
-            let (longest_name, longest_count) =
-                util::find_longest_chain_node().await?;
-            // request the blockchain from the node with the longest blockchain
-            util::download_blockchain(
-                &longest_name,
-                longest_count,
-            )
-            .await?;
-            println!(
-                "blockchain downloaded from {}",
-                longest_name
-            );
-            // recalculate utxos
-            {
-                let mut blockchain =
-                BLOCKCHAIN.write().await;
-                blockchain.rebuild_utxos();
-            }
-            // try to adjust difficulty
-            {
-                let mut blockchain =
-                BLOCKCHAIN.write().await;
-                blockchain.try_adjust_target();
+            bail!("UTXO set does not match the chain");
+        }
+    }
+    let keys = history::query(|index, _| index.len()).await;
+    println!("history index built, {} public keys", keys);
+    BLOCKCHAIN.write().await.set_mempool_limits(
+        args.max_mempool * 1_000_000,
+        args.max_mempool_transactions,
+    );
+    let rpc_credentials = match &args.rpc_bind {
+        Some(_) => Some(rpc::credentials(
+            Path::new(&args.data_dir),
+            &args.rpc_user,
+            args.rpc_password,
+        )?),
+        None => None,
+    };
+    PEERS.configure(PeerSettings {
+        data_dir: args.data_dir.into(),
+        listen_port: port,
+        target_outbound: args.outbound_peers,
+        max_inbound: args.max_inbound,
+    })?;
+    util::populate_connections(&nodes).await?;
+    println!(
+        "total amount of known nodes: {}",
+        NODES.len()
+    );
+    if NODES.is_empty() {
+        println!("no other nodes reachable, starting as a seed node");
+    } else {
+        let (longest_name, longest_count) =
+            util::find_longest_chain_node().await?;
+        if longest_count > 0 {
+            // sync with the node that is furthest ahead of us,
+            // the others catch us up later if that fails
+            match util::download_blockchain(&longest_name).await {
+                Ok(()) => println!(
+                    "blockchain downloaded from {}",
+                    longest_name
+                ),
+                Err(e) => println!(
+                    "failed to sync with {longest_name}: {e:#}"
+                ),
             }
+        } else {
+            println!("blockchain is up to date");
         }
     }
 
@@ -92,12 +212,22 @@ async fn main() -> Result<()> {
     // start a task to periodically cleanup the mempool
     // normally, you would want to keep and join the handle
     tokio::spawn(util::cleanup());
-    // and a task to periodically save the blockchain
-    tokio::spawn(util::save(blockchain_file.clone()));
+    // and a task to periodically save new blocks
+    tokio::spawn(util::save(store));
+    // and a task to keep up the connections to other nodes
+    tokio::spawn(peers::maintain());
+    // and one fetching what they announce
+    tokio::spawn(peers::watch());
+    // and the RPC server, if asked for
+    if let (Some(bind), Some(credentials)) = (args.rpc_bind, rpc_credentials) {
+        let rpc_listener = TcpListener::bind(&bind).await?;
+        println!("RPC server listening on {}", bind);
+        tokio::spawn(rpc::serve(rpc_listener, credentials));
+    }
     
     loop {
-        let (socket, _) = listener.accept().await?;
-        tokio::spawn(handler::handle_connection(socket));
+        let (socket, addr) = listener.accept().await?;
+        tokio::spawn(handler::handle_connection(socket, addr));
     }
     // Ok(())
 }
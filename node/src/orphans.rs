@@ -0,0 +1,377 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use btclib::error::{BtcError, Result};
+use btclib::network::InventoryItem;
+use btclib::sha256::Hash;
+use btclib::types::{Block, Blockchain, Transaction};
+use btclib::validation;
+
+// orphans kept at most
+const MAX_ORPHAN_BLOCKS: usize = 50;
+const MAX_ORPHAN_TRANSACTIONS: usize = 100;
+// how long orphans wait for their parents
+const ORPHAN_BLOCK_EXPIRY: Duration = Duration::from_secs(10 * 60);
+const ORPHAN_TRANSACTION_EXPIRY: Duration = Duration::from_secs(20 * 60);
+
+// set while ancestors of an orphan block are downloaded
+static DOWNLOADING: AtomicBool = AtomicBool::new(false);
+
+/// The running download of ancestors of orphan blocks,
+/// over when dropped, also if the task panics or is cancelled
+struct Download;
+
+impl Download {
+  // start a download, unless one is running already
+  fn start() -> Option<Self> {
+    (!DOWNLOADING.swap(true, Ordering::SeqCst)).then_some(Download)
+  }
+}
+
+impl Drop for Download {
+  fn drop(&mut self) {
+    DOWNLOADING.store(false, Ordering::SeqCst);
+  }
+}
+
+/// An orphan and the parents it is waiting for
+struct Orphan<T> {
+  item: T,
+  missing: Vec<Hash>,
+  expires: Instant,
+}
+
+/// Items waiting for missing parents, keyed by the parents.
+/// When the pool is full, the orphan expiring first makes room
+pub struct OrphanPool<T> {
+  orphans: HashMap<Hash, Orphan<T>>,
+  by_parent: HashMap<Hash, HashSet<Hash>>,
+  max: usize,
+  expiry: Duration,
+}
+
+impl<T> OrphanPool<T> {
+  pub fn new(max: usize, expiry: Duration) -> Self {
+    OrphanPool {
+      orphans: HashMap::new(),
+      by_parent: HashMap::new(),
+      max,
+      expiry,
+    }
+  }
+  pub fn len(&self) -> usize {
+    self.orphans.len()
+  }
+  pub fn contains(&self, hash: &Hash) -> bool {
+    self.orphans.contains_key(hash)
+  }
+  // keep an orphan until one of its missing parents
+  // arrives, returns false if it is kept already
+  pub fn insert(&mut self, hash: Hash, item: T, missing: Vec<Hash>) -> bool {
+    if self.orphans.contains_key(&hash) {
+      return false;
+    }
+    if self.orphans.len() >= self.max {
+      let oldest = self
+        .orphans
+        .iter()
+        .min_by_key(|(_, orphan)| orphan.expires)
+        .map(|(hash, _)| *hash);
+      if let Some(oldest) = oldest {
+        self.remove(&oldest);
+      }
+    }
+    for parent in &missing {
+      self.by_parent.entry(*parent).or_default().insert(hash);
+    }
+    self.orphans.insert(
+      hash,
+      Orphan {
+        item,
+        missing,
+        expires: Instant::now() + self.expiry,
+      },
+    );
+    true
+  }
+  pub fn remove(&mut self, hash: &Hash) -> Option<T> {
+    let orphan = self.orphans.remove(hash)?;
+    for parent in &orphan.missing {
+      if let Some(children) = self.by_parent.get_mut(parent) {
+        children.remove(hash);
+        if children.is_empty() {
+          self.by_parent.remove(parent);
+        }
+      }
+    }
+    Some(orphan.item)
+  }
+  // take out the orphans waiting for `parent`
+  pub fn take_children(&mut self, parent: &Hash) -> Vec<T> {
+    let children = self.by_parent.remove(parent).unwrap_or_default();
+    children
+      .iter()
+      .filter_map(|child| self.remove(child))
+      .collect()
+  }
+  // drop the orphans whose parents didn't arrive
+  // in time, returns how many were dropped
+  pub fn expire(&mut self, now: Instant) -> usize {
+    let expired: Vec<Hash> = self
+      .orphans
+      .iter()
+      .filter(|(_, orphan)| orphan.expires <= now)
+      .map(|(hash, _)| *hash)
+      .collect();
+    for hash in &expired {
+      self.remove(hash);
+    }
+    expired.len()
+  }
+}
+
+/// What became of a block or transaction
+/// handed to the orphan pools
+pub enum Outcome {
+  /// Added, followed by the orphans that were waiting for it
+  Added(Vec<InventoryItem>),
+  /// Kept as an orphan until its missing parents arrive
+  Orphaned,
+}
+
+/// Blocks whose parent we don't have, and transactions
+/// spending outputs we don't know about
+pub struct Orphans {
+  blocks: OrphanPool<Block>,
+  /// keyed by the transactions creating the outputs they spend
+  transactions: OrphanPool<Transaction>,
+}
+
+impl Orphans {
+  pub fn new() -> Self {
+    Orphans {
+      blocks: OrphanPool::new(MAX_ORPHAN_BLOCKS, ORPHAN_BLOCK_EXPIRY),
+      transactions: OrphanPool::new(
+        MAX_ORPHAN_TRANSACTIONS,
+        ORPHAN_TRANSACTION_EXPIRY,
+      ),
+    }
+  }
+  pub fn contains(&self, item: &InventoryItem) -> bool {
+    match item {
+      InventoryItem::Block(hash) | InventoryItem::CompactBlock(hash) => {
+        self.blocks.contains(hash)
+      }
+      InventoryItem::Transaction(hash) => self.transactions.contains(hash),
+    }
+  }
+  // drop the orphans that waited too long
+  pub fn expire(&mut self) {
+    let now = Instant::now();
+    let blocks = self.blocks.expire(now);
+    let transactions = self.transactions.expire(now);
+    if blocks + transactions > 0 {
+      println!(
+        "dropped {blocks} orphan blocks and {transactions} orphan transactions, \
+        {} and {} left",
+        self.blocks.len(),
+        self.transactions.len()
+      );
+    }
+  }
+  // Add a block to the blockchain, or keep it as an orphan if
+  // its parent is unknown. Orphans waiting for it follow
+  pub fn add_block(
+    &mut self,
+    blockchain: &mut Blockchain,
+    block: Block,
+  ) -> Result<Outcome> {
+    let hash = block.hash();
+    self.blocks.remove(&hash);
+    // e.g. an orphan that got adopted before it was downloaded
+    if blockchain.get_block(&hash).is_some() {
+      return Ok(Outcome::Added(vec![]));
+    }
+    let prev_hash = block.header.prev_block_hash;
+    if prev_hash != Hash::zero() && blockchain.get_block(&prev_hash).is_none() {
+      // only keep orphans that took some work to make
+      if block.header.target() > blockchain.params().min_target
+        || !block.header.hash().matches_target(block.header.target())
+      {
+        return Err(BtcError::InvalidBlockHeader);
+      }
+      self.blocks.insert(hash, block, vec![prev_hash]);
+      return Ok(Outcome::Orphaned);
+    }
+    blockchain.add_block(block)?;
+    Ok(Outcome::Added(self.adopt(blockchain, InventoryItem::Block(hash))))
+  }
+  // Add a transaction to the mempool, or keep it as an orphan
+  // if it spends outputs we don't know. Orphans waiting for
+  // its outputs follow
+  pub fn add_transaction(
+    &mut self,
+    blockchain: &mut Blockchain,
+    transaction: Transaction,
+  ) -> Result<Outcome> {
+    let hash = transaction.hash();
+    if !self.try_transaction(blockchain, transaction)? {
+      return Ok(Outcome::Orphaned);
+    }
+    Ok(Outcome::Added(
+      self.adopt(blockchain, InventoryItem::Transaction(hash)),
+    ))
+  }
+  // add a transaction to the mempool if we know all outputs
+  // it spends, otherwise keep it as an orphan.
+  // Returns whether it was added
+  fn try_transaction(
+    &mut self,
+    blockchain: &mut Blockchain,
+    transaction: Transaction,
+  ) -> Result<bool> {
+    validation::check_transaction(&transaction)?;
+    // orphan transactions wait for the
+    // transactions creating what they spend
+    let missing: Vec<Hash> = transaction
+      .inputs
+      .iter()
+      .map(|input| input.prev_output)
+      .filter(|outpoint| {
+        !blockchain.utxos().contains_key(outpoint)
+          && blockchain.mempool().output(outpoint).is_none()
+      })
+      .map(|outpoint| outpoint.txid)
+      .collect();
+    if !missing.is_empty() {
+      self.transactions.insert(transaction.hash(), transaction, missing);
+      return Ok(false);
+    }
+    blockchain.add_to_mempool(transaction)?;
+    Ok(true)
+  }
+  // the item that was just added, followed by the
+  // orphans that were waiting for it, recursively
+  fn adopt(
+    &mut self,
+    blockchain: &mut Blockchain,
+    item: InventoryItem,
+  ) -> Vec<InventoryItem> {
+    let mut added = vec![];
+    let mut queue = VecDeque::from([item]);
+    while let Some(item) = queue.pop_front() {
+      added.push(item);
+      // transactions the item confirmed or added,
+      // which orphan transactions may be waiting for
+      let created: Vec<Hash> = match item {
+        InventoryItem::Block(hash) | InventoryItem::CompactBlock(hash) => {
+          for block in self.blocks.take_children(&hash) {
+            let child = block.hash();
+            match blockchain.add_block(block) {
+              Ok(()) => queue.push_back(InventoryItem::Block(child)),
+              Err(e) => println!("orphan block {child} rejected: {e}"),
+            }
+          }
+          blockchain
+            .get_block(&hash)
+            .map(|block| {
+              block
+                .transactions
+                .iter()
+                .map(|tx| tx.hash())
+                .collect()
+            })
+            .unwrap_or_default()
+        }
+        InventoryItem::Transaction(hash) => {
+          if blockchain.mempool().contains(&hash) {
+            vec![hash]
+          } else {
+            vec![]
+          }
+        }
+      };
+      for parent in created {
+        for transaction in self.transactions.take_children(&parent) {
+          let child = transaction.hash();
+          match self.try_transaction(blockchain, transaction) {
+            Ok(true) => queue.push_back(InventoryItem::Transaction(child)),
+            // still missing other parents
+            Ok(false) => {}
+            Err(e) => println!("orphan transaction {child} rejected: {e}"),
+          }
+        }
+      }
+    }
+    added
+  }
+}
+
+impl Default for Orphans {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+// Hand a block from a peer to the blockchain, returning what got
+// added. The missing ancestors of an orphan are requested from
+// `peer`, the address the peer accepts connections on
+pub async fn process_block(
+  block: Block,
+  peer: Option<&str>,
+) -> Result<Vec<InventoryItem>> {
+  let hash = block.hash();
+  let outcome = {
+    let mut blockchain = crate::BLOCKCHAIN.write().await;
+    crate::ORPHANS.lock().unwrap().add_block(&mut blockchain, block)?
+  };
+  match outcome {
+    Outcome::Added(items) => Ok(items),
+    Outcome::Orphaned => {
+      println!("block {hash} is an orphan");
+      if let Some(peer) = peer {
+        tokio::spawn(request_ancestors(peer.to_string()));
+      }
+      Ok(vec![])
+    }
+  }
+}
+
+// hand a transaction to the mempool, returning what got added
+pub async fn process_transaction(
+  transaction: Transaction,
+) -> Result<Vec<InventoryItem>> {
+  let hash = transaction.hash();
+  let outcome = {
+    let mut blockchain = crate::BLOCKCHAIN.write().await;
+    crate::ORPHANS
+      .lock()
+      .unwrap()
+      .add_transaction(&mut blockchain, transaction)?
+  };
+  match outcome {
+    Outcome::Added(items) => Ok(items),
+    Outcome::Orphaned => {
+      println!("transaction {hash} is an orphan");
+      Ok(vec![])
+    }
+  }
+}
+
+// Download the blocks between our chain and an orphan from the
+// peer that sent it. One download runs at a time, orphans
+// arriving meanwhile get connected by it as well
+async fn request_ancestors(peer: String) {
+  let Some(_download) = Download::start() else {
+    return;
+  };
+  println!("requesting missing blocks from {peer}");
+  let result = match crate::peers::connect_outbound(&peer).await {
+    Ok(()) => crate::util::download_blockchain(&peer).await,
+    Err(e) => Err(e),
+  };
+  if let Err(e) = result {
+    println!("failed to get missing blocks from {peer}: {e:#}");
+  }
+}
@@ -0,0 +1,638 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, RwLock};
+use std::task::{Context as TaskContext, Poll, Waker};
+
+use anyhow::{bail, ensure, Context, Result};
+use btclib::error::NetworkError;
+use btclib::network::{
+  handshake, InventoryItem, Message, Version, MAX_ADDR, MAX_INV,
+  NODE_NETWORK, RESPONSE_TIMEOUT,
+};
+use chrono::Utc;
+use dashmap::mapref::entry::Entry;
+use serde::{Deserialize, Serialize};
+use tokio::io::ReadBuf;
+use tokio::net::TcpStream;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::time::{self, Duration};
+
+use crate::relay::{self, KnownInventory};
+
+const PEERS_FILE: &str = "peers.cbor";
+// addresses kept in the address book at most
+const MAX_ADDRESSES: usize = 10_000;
+// how often the outbound target is kept up and the book is saved
+const MAINTENANCE_INTERVAL: Duration = Duration::from_secs(30);
+// how often idle outbound connections are checked for announcements
+const WATCH_INTERVAL: Duration = Duration::from_secs(1);
+// time a node has to accept our connection
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+// seconds to wait after a failed connection attempt, doubled
+// with every further failure up to MAX_RETRY_DELAY
+const RETRY_DELAY: u64 = 30;
+const MAX_RETRY_DELAY: u64 = 60 * 60;
+// addresses not seen for this many seconds are not advertised
+const ADVERTISE_HORIZON: u64 = 24 * 60 * 60;
+
+fn now() -> u64 {
+  Utc::now().timestamp() as u64
+}
+
+/// What we know about a peer address
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct AddressInfo {
+  /// Last time we were connected to the peer or heard
+  /// about it, in seconds since the unix epoch
+  pub last_seen: u64,
+  /// Last time we tried to connect to the peer
+  pub last_attempt: u64,
+  /// Successful connections
+  pub successes: u32,
+  /// Failed connection attempts since the last success
+  pub failures: u32,
+}
+
+impl AddressInfo {
+  // earliest time for the next connection attempt
+  fn next_attempt(&self) -> u64 {
+    if self.failures == 0 {
+      return self.last_attempt;
+    }
+    let backoff = 1u64 << (self.failures - 1).min(16);
+    self.last_attempt
+      + RETRY_DELAY.saturating_mul(backoff).min(MAX_RETRY_DELAY)
+  }
+}
+
+/// Known peer addresses, kept on disk between runs
+#[derive(Serialize, Deserialize, Default)]
+pub struct AddressBook {
+  addresses: HashMap<String, AddressInfo>,
+  /// Addresses that turned out to be our own
+  #[serde(default)]
+  own: HashSet<String>,
+}
+
+impl AddressBook {
+  /// Load the address book from `path`, empty if there is none
+  pub fn load(path: &Path) -> Result<Self> {
+    if !path.exists() {
+      return Ok(AddressBook::default());
+    }
+    ciborium::from_reader(BufReader::new(File::open(path)?))
+      .context("failed to read address book")
+  }
+  pub fn save(&self, path: &Path) -> Result<()> {
+    let tmp_path = path.with_extension("cbor.tmp");
+    let mut file = File::create(&tmp_path)?;
+    ciborium::into_writer(self, &mut file)
+      .context("failed to write address book")?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+  }
+  pub fn len(&self) -> usize {
+    self.addresses.len()
+  }
+  pub fn get(&self, address: &str) -> Option<&AddressInfo> {
+    self.addresses.get(address)
+  }
+  // Add an address we heard about at `seen`. When the book is
+  // full, the address failing the most and seen the longest
+  // ago makes room. Returns true if the address is new
+  pub fn add(&mut self, address: &str, seen: u64) -> bool {
+    if self.own.contains(address) {
+      return false;
+    }
+    if let Some(info) = self.addresses.get_mut(address) {
+      info.last_seen = info.last_seen.max(seen);
+      return false;
+    }
+    if self.addresses.len() >= MAX_ADDRESSES {
+      let worst = self
+        .addresses
+        .iter()
+        .max_by_key(|(_, info)| (info.failures, u64::MAX - info.last_seen))
+        .map(|(address, _)| address.clone());
+      if let Some(worst) = worst {
+        self.addresses.remove(&worst);
+      }
+    }
+    self.addresses.insert(
+      address.to_string(),
+      AddressInfo {
+        last_seen: seen,
+        ..Default::default()
+      },
+    );
+    true
+  }
+  // forget an address that leads back to ourselves
+  pub fn remove_own(&mut self, address: &str) {
+    self.addresses.remove(address);
+    self.own.insert(address.to_string());
+  }
+  pub fn attempted(&mut self, address: &str, now: u64) {
+    if let Some(info) = self.addresses.get_mut(address) {
+      info.last_attempt = now;
+    }
+  }
+  pub fn succeeded(&mut self, address: &str, now: u64) {
+    if let Some(info) = self.addresses.get_mut(address) {
+      info.last_seen = now;
+      info.successes += 1;
+      info.failures = 0;
+    }
+  }
+  pub fn failed(&mut self, address: &str) {
+    if let Some(info) = self.addresses.get_mut(address) {
+      info.failures = info.failures.saturating_add(1);
+    }
+  }
+  // addresses due for a connection attempt at `now`,
+  // the most reliable first
+  pub fn candidates(&self, now: u64) -> Vec<String> {
+    let mut candidates: Vec<(&String, &AddressInfo)> = self
+      .addresses
+      .iter()
+      .filter(|(_, info)| info.next_attempt() <= now)
+      .collect();
+    candidates.sort_by_key(|(_, info)| {
+      (info.failures, u32::MAX - info.successes, u64::MAX - info.last_seen)
+    });
+    candidates
+      .into_iter()
+      .map(|(address, _)| address.clone())
+      .collect()
+  }
+  // at most MAX_ADDR recently seen addresses
+  // to tell other nodes about
+  pub fn advertised(&self, now: u64) -> Vec<String> {
+    let mut recent: Vec<(&String, &AddressInfo)> = self
+      .addresses
+      .iter()
+      .filter(|(_, info)| info.failures == 0)
+      .filter(|(_, info)| info.last_seen + ADVERTISE_HORIZON >= now)
+      .collect();
+    recent.sort_by_key(|(_, info)| u64::MAX - info.last_seen);
+    recent
+      .into_iter()
+      .take(MAX_ADDR)
+      .map(|(address, _)| address.clone())
+      .collect()
+  }
+}
+
+/// Settings of the peer manager
+pub struct PeerSettings {
+  /// Directory the address book is kept in
+  pub data_dir: PathBuf,
+  /// Port we accept connections on
+  pub listen_port: u16,
+  /// Outbound connections we try to keep open
+  pub target_outbound: usize,
+  /// Inbound connections we accept at most
+  pub max_inbound: usize,
+}
+
+impl Default for PeerSettings {
+  fn default() -> Self {
+    PeerSettings {
+      data_dir: PathBuf::from("./data"),
+      listen_port: 0,
+      target_outbound: 8,
+      max_inbound: 32,
+    }
+  }
+}
+
+/// Keeps the address book and the connections to other
+/// nodes: the outbound ones we open live in crate::NODES and
+/// are topped up to the target from the address book, inbound
+/// ones are handled by the handler as long as there is room.
+/// Inbound nodes get the items we relay through a queue their
+/// connection handler drains
+pub struct PeerManager {
+  book: Mutex<AddressBook>,
+  settings: RwLock<PeerSettings>,
+  inbound: AtomicUsize,
+  /// Inbound connections from other nodes, by the address
+  /// they come from
+  inbound_peers: Mutex<HashMap<SocketAddr, InboundPeer>>,
+  /// Inventory of every node we announce to
+  known: Mutex<HashMap<String, KnownInventory>>,
+  /// Items outbound peers announced while we were waiting
+  /// for a reply from them, still to be fetched
+  announced: Mutex<HashMap<String, Vec<InventoryItem>>>,
+  /// Announced in our Version to notice connections to ourselves
+  nonce: u64,
+}
+
+/// Room for one inbound connection, given back when dropped
+pub struct InboundSlot<'a>(&'a AtomicUsize);
+
+impl Drop for InboundSlot<'_> {
+  fn drop(&mut self) {
+    self.0.fetch_sub(1, Ordering::SeqCst);
+  }
+}
+
+/// An inbound connection from another node
+#[derive(Clone)]
+pub struct InboundPeer {
+  /// The address the node accepts connections on
+  pub address: String,
+  pub user_agent: String,
+  /// Seconds since the unix epoch
+  pub connected_at: u64,
+  /// Items to announce over the connection, the
+  /// connection handler sends them as Inv messages
+  queue: UnboundedSender<Vec<InventoryItem>>,
+}
+
+/// An inbound connection registered with the peer
+/// manager, forgotten when dropped
+pub struct InboundRegistration<'a> {
+  manager: &'a PeerManager,
+  from: SocketAddr,
+}
+
+impl Drop for InboundRegistration<'_> {
+  fn drop(&mut self) {
+    let mut peers = self.manager.inbound_peers.lock().unwrap();
+    if let Some(peer) = peers.remove(&self.from) {
+      if !crate::NODES.contains_key(&peer.address) {
+        self.manager.known.lock().unwrap().remove(&peer.address);
+      }
+    }
+  }
+}
+
+impl PeerManager {
+  pub fn new() -> Self {
+    PeerManager {
+      book: Mutex::new(AddressBook::default()),
+      settings: RwLock::new(PeerSettings::default()),
+      inbound: AtomicUsize::new(0),
+      inbound_peers: Mutex::new(HashMap::new()),
+      known: Mutex::new(HashMap::new()),
+      announced: Mutex::new(HashMap::new()),
+      nonce: rand::random(),
+    }
+  }
+  /// Apply the settings and load the address book
+  pub fn configure(&self, settings: PeerSettings) -> Result<()> {
+    let book = AddressBook::load(&settings.data_dir.join(PEERS_FILE))?;
+    println!("address book loaded, {} known addresses", book.len());
+    *self.book.lock().unwrap() = book;
+    *self.settings.write().unwrap() = settings;
+    Ok(())
+  }
+  pub fn save(&self) -> Result<()> {
+    let path = self.settings.read().unwrap().data_dir.join(PEERS_FILE);
+    self.book.lock().unwrap().save(&path)
+  }
+  pub fn with_book<T>(&self, f: impl FnOnce(&mut AddressBook) -> T) -> T {
+    f(&mut self.book.lock().unwrap())
+  }
+  // what we announce to peers in the handshake
+  pub fn version(&self, blockchain: &btclib::types::Blockchain) -> Version {
+    Version {
+      listen_port: Some(self.settings.read().unwrap().listen_port),
+      nonce: self.nonce,
+      ..Version::new(
+        blockchain.params(),
+        NODE_NETWORK,
+        blockchain.block_height(),
+        crate::USER_AGENT,
+      )
+    }
+  }
+  // room for another inbound connection, if there is any
+  pub fn inbound_slot(&self) -> Option<InboundSlot<'_>> {
+    let max = self.settings.read().unwrap().max_inbound;
+    self
+      .inbound
+      .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |count| {
+        (count < max).then_some(count + 1)
+      })
+      .ok()
+      .map(|_| InboundSlot(&self.inbound))
+  }
+  // Register an inbound connection from `from` to a node that
+  // accepts connections at `address`. Items relayed to it
+  // arrive at the returned receiver
+  pub fn register_inbound(
+    &self,
+    from: SocketAddr,
+    address: String,
+    user_agent: String,
+  ) -> (InboundRegistration<'_>, UnboundedReceiver<Vec<InventoryItem>>) {
+    let (queue, announcements) = mpsc::unbounded_channel();
+    let peer = InboundPeer {
+      address,
+      user_agent,
+      connected_at: now(),
+      queue,
+    };
+    self.inbound_peers.lock().unwrap().insert(from, peer);
+    let registration = InboundRegistration {
+      manager: self,
+      from,
+    };
+    (registration, announcements)
+  }
+  pub fn inbound_peers(&self) -> Vec<(SocketAddr, InboundPeer)> {
+    let peers = self.inbound_peers.lock().unwrap();
+    peers
+      .iter()
+      .map(|(from, peer)| (*from, peer.clone()))
+      .collect()
+  }
+  // Queue the items an inbound peer doesn't know yet for
+  // its connection handler to announce
+  pub fn announce_inbound(&self, items: &[InventoryItem]) {
+    for (_, peer) in self.inbound_peers() {
+      let unknown = self.take_unknown(&peer.address, items);
+      if !unknown.is_empty() {
+        // the connection is closing if nobody receives
+        let _ = peer.queue.send(unknown);
+      }
+    }
+  }
+  // add an address we got from somewhere else
+  // than another node, returns true if it is new
+  pub fn add_address(&self, address: &str) -> bool {
+    self.with_book(|book| book.add(address, now()))
+  }
+  // Add addresses other nodes told us about, ignoring the
+  // ones that are not an ip and port. Returns the new ones
+  pub fn add_heard(&self, addresses: Vec<String>) -> Vec<String> {
+    let now = now();
+    self.with_book(|book| {
+      addresses
+        .into_iter()
+        .filter(|address| address.parse::<SocketAddr>().is_ok())
+        .filter(|address| book.add(address, now))
+        .collect()
+    })
+  }
+  pub fn advertised(&self) -> Vec<String> {
+    self.with_book(|book| book.advertised(now()))
+  }
+  // remember that a node has these items
+  pub fn mark_known(&self, node: &str, items: &[InventoryItem]) {
+    let mut known = self.known.lock().unwrap();
+    let inventory = known.entry(node.to_string()).or_default();
+    for item in items {
+      inventory.insert(*item);
+    }
+  }
+  // the items a node doesn't know about yet,
+  // which it will know once we announced them
+  pub fn take_unknown(
+    &self,
+    node: &str,
+    items: &[InventoryItem],
+  ) -> Vec<InventoryItem> {
+    let mut known = self.known.lock().unwrap();
+    let inventory = known.entry(node.to_string()).or_default();
+    items
+      .iter()
+      .filter(|item| inventory.insert(**item))
+      .copied()
+      .collect()
+  }
+  // remember items a node announced over our outbound
+  // connection while we were waiting for a reply
+  pub fn defer_announced(&self, node: &str, items: Vec<InventoryItem>) {
+    let mut announced = self.announced.lock().unwrap();
+    announced.entry(node.to_string()).or_default().extend(items);
+  }
+  pub fn take_announced(&self, node: &str) -> Vec<InventoryItem> {
+    let mut announced = self.announced.lock().unwrap();
+    announced.remove(node).unwrap_or_default()
+  }
+  // forget an outbound connection that is gone
+  pub fn disconnected(&self, node: &str) {
+    crate::NODES.remove(node);
+    self.announced.lock().unwrap().remove(node);
+    let inbound = self
+      .inbound_peers
+      .lock()
+      .unwrap()
+      .values()
+      .any(|peer| peer.address == node);
+    if !inbound {
+      self.known.lock().unwrap().remove(node);
+    }
+  }
+}
+
+impl Default for PeerManager {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+// what we announce to peers in the handshake
+pub async fn local_version() -> Version {
+  crate::PEERS.version(&*crate::BLOCKCHAIN.read().await)
+}
+
+// Count misbehavior of an outbound peer against its address
+// and drop the connection
+pub fn misbehaved(node: &str, error: &NetworkError) {
+  let address = crate::NODES
+    .get(node)
+    .and_then(|stream| stream.peer_addr().ok());
+  if let Some(address) = address {
+    crate::BANS.misbehaving(address.ip(), error);
+  }
+  crate::PEERS.disconnected(node);
+}
+
+// open a connection to a node and complete the handshake
+pub async fn connect(node: &str) -> Result<TcpStream> {
+  let mut stream = time::timeout(CONNECT_TIMEOUT, TcpStream::connect(node))
+    .await
+    .map_err(|_| NetworkError::Timeout)??;
+  let ip = stream.peer_addr()?.ip();
+  ensure!(!crate::BANS.is_banned(ip), "{node} is banned");
+  let version = match handshake(&mut stream, &local_version().await).await {
+    Ok(version) => version,
+    Err(e) => {
+      crate::BANS.misbehaving(ip, &e);
+      return Err(
+        anyhow::Error::new(e).context(format!("handshake with {node} failed")),
+      );
+    }
+  };
+  println!(
+    "connected to {node}: {}, height {}",
+    version.user_agent, version.best_height
+  );
+  Ok(stream)
+}
+
+// Open an outbound connection to a node from the address book,
+// keeping track of how that went. The node is told about the
+// addresses we know
+pub async fn connect_outbound(node: &str) -> Result<()> {
+  if crate::NODES.contains_key(node) {
+    return Ok(());
+  }
+  crate::PEERS.with_book(|book| book.attempted(node, now()));
+  let mut stream = match connect(node).await {
+    Ok(stream) => stream,
+    Err(e) => {
+      crate::PEERS.with_book(|book| {
+        if let Some(NetworkError::SelfConnection) = e.downcast_ref() {
+          book.remove_own(node);
+        } else {
+          book.failed(node);
+        }
+      });
+      return Err(e);
+    }
+  };
+  crate::PEERS.with_book(|book| book.succeeded(node, now()));
+  let addresses = crate::PEERS.advertised();
+  if !addresses.is_empty() {
+    Message::Addr(addresses).send_async(&mut stream).await?;
+  }
+  crate::NODES.insert(node.to_string(), stream);
+  Ok(())
+}
+
+// open outbound connections until we have as many as targeted
+pub async fn fill_outbound() {
+  let target = crate::PEERS.settings.read().unwrap().target_outbound;
+  if crate::NODES.len() >= target {
+    return;
+  }
+  let candidates = crate::PEERS.with_book(|book| book.candidates(now()));
+  for node in candidates {
+    if crate::NODES.len() >= target {
+      break;
+    }
+    if crate::NODES.contains_key(&node) {
+      continue;
+    }
+    if let Err(e) = connect_outbound(&node).await {
+      println!("failed to connect to {node}: {e:#}");
+    }
+  }
+}
+
+// Check the outbound connections not in use right now. They
+// carry our requests and their responses, and in between only
+// the Inv messages the peer announces items with: those items
+// are fetched, anything else means the peer closed the stream
+// or is out of step with our requests, and it is dropped
+pub async fn check_connections() {
+  let nodes = crate::NODES
+    .iter()
+    .map(|x| x.key().clone())
+    .collect::<Vec<_>>();
+  for node in nodes {
+    // take the stream out of the pool while we use it,
+    // skipping the ones that are in use
+    let Some(Entry::Occupied(entry)) = crate::NODES.try_entry(node.clone())
+    else {
+      continue;
+    };
+    let mut stream = entry.remove();
+    match receive_announcements(&mut stream, &node).await {
+      Ok(()) => {
+        crate::NODES.insert(node, stream);
+      }
+      Err(e) => {
+        println!("connection to {node} is gone: {e:#}, dropping it");
+        crate::PEERS.disconnected(&node);
+      }
+    }
+  }
+}
+
+// Fetch the items a node announced over its idle outbound
+// stream: the ones that arrived while we were waiting for
+// a reply, and an Inv the node sent since
+async fn receive_announcements(
+  stream: &mut TcpStream,
+  node: &str,
+) -> Result<()> {
+  let deferred = crate::PEERS.take_announced(node);
+  for chunk in deferred.chunks(MAX_INV) {
+    relay::handle_inv(stream, chunk.to_vec(), Some(node), true).await?;
+  }
+  if !has_data(stream)? {
+    return Ok(());
+  }
+  match Message::receive_async_timeout(stream, RESPONSE_TIMEOUT).await? {
+    Message::Inv(items) => {
+      relay::handle_inv(stream, items, Some(node), true).await?
+    }
+    e => bail!("unexpected message: {e:?}"),
+  }
+  Ok(())
+}
+
+// Whether there is anything to read on a stream, without
+// waiting. Fails if the peer closed the stream
+fn has_data(stream: &TcpStream) -> Result<bool> {
+  let mut byte = [0u8; 1];
+  let mut buf = ReadBuf::new(&mut byte);
+  let mut cx = TaskContext::from_waker(Waker::noop());
+  match stream.poll_peek(&mut cx, &mut buf) {
+    Poll::Pending => Ok(false),
+    Poll::Ready(Ok(0)) => bail!("closed by the peer"),
+    Poll::Ready(result) => Ok(result.map(|_| true)?),
+  }
+}
+
+// check idle outbound connections every WATCH_INTERVAL
+pub async fn watch() {
+  let mut interval = time::interval(WATCH_INTERVAL);
+  loop {
+    interval.tick().await;
+    check_connections().await;
+  }
+}
+
+// send a message to all outbound peers,
+// dropping the connections that fail
+pub async fn broadcast(message: &Message) {
+  let nodes = crate::NODES
+    .iter()
+    .map(|x| x.key().clone())
+    .collect::<Vec<_>>();
+  for node in nodes {
+    let failed = match crate::NODES.get_mut(&node) {
+      Some(mut stream) => message.send_async(&mut *stream).await.is_err(),
+      None => false,
+    };
+    if failed {
+      println!("failed to send to {node}, dropping it");
+      crate::PEERS.disconnected(&node);
+    }
+  }
+}
+
+// Periodically reconnect to keep the outbound
+// target and save the address book
+pub async fn maintain() {
+  let mut interval = time::interval(MAINTENANCE_INTERVAL);
+  loop {
+    interval.tick().await;
+    fill_outbound().await;
+    if let Err(e) = crate::PEERS.save() {
+      println!("failed to save the address book: {e}");
+    }
+  }
+}
@@ -0,0 +1,407 @@
+use std::collections::{HashSet, VecDeque};
+
+use anyhow::{bail, ensure, Context, Result};
+use btclib::compact::{CompactBlock, PartialBlock};
+use btclib::error::{BtcError, NetworkError};
+use btclib::network::{InventoryItem, Message, MAX_INV, RESPONSE_TIMEOUT};
+use btclib::sha256::Hash;
+use btclib::types::{Block, Blockchain};
+use tokio::net::TcpStream;
+
+use crate::orphans::{self, Orphans};
+
+// inventory remembered per peer at most
+const MAX_KNOWN_INVENTORY: usize = 50_000;
+
+/// Inventory a peer is known to have, because it announced it
+/// to us or we announced it to the peer. The oldest items are
+/// forgotten first
+#[derive(Default)]
+pub struct KnownInventory {
+  items: HashSet<InventoryItem>,
+  order: VecDeque<InventoryItem>,
+}
+
+impl KnownInventory {
+  // returns false if the item was known already
+  pub fn insert(&mut self, item: InventoryItem) -> bool {
+    if !self.items.insert(item) {
+      return false;
+    }
+    self.order.push_back(item);
+    if self.order.len() > MAX_KNOWN_INVENTORY {
+      if let Some(oldest) = self.order.pop_front() {
+        self.items.remove(&oldest);
+      }
+    }
+    true
+  }
+}
+
+// whether we still need to download an item
+pub fn is_wanted(
+  blockchain: &Blockchain,
+  orphans: &Orphans,
+  item: &InventoryItem,
+) -> bool {
+  let known = match item {
+    InventoryItem::Transaction(hash) => blockchain.mempool().contains(hash),
+    InventoryItem::Block(hash) | InventoryItem::CompactBlock(hash) => {
+      blockchain.get_block(hash).is_some()
+    }
+  };
+  !known && !orphans.contains(item)
+}
+
+// The answer to a GetData: the data of every item in
+// order, or a NotFound for the items we don't have
+pub fn get_data(
+  blockchain: &Blockchain,
+  items: &[InventoryItem],
+) -> Vec<Message> {
+  items
+    .iter()
+    .map(|item| {
+      let data = match item {
+        InventoryItem::Transaction(hash) => blockchain
+          .mempool()
+          .get(hash)
+          .map(|entry| Message::NewTransaction(entry.transaction.clone())),
+        InventoryItem::Block(hash) => {
+          blockchain.get_block(hash).cloned().map(Message::NewBlock)
+        }
+        InventoryItem::CompactBlock(hash) => {
+          blockchain.get_block(hash).map(|block| {
+            Message::CompactBlock(CompactBlock::new(block, rand::random()))
+          })
+        }
+      };
+      data.unwrap_or(Message::NotFound(vec![*item]))
+    })
+    .collect()
+}
+
+// The answer to a FetchTransaction: a mempool transaction,
+// or a confirmed one if the transaction index is enabled
+pub fn transaction_info(blockchain: &Blockchain, txid: &Hash) -> Message {
+  if let Some(entry) = blockchain.mempool().get(txid) {
+    return Message::TransactionInfo {
+      transaction: entry.transaction.clone(),
+      location: None,
+      confirmations: 0,
+    };
+  }
+  match blockchain.find_transaction(txid) {
+    Some((transaction, location)) => Message::TransactionInfo {
+      transaction: transaction.clone(),
+      location: Some(location),
+      confirmations: blockchain.block_height() - location.height,
+    },
+    None => Message::NotFound(vec![InventoryItem::Transaction(*txid)]),
+  }
+}
+
+// The answer to a non-empty GetBlockTxn: the transactions
+// of the block at the indexes asked for
+pub fn block_transactions(
+  blockchain: &Blockchain,
+  hash: Hash,
+  indexes: &[u32],
+) -> Result<Message, NetworkError> {
+  let Some(block) = blockchain.get_block(&hash) else {
+    return Ok(Message::NotFound(vec![InventoryItem::Block(hash)]));
+  };
+  let transactions = indexes
+    .iter()
+    .map(|index| block.transactions.get(*index as usize).cloned())
+    .collect::<Option<Vec<_>>>()
+    .ok_or(NetworkError::UnexpectedMessage(
+      "transaction index out of range",
+    ))?;
+  Ok(Message::BlockTxn {
+    block: hash,
+    transactions,
+  })
+}
+
+// Announce items to all peers that don't know them yet. Outbound
+// peers are sent the ones they ask for right away and dropped
+// if they fail to answer, inbound ones get an Inv from their
+// connection handler and ask for the items over their own
+// connection to us
+pub async fn relay(items: Vec<InventoryItem>) {
+  crate::PEERS.announce_inbound(&items);
+  let nodes = crate::NODES
+    .iter()
+    .map(|x| x.key().clone())
+    .collect::<Vec<_>>();
+  for node in nodes {
+    let unknown = crate::PEERS.take_unknown(&node, &items);
+    if unknown.is_empty() {
+      continue;
+    }
+    let result = match crate::NODES.get_mut(&node) {
+      Some(mut stream) => announce(&mut stream, &node, &unknown).await,
+      None => Ok(()),
+    };
+    if let Err(e) = result {
+      println!("failed to relay to {node}: {e:#}, dropping it");
+      crate::PEERS.disconnected(&node);
+    }
+  }
+}
+
+// Send Inv messages and answer the GetData following each.
+// Every compact block sent is followed by the peer's
+// GetBlockTxn for what it couldn't rebuild
+async fn announce(
+  stream: &mut TcpStream,
+  node: &str,
+  items: &[InventoryItem],
+) -> Result<()> {
+  for chunk in items.chunks(MAX_INV) {
+    Message::Inv(chunk.to_vec()).send_async(stream).await?;
+    let wanted = match receive_reply(stream, Some(node)).await? {
+      Message::GetData(wanted) => wanted,
+      e => bail!("unexpected message: {e:?}"),
+    };
+    ensure!(
+      wanted.iter().all(|item| chunk.contains(&announced(*item))),
+      "peer asked for items we did not announce"
+    );
+    let responses = get_data(&*crate::BLOCKCHAIN.read().await, &wanted);
+    for response in responses {
+      response.send_async(stream).await?;
+      if let Message::CompactBlock(compact) = response {
+        send_block_transactions(stream, node, compact.hash()).await?;
+      }
+    }
+  }
+  Ok(())
+}
+
+// the item announced for one asked for in a GetData
+fn announced(item: InventoryItem) -> InventoryItem {
+  match item {
+    InventoryItem::CompactBlock(hash) => InventoryItem::Block(hash),
+    item => item,
+  }
+}
+
+// answer the GetBlockTxn for a compact block we sent
+async fn send_block_transactions(
+  stream: &mut TcpStream,
+  node: &str,
+  hash: Hash,
+) -> Result<()> {
+  let indexes = match receive_reply(stream, Some(node)).await? {
+    Message::GetBlockTxn { block, indexes } if block == hash => indexes,
+    e => bail!("unexpected message: {e:?}"),
+  };
+  if indexes.is_empty() {
+    return Ok(());
+  }
+  let response =
+    block_transactions(&*crate::BLOCKCHAIN.read().await, hash, &indexes)?;
+  response.send_async(stream).await?;
+  Ok(())
+}
+
+// Receive the reply to a request. Over our outbound connection
+// to `node` the peer may announce items at any time, Invs
+// arriving before the reply are left to peers::check_connections
+pub async fn receive_reply(
+  stream: &mut TcpStream,
+  node: Option<&str>,
+) -> Result<Message, NetworkError> {
+  loop {
+    let message =
+      Message::receive_async_timeout(stream, RESPONSE_TIMEOUT).await?;
+    match (message, node) {
+      (Message::Inv(items), Some(node)) => {
+        if items.len() > MAX_INV {
+          return Err(NetworkError::UnexpectedMessage(
+            "too many inventory items",
+          ));
+        }
+        crate::PEERS.defer_announced(node, items);
+      }
+      (message, _) => return Ok(message),
+    }
+  }
+}
+
+// Answer an Inv: ask for the items we don't have, blocks as
+// compact blocks, add what we get and relay it further.
+// `peer` is the address the peer accepts connections on, and
+// `outbound` whether the Inv came over our connection to it
+// rather than from a peer that connected to us
+pub async fn handle_inv(
+  socket: &mut TcpStream,
+  items: Vec<InventoryItem>,
+  peer: Option<&str>,
+  outbound: bool,
+) -> Result<(), NetworkError> {
+  if items.len() > MAX_INV {
+    return Err(NetworkError::UnexpectedMessage("too many inventory items"));
+  }
+  if let Some(peer) = peer {
+    crate::PEERS.mark_known(peer, &items);
+  }
+  let wanted: Vec<InventoryItem> = {
+    let blockchain = crate::BLOCKCHAIN.read().await;
+    let orphans = crate::ORPHANS.lock().unwrap();
+    items
+      .into_iter()
+      .filter(|item| is_wanted(&blockchain, &orphans, item))
+      .map(|item| match item {
+        InventoryItem::Block(hash) => InventoryItem::CompactBlock(hash),
+        item => item,
+      })
+      .collect()
+  };
+  // a peer announcing over our connection doesn't wait for
+  // the GetData, so there is no need to send an empty one
+  if outbound && wanted.is_empty() {
+    return Ok(());
+  }
+  let reply_from = peer.filter(|_| outbound);
+  Message::GetData(wanted.clone()).send_async(socket).await?;
+  let mut accepted = vec![];
+  for item in wanted {
+    let message = receive_reply(socket, reply_from).await?;
+    match (item, message) {
+      (InventoryItem::Transaction(hash), Message::NewTransaction(tx))
+        if tx.hash() == hash =>
+      {
+        match orphans::process_transaction(tx).await {
+          Ok(added) if added.is_empty() => {}
+          Ok(added) => {
+            println!("received relayed transaction {hash}");
+            accepted.extend(added);
+          }
+          Err(e) => println!("relayed transaction {hash} rejected: {e}"),
+        }
+      }
+      (InventoryItem::CompactBlock(hash), Message::CompactBlock(compact))
+        if compact.hash() == hash =>
+      {
+        let Some(block) =
+          receive_compact_block(socket, compact, peer, reply_from).await?
+        else {
+          continue;
+        };
+        match orphans::process_block(block, peer).await {
+          Ok(added) if added.is_empty() => {}
+          Ok(added) => {
+            println!("received relayed block {hash}");
+            accepted.extend(added);
+          }
+          Err(e) => println!("relayed block {hash} rejected: {e}"),
+        }
+      }
+      (_, Message::NotFound(_)) => {}
+      _ => {
+        return Err(NetworkError::UnexpectedMessage(
+          "data does not match the GetData",
+        ))
+      }
+    }
+  }
+  if !accepted.is_empty() {
+    tokio::spawn(relay(accepted));
+  }
+  Ok(())
+}
+
+// Rebuild a compact block from the mempool, asking the peer for
+// the transactions that are missing. If the block doesn't match
+// its merkle root, the full block is fetched from `peer` instead
+// and None is returned. `reply_from` is the node whose outbound
+// stream `socket` is, if it is one
+async fn receive_compact_block(
+  socket: &mut TcpStream,
+  compact: CompactBlock,
+  peer: Option<&str>,
+  reply_from: Option<&str>,
+) -> Result<Option<Block>, NetworkError> {
+  let hash = compact.hash();
+  let partial = {
+    let blockchain = crate::BLOCKCHAIN.read().await;
+    PartialBlock::new(compact, blockchain.mempool(), blockchain.params())
+  }
+  .map_err(|e| match e {
+    BtcError::BlockTooLarge => {
+      NetworkError::UnexpectedMessage("compact block has too many transactions")
+    }
+    _ => NetworkError::UnexpectedMessage("malformed compact block"),
+  })?;
+  let missing = partial.missing();
+  Message::GetBlockTxn {
+    block: hash,
+    indexes: missing.clone(),
+  }
+  .send_async(socket)
+  .await?;
+  let transactions = if missing.is_empty() {
+    vec![]
+  } else {
+    match receive_reply(socket, reply_from).await? {
+      Message::BlockTxn {
+        block,
+        transactions,
+      } if block == hash => transactions,
+      Message::NotFound(_) => return Ok(None),
+      _ => {
+        return Err(NetworkError::UnexpectedMessage(
+          "expected BlockTxn after GetBlockTxn",
+        ))
+      }
+    }
+  };
+  println!(
+    "rebuilt compact block {hash}, {} transactions were missing",
+    missing.len()
+  );
+  match partial.fill(transactions) {
+    Ok(block) => Ok(Some(block)),
+    Err(BtcError::InvalidMerkleRoot) => {
+      println!("compact block {hash} does not match its merkle root");
+      if let Some(peer) = peer {
+        tokio::spawn(fetch_full_block(peer.to_string(), hash));
+      }
+      Ok(None)
+    }
+    Err(_) => Err(NetworkError::UnexpectedMessage(
+      "BlockTxn does not match the GetBlockTxn",
+    )),
+  }
+}
+
+// fetch a block that could not be rebuilt from its compact
+// block over our outbound connection to `peer`
+async fn fetch_full_block(peer: String, hash: Hash) {
+  let result = async {
+    crate::peers::connect_outbound(&peer).await?;
+    let mut stream = crate::NODES.get_mut(&peer).context("no node")?;
+    Message::GetData(vec![InventoryItem::Block(hash)])
+      .send_async(&mut *stream)
+      .await?;
+    match receive_reply(&mut stream, Some(&peer)).await? {
+      Message::NewBlock(block) if block.hash() == hash => Ok(block),
+      e => bail!("unexpected message: {e:?}"),
+    }
+  }
+  .await;
+  let block = match result {
+    Ok(block) => block,
+    Err(e) => {
+      println!("failed to fetch block {hash} from {peer}: {e:#}");
+      return;
+    }
+  };
+  match orphans::process_block(block, Some(&peer)).await {
+    Ok(added) => relay(added).await,
+    Err(e) => println!("block {hash} rejected: {e}"),
+  }
+}
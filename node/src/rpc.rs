@@ -0,0 +1,717 @@
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use base64ct::{Base64, Encoding};
+use btclib::pow;
+use btclib::sha256::Hash;
+use btclib::types::{Block, Blockchain, OutPoint, Transaction};
+use chrono::Utc;
+use serde_json::{json, Value};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::{self, Duration};
+
+use crate::{orphans, relay};
+
+// JSON-RPC 2.0 over HTTP for dashboards and scripts. Requests
+// are POSTed to any path with HTTP basic authentication, either
+// with the configured password or the cookie written to the
+// data directory
+
+const COOKIE_FILE: &str = ".cookie";
+const COOKIE_USER: &str = "__cookie__";
+// limits of a request
+const MAX_LINE: usize = 8 * 1024;
+const MAX_HEADERS: usize = 100;
+const MAX_BODY: usize = 4_000_000;
+// time a client has to send a request, including
+// the wait for the next one on a kept-alive connection
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+// delay before answering a request with wrong
+// credentials, to slow down guessing
+const AUTH_FAILURE_DELAY: Duration = Duration::from_millis(250);
+
+// error codes, the same as bitcoind's
+const PARSE_ERROR: i32 = -32700;
+const INVALID_REQUEST: i32 = -32600;
+const METHOD_NOT_FOUND: i32 = -32601;
+const INVALID_PARAMS: i32 = -32602;
+const INVALID_PARAMETER: i32 = -8;
+const NOT_FOUND: i32 = -5;
+const DESERIALIZATION_ERROR: i32 = -22;
+const VERIFY_REJECTED: i32 = -26;
+
+/// An error returned to the caller of a method
+struct RpcError {
+  code: i32,
+  message: String,
+}
+
+impl RpcError {
+  fn new(code: i32, message: impl Into<String>) -> Self {
+    RpcError {
+      code,
+      message: message.into(),
+    }
+  }
+}
+
+/// Why a request could not be read
+enum RequestError {
+  /// The connection was closed or broke
+  Closed,
+  /// The request is malformed, it is answered
+  /// with this status before closing
+  Invalid(u16, &'static str),
+}
+
+impl From<io::Error> for RequestError {
+  fn from(_: io::Error) -> Self {
+    RequestError::Closed
+  }
+}
+
+/// The parts of an HTTP request we look at
+struct Request {
+  method: String,
+  authorization: Option<String>,
+  keep_alive: bool,
+  body: Vec<u8>,
+}
+
+// The "user:password" clients have to authenticate with. Without
+// a password, a random one is written to a cookie file in the
+// data directory for local clients to read
+pub fn credentials(
+  data_dir: &Path,
+  user: &str,
+  password: Option<String>,
+) -> Result<String> {
+  if let Some(password) = password {
+    return Ok(format!("{user}:{password}"));
+  }
+  let cookie = format!(
+    "{COOKIE_USER}:{}",
+    hex::encode(rand::random::<[u8; 32]>())
+  );
+  let path = data_dir.join(COOKIE_FILE);
+  fs::create_dir_all(data_dir)?;
+  fs::write(&path, &cookie)
+    .with_context(|| format!("failed to write {}", path.display()))?;
+  #[cfg(unix)]
+  {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o600))?;
+  }
+  println!("RPC cookie written to {}", path.display());
+  Ok(cookie)
+}
+
+// accept RPC clients until the listener fails
+pub async fn serve(listener: TcpListener, credentials: String) {
+  let credentials = Arc::new(credentials);
+  loop {
+    match listener.accept().await {
+      Ok((socket, addr)) => {
+        tokio::spawn(handle_connection(socket, addr, credentials.clone()));
+      }
+      Err(e) => {
+        println!("RPC server stopped: {e}");
+        return;
+      }
+    }
+  }
+}
+
+async fn handle_connection(
+  socket: TcpStream,
+  addr: SocketAddr,
+  credentials: Arc<String>,
+) {
+  let mut stream = BufReader::new(socket);
+  loop {
+    let request =
+      match time::timeout(REQUEST_TIMEOUT, read_request(&mut stream)).await {
+        Ok(Ok(request)) => request,
+        Ok(Err(RequestError::Invalid(status, reason))) => {
+          println!("invalid RPC request from {addr}: {reason}");
+          let _ =
+            write_response(&mut stream, status, reason.to_string(), false)
+              .await;
+          return;
+        }
+        Ok(Err(RequestError::Closed)) | Err(_) => return,
+      };
+    let (status, body) = if !authorized(&request, &credentials) {
+      println!("RPC request from {addr} with wrong credentials");
+      time::sleep(AUTH_FAILURE_DELAY).await;
+      (401, String::new())
+    } else if request.method != "POST" {
+      (405, "JSON-RPC requests have to be POSTed".to_string())
+    } else {
+      (200, handle_body(&request.body).await.to_string())
+    };
+    let keep_alive = request.keep_alive && status == 200;
+    if write_response(&mut stream, status, body, keep_alive)
+      .await
+      .is_err()
+      || !keep_alive
+    {
+      return;
+    }
+  }
+}
+
+// a line of the request head, without the line break
+async fn read_line(
+  stream: &mut BufReader<TcpStream>,
+) -> Result<String, RequestError> {
+  let mut line = vec![];
+  let read = (&mut *stream)
+    .take(MAX_LINE as u64)
+    .read_until(b'\n', &mut line)
+    .await?;
+  if read == 0 {
+    return Err(RequestError::Closed);
+  }
+  if line.last() != Some(&b'\n') {
+    return Err(RequestError::Invalid(431, "request line too long"));
+  }
+  let line = String::from_utf8(line)
+    .map_err(|_| RequestError::Invalid(400, "request is not UTF-8"))?;
+  Ok(line.trim_end_matches(['\r', '\n']).to_string())
+}
+
+async fn read_request(
+  stream: &mut BufReader<TcpStream>,
+) -> Result<Request, RequestError> {
+  let request_line = read_line(stream).await?;
+  let mut parts = request_line.split_whitespace();
+  let (Some(method), Some(_path), Some(version)) =
+    (parts.next(), parts.next(), parts.next())
+  else {
+    return Err(RequestError::Invalid(400, "malformed request line"));
+  };
+  let mut request = Request {
+    method: method.to_string(),
+    authorization: None,
+    // the default of HTTP/1.1
+    keep_alive: version == "HTTP/1.1",
+    body: vec![],
+  };
+  let mut content_length = 0;
+  for header_count in 0.. {
+    let line = read_line(stream).await?;
+    if line.is_empty() {
+      break;
+    }
+    if header_count == MAX_HEADERS {
+      return Err(RequestError::Invalid(431, "too many headers"));
+    }
+    let Some((name, value)) = line.split_once(':') else {
+      return Err(RequestError::Invalid(400, "malformed header"));
+    };
+    let value = value.trim();
+    match name.trim().to_ascii_lowercase().as_str() {
+      "authorization" => request.authorization = Some(value.to_string()),
+      "connection" => {
+        request.keep_alive = !value.eq_ignore_ascii_case("close")
+      }
+      "content-length" => {
+        content_length = value
+          .parse()
+          .map_err(|_| RequestError::Invalid(400, "invalid Content-Length"))?
+      }
+      "transfer-encoding" => {
+        return Err(RequestError::Invalid(
+          501,
+          "Transfer-Encoding is not supported",
+        ))
+      }
+      _ => {}
+    }
+  }
+  if content_length > MAX_BODY {
+    return Err(RequestError::Invalid(413, "request body too large"));
+  }
+  request.body = vec![0; content_length];
+  stream.read_exact(&mut request.body).await?;
+  Ok(request)
+}
+
+async fn write_response(
+  stream: &mut BufReader<TcpStream>,
+  status: u16,
+  body: String,
+  keep_alive: bool,
+) -> io::Result<()> {
+  let reason = match status {
+    200 => "OK",
+    400 => "Bad Request",
+    401 => "Unauthorized",
+    405 => "Method Not Allowed",
+    413 => "Payload Too Large",
+    431 => "Request Header Fields Too Large",
+    501 => "Not Implemented",
+    _ => "Error",
+  };
+  let content_type = if status == 200 {
+    "application/json"
+  } else {
+    "text/plain"
+  };
+  let mut head = format!(
+    "HTTP/1.1 {status} {reason}\r\n\
+    Content-Type: {content_type}\r\n\
+    Content-Length: {}\r\n",
+    body.len()
+  );
+  if status == 401 {
+    head.push_str("WWW-Authenticate: Basic realm=\"jsonrpc\"\r\n");
+  }
+  if !keep_alive {
+    head.push_str("Connection: close\r\n");
+  }
+  head.push_str("\r\n");
+  let stream = stream.get_mut();
+  stream.write_all(head.as_bytes()).await?;
+  stream.write_all(body.as_bytes()).await?;
+  stream.flush().await
+}
+
+// whether the request carries the credentials, compared
+// in constant time
+fn authorized(request: &Request, credentials: &str) -> bool {
+  let Some(encoded) = request
+    .authorization
+    .as_deref()
+    .and_then(|value| value.strip_prefix("Basic "))
+  else {
+    return false;
+  };
+  let Ok(given) = Base64::decode_vec(encoded.trim()) else {
+    return false;
+  };
+  given.len() == credentials.len()
+    && given
+      .iter()
+      .zip(credentials.as_bytes())
+      .fold(0, |diff, (a, b)| diff | (a ^ b))
+      == 0
+}
+
+// the response to a single call or a batch of calls
+async fn handle_body(body: &[u8]) -> Value {
+  let request: Value = match serde_json::from_slice(body) {
+    Ok(request) => request,
+    Err(e) => {
+      return error_response(
+        Value::Null,
+        RpcError::new(PARSE_ERROR, format!("parse error: {e}")),
+      )
+    }
+  };
+  match request {
+    Value::Array(calls) if !calls.is_empty() => {
+      let mut responses = vec![];
+      for call in calls {
+        responses.push(handle_call(call).await);
+      }
+      Value::Array(responses)
+    }
+    call => handle_call(call).await,
+  }
+}
+
+async fn handle_call(call: Value) -> Value {
+  let id = call.get("id").cloned().unwrap_or(Value::Null);
+  let Some(method) = call.get("method").and_then(Value::as_str) else {
+    return error_response(
+      id,
+      RpcError::new(INVALID_REQUEST, "missing method"),
+    );
+  };
+  let params = match call.get("params") {
+    None | Some(Value::Null) => vec![],
+    Some(Value::Array(params)) => params.clone(),
+    Some(_) => {
+      return error_response(
+        id,
+        RpcError::new(INVALID_PARAMS, "params have to be an array"),
+      )
+    }
+  };
+  match call_method(method, &params).await {
+    Ok(result) => json!({ "jsonrpc": "2.0", "result": result, "id": id }),
+    Err(e) => error_response(id, e),
+  }
+}
+
+fn error_response(id: Value, error: RpcError) -> Value {
+  json!({
+    "jsonrpc": "2.0",
+    "error": { "code": error.code, "message": error.message },
+    "id": id,
+  })
+}
+
+async fn call_method(
+  method: &str,
+  params: &[Value],
+) -> Result<Value, RpcError> {
+  match method {
+    "getblockcount" => {
+      let blockchain = crate::BLOCKCHAIN.read().await;
+      // the height of the tip, -1 without blocks
+      Ok(json!(blockchain.block_height() as i64 - 1))
+    }
+    "getblock" => get_block(params).await,
+    "getrawtransaction" => get_raw_transaction(params).await,
+    "getmempoolinfo" => {
+      let blockchain = crate::BLOCKCHAIN.read().await;
+      let mempool = blockchain.mempool();
+      Ok(json!({
+        "size": mempool.len(),
+        "bytes": mempool.size(),
+        "maxmempool": mempool.max_size(),
+        "mempoolminfee": mempool.min_fee_rate(Utc::now()),
+        "minrelaytxfee": btclib::MIN_RELAY_FEE_RATE,
+      }))
+    }
+    "getrawmempool" => {
+      let verbose = bool_param(params, 0)?.unwrap_or(false);
+      let blockchain = crate::BLOCKCHAIN.read().await;
+      let mempool = blockchain.mempool();
+      if !verbose {
+        let txids: Vec<String> =
+          mempool.txids().map(|txid| txid.to_string()).collect();
+        return Ok(json!(txids));
+      }
+      let entries: serde_json::Map<String, Value> = mempool
+        .txids()
+        .filter_map(|txid| mempool.get(txid).map(|entry| (txid, entry)))
+        .map(|(txid, entry)| {
+          let info = json!({
+            "size": entry.size,
+            "fee": entry.fee,
+            "feerate": entry.fee_rate(),
+            "time": entry.time.timestamp(),
+            "ancestors": mempool.ancestors(txid).len(),
+          });
+          (txid.to_string(), info)
+        })
+        .collect();
+      Ok(Value::Object(entries))
+    }
+    "gettxout" => get_tx_out(params).await,
+    "sendrawtransaction" => send_raw_transaction(params).await,
+    "getpeerinfo" => {
+      let nodes = crate::NODES
+        .iter()
+        .map(|x| x.key().clone())
+        .collect::<Vec<_>>();
+      let mut peers: Vec<Value> = nodes
+        .into_iter()
+        .map(|node| {
+          let info = crate::PEERS.with_book(|book| book.get(&node).cloned());
+          json!({
+            "addr": node,
+            "inbound": false,
+            "lastseen": info.as_ref().map(|info| info.last_seen),
+            "connections": info.as_ref().map(|info| info.successes),
+          })
+        })
+        .collect();
+      // inbound nodes are listed by the address they connected
+      // from, along with the one they accept connections on
+      peers.extend(crate::PEERS.inbound_peers().into_iter().map(
+        |(from, peer)| {
+          json!({
+            "addr": from.to_string(),
+            "inbound": true,
+            "listenaddr": peer.address,
+            "subver": peer.user_agent,
+            "conntime": peer.connected_at,
+          })
+        },
+      ));
+      Ok(json!(peers))
+    }
+    "getdifficulty" => {
+      let blockchain = crate::BLOCKCHAIN.read().await;
+      Ok(json!(pow::difficulty(
+        blockchain.target(),
+        blockchain.params().min_target,
+      )))
+    }
+    _ => Err(RpcError::new(
+      METHOD_NOT_FOUND,
+      format!("method {method} not found"),
+    )),
+  }
+}
+
+// getblock "hash"|height ( verbosity ), verbosity 0 is the
+// hex encoded block, 1 lists the txids and 2 the transactions
+async fn get_block(params: &[Value]) -> Result<Value, RpcError> {
+  let verbosity = match params.get(1) {
+    None | Some(Value::Null) => 1,
+    Some(Value::Bool(verbose)) => *verbose as u64,
+    Some(value) => value
+      .as_u64()
+      .filter(|verbosity| *verbosity <= 2)
+      .ok_or(RpcError::new(INVALID_PARAMS, "invalid verbosity"))?,
+  };
+  let blockchain = crate::BLOCKCHAIN.read().await;
+  let block = match params.first() {
+    Some(Value::Number(height)) => {
+      let height = height
+        .as_u64()
+        .ok_or(RpcError::new(INVALID_PARAMS, "invalid height"))?;
+      blockchain
+        .blocks()
+        .nth(height as usize)
+        .ok_or(RpcError::new(INVALID_PARAMETER, "block height out of range"))?
+    }
+    _ => {
+      let hash = hash_param(params, 0)?;
+      blockchain
+        .get_block(&hash)
+        .ok_or(RpcError::new(NOT_FOUND, "block not found"))?
+    }
+  };
+  if verbosity == 0 {
+    return Ok(json!(encode_hex(block)));
+  }
+  let mut result = block_info(&blockchain, block);
+  result["tx"] = if verbosity == 1 {
+    block
+      .transactions
+      .iter()
+      .map(|tx| json!(tx.hash().to_string()))
+      .collect()
+  } else {
+    block.transactions.iter().map(transaction_info).collect()
+  };
+  Ok(result)
+}
+
+// getrawtransaction "txid" ( verbose "blockhash" ). Looks in the
+// block given, or in the mempool and the transaction index
+async fn get_raw_transaction(params: &[Value]) -> Result<Value, RpcError> {
+  let txid = hash_param(params, 0)?;
+  let verbose = bool_param(params, 1)?.unwrap_or(false);
+  let block_hash = match params.get(2) {
+    None | Some(Value::Null) => None,
+    Some(_) => Some(hash_param(params, 2)?),
+  };
+  let blockchain = crate::BLOCKCHAIN.read().await;
+  let (transaction, block) = match block_hash {
+    Some(block_hash) => {
+      let block = blockchain
+        .get_block(&block_hash)
+        .ok_or(RpcError::new(NOT_FOUND, "block not found"))?;
+      let transaction = block
+        .transactions
+        .iter()
+        .find(|tx| tx.hash() == txid)
+        .ok_or(RpcError::new(
+          NOT_FOUND,
+          "no such transaction found in the provided block",
+        ))?;
+      (transaction, Some(block))
+    }
+    None => match blockchain.mempool().get(&txid) {
+      Some(entry) => (&entry.transaction, None),
+      None => {
+        let (transaction, location) = blockchain
+          .find_transaction(&txid)
+          .ok_or(RpcError::new(
+            NOT_FOUND,
+            "no such mempool or indexed transaction, use --txindex \
+            or provide the hash of its block",
+          ))?;
+        (transaction, blockchain.get_block(&location.block))
+      }
+    },
+  };
+  if !verbose {
+    return Ok(json!(encode_hex(transaction)));
+  }
+  let mut result = transaction_info(transaction);
+  if let Some(block) = block {
+    result["blockhash"] = json!(block.hash().to_string());
+    result["confirmations"] = json!(confirmations(&blockchain, block));
+  }
+  Ok(result)
+}
+
+// gettxout "txid" n ( include_mempool ), null if the output
+// is spent or unknown
+async fn get_tx_out(params: &[Value]) -> Result<Value, RpcError> {
+  let txid = hash_param(params, 0)?;
+  let vout = params
+    .get(1)
+    .and_then(Value::as_u64)
+    .and_then(|vout| u32::try_from(vout).ok())
+    .ok_or(RpcError::new(INVALID_PARAMS, "invalid output index"))?;
+  let include_mempool = bool_param(params, 2)?.unwrap_or(true);
+  let blockchain = crate::BLOCKCHAIN.read().await;
+  let key = OutPoint::new(txid, vout);
+  let mempool = blockchain.mempool();
+  if include_mempool && mempool.spender(&key).is_some() {
+    return Ok(Value::Null);
+  }
+  let (output, confirmed) = match blockchain.utxos().get(&key) {
+    Some((_, output)) => (output, true),
+    None => match mempool.output(&key).filter(|_| include_mempool) {
+      Some(output) => (output, false),
+      None => return Ok(Value::Null),
+    },
+  };
+  let tip = blockchain.blocks().last().map(|block| block.hash());
+  Ok(json!({
+    "bestblock": tip.map(|hash| hash.to_string()),
+    "confirmed": confirmed,
+    "value": output.value,
+    "pubkey": serde_json::to_value(&output.pubkey).unwrap_or_default(),
+    "unique_id": output.unique_id.to_string(),
+  }))
+}
+
+// sendrawtransaction "hex", adds the transaction
+// to the mempool and relays it
+async fn send_raw_transaction(params: &[Value]) -> Result<Value, RpcError> {
+  let encoded = params
+    .first()
+    .and_then(Value::as_str)
+    .ok_or(RpcError::new(INVALID_PARAMS, "missing transaction"))?;
+  let transaction: Transaction = hex::decode(encoded)
+    .ok()
+    .and_then(|bytes| ciborium::from_reader(bytes.as_slice()).ok())
+    .ok_or(RpcError::new(
+      DESERIALIZATION_ERROR,
+      "transaction decode failed",
+    ))?;
+  let txid = transaction.hash();
+  // an orphan is kept until its inputs show up
+  let added = orphans::process_transaction(transaction)
+    .await
+    .map_err(|e| RpcError::new(VERIFY_REJECTED, e.to_string()))?;
+  if !added.is_empty() {
+    tokio::spawn(relay::relay(added));
+  }
+  Ok(json!(txid.to_string()))
+}
+
+fn hash_param(params: &[Value], index: usize) -> Result<Hash, RpcError> {
+  params
+    .get(index)
+    .and_then(Value::as_str)
+    .and_then(|hash| hash.parse().ok())
+    .ok_or(RpcError::new(
+      INVALID_PARAMS,
+      format!("parameter {} has to be a hex hash", index + 1),
+    ))
+}
+
+// an optional boolean, 0 and 1 are accepted too
+fn bool_param(
+  params: &[Value],
+  index: usize,
+) -> Result<Option<bool>, RpcError> {
+  match params.get(index) {
+    None | Some(Value::Null) => Ok(None),
+    Some(Value::Bool(value)) => Ok(Some(*value)),
+    Some(Value::Number(value)) if value.as_u64() == Some(0) => Ok(Some(false)),
+    Some(Value::Number(value)) if value.as_u64() == Some(1) => Ok(Some(true)),
+    Some(_) => Err(RpcError::new(
+      INVALID_PARAMS,
+      format!("parameter {} has to be a boolean", index + 1),
+    )),
+  }
+}
+
+// CBOR as sent over the wire, hex encoded
+fn encode_hex<T: serde::Serialize>(data: &T) -> String {
+  let mut bytes = vec![];
+  ciborium::into_writer(data, &mut bytes)
+    .expect("BUG: data can't be serialized");
+  hex::encode(bytes)
+}
+
+// blocks on top of a block of the active
+// chain including itself, -1 off it
+fn confirmations(blockchain: &Blockchain, block: &Block) -> i64 {
+  let hash = block.hash();
+  match blockchain.main_chain_position(&hash) {
+    Some(height) => blockchain.block_height() as i64 - height as i64,
+    None => -1,
+  }
+}
+
+fn block_info(blockchain: &Blockchain, block: &Block) -> Value {
+  let hash = block.hash();
+  let height = blockchain.main_chain_position(&hash);
+  let next = height
+    .and_then(|height| blockchain.blocks().nth(height + 1))
+    .map(|block| block.hash().to_string());
+  let prev = block.header.prev_block_hash;
+  json!({
+    "hash": hash.to_string(),
+    "confirmations": confirmations(blockchain, block),
+    "height": height,
+    "size": block.size(),
+    "time": block.header.timestamp.timestamp(),
+    "nonce": block.header.nonce,
+    "bits": format!("{:08x}", block.header.bits),
+    "target": format!("{:x}", block.header.target()),
+    "difficulty": pow::difficulty(
+      block.header.target(),
+      blockchain.params().min_target,
+    ),
+    "ntx": block.transactions.len(),
+    "previousblockhash": (prev != Hash::zero()).then(|| prev.to_string()),
+    "nextblockhash": next,
+  })
+}
+
+fn transaction_info(transaction: &Transaction) -> Value {
+  let inputs: Vec<Value> = transaction
+    .inputs
+    .iter()
+    .map(|input| {
+      json!({
+        "txid": input.prev_output.txid.to_string(),
+        "vout": input.prev_output.vout,
+        "sequence": input.sequence,
+      })
+    })
+    .collect();
+  let outputs: Vec<Value> = transaction
+    .outputs
+    .iter()
+    .enumerate()
+    .map(|(n, output)| {
+      json!({
+        "n": n,
+        "value": output.value,
+        "pubkey": serde_json::to_value(&output.pubkey).unwrap_or_default(),
+        "unique_id": output.unique_id.to_string(),
+        "condition":
+          serde_json::to_value(&output.condition).unwrap_or_default(),
+        "timelock": serde_json::to_value(output.timelock).unwrap_or_default(),
+      })
+    })
+    .collect();
+  json!({
+    "txid": transaction.hash().to_string(),
+    "size": transaction.size(),
+    "vin": inputs,
+    "vout": outputs,
+  })
+}
@@ -0,0 +1,271 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+use btclib::sha256::Hash;
+use btclib::types::{Block, Blockchain};
+use btclib::util::Saveable;
+use serde::{Deserialize, Serialize};
+
+const BLOCKS_FILE: &str = "blocks.dat";
+const INDEX_FILE: &str = "index.dat";
+// the index of earlier versions, rewritten as a whole on every
+// save. The block file is scanned again instead
+const OLD_INDEX_FILE: &str = "index.cbor";
+
+/// Location of a stored block
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub struct IndexEntry {
+  pub hash: Hash,
+  pub height: u64,
+  /// offset of the record in the block file
+  pub offset: u64,
+  /// length of the CBOR-encoded block, without the length prefix
+  pub len: u64,
+}
+
+/// Append-only block storage.
+/// Blocks are appended to the block file as length-prefixed
+/// CBOR records, parents always before their children. The
+/// height/hash index next to it is appended to the same way
+/// once the blocks are on disk. Records that made it to the
+/// block file but not to the index are picked up again when
+/// the store is opened, and partially written records at the
+/// end of either file are cut off.
+pub struct BlockStore {
+  dir: PathBuf,
+  index: Vec<IndexEntry>,
+  by_hash: HashMap<Hash, usize>,
+}
+
+impl BlockStore {
+  /// Open the block store in `dir`, creating it if needed
+  pub fn open<P: AsRef<Path>>(dir: P) -> Result<Self> {
+    let dir = dir.as_ref().to_path_buf();
+    fs::create_dir_all(&dir)?;
+    let old_index_path = dir.join(OLD_INDEX_FILE);
+    if old_index_path.exists() {
+      fs::remove_file(old_index_path)?;
+      fs::remove_file(dir.join(INDEX_FILE)).ok();
+    }
+    let index = read_index(&dir.join(INDEX_FILE))?;
+    let by_hash = index
+      .iter()
+      .enumerate()
+      .map(|(idx, entry)| (entry.hash, idx))
+      .collect();
+    let mut store = BlockStore {
+      dir,
+      index,
+      by_hash,
+    };
+    store.recover()?;
+    Ok(store)
+  }
+  pub fn len(&self) -> usize {
+    self.index.len()
+  }
+  pub fn is_empty(&self) -> bool {
+    self.index.is_empty()
+  }
+  pub fn contains(&self, hash: &Hash) -> bool {
+    self.by_hash.contains_key(hash)
+  }
+  fn blocks_path(&self) -> PathBuf {
+    self.dir.join(BLOCKS_FILE)
+  }
+  // index records written after the last index update, and
+  // cut off a record that was only partially written
+  fn recover(&mut self) -> Result<()> {
+    let mut file = OpenOptions::new()
+      .read(true)
+      .write(true)
+      .create(true)
+      .truncate(false)
+      .open(self.blocks_path())?;
+    let file_len = file.metadata()?.len();
+    let mut pos = self
+      .index
+      .last()
+      .map(|entry| entry.offset + 8 + entry.len)
+      .unwrap_or(0);
+    let indexed = self.index.len();
+    file.seek(SeekFrom::Start(pos))?;
+    let mut reader = BufReader::new(&mut file);
+    while pos + 8 <= file_len {
+      let mut len_bytes = [0u8; 8];
+      reader.read_exact(&mut len_bytes)?;
+      let len = u64::from_be_bytes(len_bytes);
+      if pos + 8 + len > file_len {
+        break;
+      }
+      let mut data = vec![0u8; len as usize];
+      reader.read_exact(&mut data)?;
+      let Ok(block) = Block::load(data.as_slice()) else {
+        break;
+      };
+      if self.push_entry(&block, pos, len).is_err() {
+        break;
+      }
+      pos += 8 + len;
+    }
+    drop(reader);
+    if pos < file_len {
+      println!(
+        "block store: dropping {} bytes of incomplete data",
+        file_len - pos
+      );
+      file.set_len(pos)?;
+      file.sync_all()?;
+    }
+    if self.index.len() > indexed {
+      println!(
+        "block store: recovered {} unindexed blocks",
+        self.index.len() - indexed
+      );
+      self.append_index(indexed)?;
+    }
+    Ok(())
+  }
+  fn push_entry(&mut self, block: &Block, offset: u64, len: u64) -> Result<()> {
+    let hash = block.hash();
+    let prev_hash = block.header.prev_block_hash;
+    let height = if prev_hash == Hash::zero() {
+      0
+    } else {
+      let parent = self
+        .by_hash
+        .get(&prev_hash)
+        .ok_or_else(|| anyhow!("parent of block {hash} is not stored"))?;
+      self.index[*parent].height + 1
+    };
+    self.by_hash.insert(hash, self.index.len());
+    self.index.push(IndexEntry {
+      hash,
+      height,
+      offset,
+      len,
+    });
+    Ok(())
+  }
+  // append the index entries from `from` on to the index file
+  fn append_index(&self, from: usize) -> Result<()> {
+    let mut file = OpenOptions::new()
+      .append(true)
+      .create(true)
+      .open(self.dir.join(INDEX_FILE))?;
+    let mut bytes = vec![];
+    for entry in &self.index[from..] {
+      let mut record = vec![];
+      ciborium::into_writer(entry, &mut record)
+        .context("failed to write block index")?;
+      bytes.extend_from_slice(&(record.len() as u64).to_be_bytes());
+      bytes.extend_from_slice(&record);
+    }
+    file.write_all(&bytes)?;
+    file.sync_data()?;
+    Ok(())
+  }
+  /// Append blocks to the store. Parents have to be stored
+  /// before (or together with) their children.
+  pub fn append(&mut self, blocks: &[Block]) -> Result<()> {
+    let mut file = OpenOptions::new()
+      .append(true)
+      .create(true)
+      .open(self.blocks_path())?;
+    let mut offset = file.metadata()?.len();
+    let indexed = self.index.len();
+    for block in blocks {
+      if self.contains(&block.hash()) {
+        continue;
+      }
+      let mut bytes = vec![];
+      block.save(&mut bytes)?;
+      let len = bytes.len() as u64;
+      file.write_all(&len.to_be_bytes())?;
+      file.write_all(&bytes)?;
+      self.push_entry(block, offset, len)?;
+      offset += 8 + len;
+    }
+    file.sync_data()?;
+    self.append_index(indexed)
+  }
+  /// Read all stored blocks one at a time, in the order
+  /// they were stored
+  pub fn load_blocks(
+    &self,
+  ) -> Result<impl Iterator<Item = Result<Block>> + '_> {
+    let mut reader = BufReader::new(File::open(self.blocks_path())?);
+    Ok(self.index.iter().map(move |entry| {
+      reader.seek(SeekFrom::Start(entry.offset + 8))?;
+      let mut data = vec![0u8; entry.len as usize];
+      reader.read_exact(&mut data)?;
+      Ok(Block::load(data.as_slice())?)
+    }))
+  }
+  /// Blocks of the blockchain that are not stored yet,
+  /// with parents ordered before their children
+  pub fn missing_blocks(&self, blockchain: &Blockchain) -> Vec<Block> {
+    // everything below a stored block is stored too
+    let mut missing: Vec<Block> = blockchain
+      .blocks()
+      .rev()
+      .take_while(|block| !self.contains(&block.hash()))
+      .cloned()
+      .collect();
+    missing.reverse();
+    let mut known: HashSet<Hash> =
+      missing.iter().map(|block| block.hash()).collect();
+    let mut side_blocks: Vec<(Hash, &Block)> = blockchain
+      .side_blocks()
+      .map(|block| (block.hash(), block))
+      .filter(|(hash, _)| !self.contains(hash))
+      .collect();
+    loop {
+      let (ready, waiting): (Vec<_>, Vec<_>) =
+        side_blocks.into_iter().partition(|(_, block)| {
+          let prev_hash = block.header.prev_block_hash;
+          self.contains(&prev_hash) || known.contains(&prev_hash)
+        });
+      if ready.is_empty() {
+        break;
+      }
+      for (hash, block) in ready {
+        known.insert(hash);
+        missing.push(block.clone());
+      }
+      side_blocks = waiting;
+    }
+    missing
+  }
+}
+
+// Read the index entries from the index file, cutting off
+// a record that was only partially written
+fn read_index(path: &Path) -> Result<Vec<IndexEntry>> {
+  if !path.exists() {
+    return Ok(vec![]);
+  }
+  let bytes = fs::read(path)?;
+  let mut index = vec![];
+  let mut pos = 0;
+  while let Some(len_bytes) = bytes.get(pos..pos + 8) {
+    let len = u64::from_be_bytes(len_bytes.try_into()?) as usize;
+    let Some(record) = bytes.get(pos + 8..pos + 8 + len) else {
+      break;
+    };
+    index.push(
+      ciborium::from_reader(record).context("failed to read block index")?,
+    );
+    pos += 8 + len;
+  }
+  if pos < bytes.len() {
+    println!("block store: dropping an incomplete index record");
+    let file = OpenOptions::new().write(true).open(path)?;
+    file.set_len(pos as u64)?;
+    file.sync_all()?;
+  }
+  Ok(index)
+}
@@ -1,51 +1,139 @@
-use anyhow::{Context, Result};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::ErrorKind;
+use anyhow::{bail, ensure, Context, Result};
 use tokio::net::TcpStream;
+use tokio::task::JoinSet;
 use tokio::time;
-use btclib::network::Message;
-use btclib::types::Blockchain;
+use btclib::error::{BtcError, NetworkError};
+use btclib::network::{Message, MAX_HEADERS};
+use btclib::params::ChainParams;
+use btclib::sha256::Hash;
+use btclib::types::{Block, BlockHeader, Blockchain};
 use btclib::util::Saveable;
+use crate::{peers, relay};
+use crate::store::BlockStore;
 
-pub async fn load_blockchain(
-  blockchain_file: &str,
-) -> Result<()> {
-  println!("blockchain file exists, loading...");
-  let new_blockchain = Blockchain::load_from_file(blockchain_file)?;
-  println!("blockchain loaded");
+pub async fn load_blockchain(store: &BlockStore) -> Result<()> {
+  println!("loading {} blocks from the block store...", store.len());
   let mut blockchain = crate::BLOCKCHAIN.write().await;
-  *blockchain = new_blockchain;
-  println!("rebuilding utxos...");
-  blockchain.rebuild_utxos();
-  println!("utxos rebuilt");
-  println!("checking if target needs to be adjusted...");
+  // the store only holds blocks that were checked before
+  // they were saved, so only their headers are checked again
+  for block in store.load_blocks()? {
+    match blockchain.add_validated_block(block?) {
+      Ok(()) => {}
+      Err(BtcError::InvalidGenesisBlock) => bail!(
+        "the block store holds a chain that doesn't start with \
+        the genesis block of this network"
+      ),
+      Err(e) => println!("stored block rejected: {e}"),
+    }
+  }
+  println!(
+    "blockchain loaded, height: {}",
+    blockchain.block_height()
+  );
   println!("current target: {}", blockchain.target());
-  blockchain.try_adjust_target();
-  println!("new target: {}", blockchain.target());
   println!("initialization complete");
   Ok(())
 }
 
+// Import a blockchain saved as a single CBOR file by older
+// versions of the node. Files from before chain parameters are
+// migrated to the current format
+pub async fn import_blockchain(
+  blockchain_file: &str,
+  params: ChainParams,
+) -> Result<()> {
+  println!("importing blockchain file...");
+  let loaded = match Blockchain::load_from_file(blockchain_file) {
+    Ok(loaded) => loaded,
+    Err(e) if e.kind() == ErrorKind::InvalidData => {
+      if !Blockchain::is_legacy(File::open(blockchain_file)?) {
+        return Err(e).context(format!("{blockchain_file} is corrupt"));
+      }
+      return migrate_blockchain(blockchain_file, params).await;
+    }
+    Err(e) => {
+      return Err(e).context(format!("failed to read {blockchain_file}"))
+    }
+  };
+  // replay the blocks, which checks them and builds
+  // the UTXO set and its undo data along the way
+  let mut new_blockchain = Blockchain::new(params);
+  for block in loaded.blocks() {
+    match new_blockchain.add_block(block.clone()) {
+      Ok(()) => {}
+      Err(BtcError::InvalidGenesisBlock) => bail!(
+        "{blockchain_file} holds a chain that doesn't start with \
+        the genesis block of this network"
+      ),
+      Err(e) => {
+        println!("imported block rejected: {e}");
+        break;
+      }
+    }
+  }
+  println!(
+    "blockchain imported, height: {}",
+    new_blockchain.block_height()
+  );
+  println!("current target: {}", new_blockchain.target());
+  *crate::BLOCKCHAIN.write().await = new_blockchain;
+  println!("initialization complete");
+  Ok(())
+}
+
+// Migrate a blockchain file in the legacy format, mining its
+// blocks again on this network's genesis block
+async fn migrate_blockchain(
+  blockchain_file: &str,
+  params: ChainParams,
+) -> Result<()> {
+  println!("{blockchain_file} is in the legacy format, migrating...");
+  let migrated =
+    Blockchain::migrate_legacy(File::open(blockchain_file)?, params)
+      .with_context(|| format!("failed to migrate {blockchain_file}"))?;
+  println!(
+    "blockchain migrated, height: {}",
+    migrated.block_height()
+  );
+  println!(
+    "note: spends in migrated blocks carry their legacy signatures, \
+    nodes that did not migrate the same file reject them"
+  );
+  println!("current target: {}", migrated.target());
+  *crate::BLOCKCHAIN.write().await = migrated;
+  println!("initialization complete");
+  Ok(())
+}
+
+// Connect to the nodes given on the command line, ask them
+// for the nodes they know and fill up the outbound connections
+// from the address book
 pub async fn populate_connections(
   nodes: &[String],
 ) -> Result<()> {
   println!("trying to connect to other nodes...");
   for node in nodes {
       println!("connecting to {}", node);
-      let mut stream = TcpStream::connect(&node).await?;
+      crate::PEERS.add_address(node);
+      if let Err(e) = peers::connect_outbound(node).await {
+          println!("failed to connect to {node}: {e:#}");
+          continue;
+      }
+      let mut stream =
+          crate::NODES.get_mut(node).context("no node")?;
       let message = Message::DiscoverNodes;
-      message.send_async(&mut stream).await?;
+      message.send_async(&mut *stream).await?;
       println!("sent DiscoverNodes to {}", node);
       let message =
-          Message::receive_async(&mut stream).await?;
+          relay::receive_reply(&mut stream, Some(node)).await?;
       match message {
           Message::NodeList(child_nodes) => {
               println!("received NodeList from {}", node);
-              for child_node in child_nodes {
-                  println!("adding node {}", child_node);
-                  let new_stream =
-                      TcpStream::connect(&child_node)
-                          .await?;
-                  crate::NODES.insert(child_node, new_stream);
-              }
+              let new_nodes = crate::PEERS.add_heard(child_nodes);
+              println!("{} new addresses", new_nodes.len());
           }
           _ => {
               println!(
@@ -54,8 +142,8 @@ pub async fn populate_connections(
               );
           }
       }
-      crate::NODES.insert(node.clone(), stream);
   }
+  peers::fill_outbound().await;
   Ok(())
 }
 
@@ -64,6 +152,8 @@ pub async fn find_longest_chain_node(
   println!("finding nodes with the highest blockchain length...");
   let mut longest_name = String::new();
   let mut longest_count = 0;
+  let local_height =
+    crate::BLOCKCHAIN.read().await.block_height() as u32;
   let all_nodes = crate::NODES
     .iter()
     .map(|x| x.key().clone())
@@ -73,11 +163,11 @@ pub async fn find_longest_chain_node(
     let mut stream = crate::NODES
       .get_mut(&node)
       .context("no node")?;
-    let message = Message::AskDifference(0);
+    let message = Message::AskDifference(local_height);
     message.send_async(&mut *stream).await.unwrap();
     println!("sent AskDifference to {}", node);
     let message =
-      Message::receive_async(&mut *stream).await?;
+      relay::receive_reply(&mut stream, Some(&node)).await?;
     match message {
       Message::Difference(count) => {
         println!(
@@ -86,7 +176,7 @@ pub async fn find_longest_chain_node(
         if count > longest_count {
           println!(
             "new longest blockchain: \
-            {} blocks from {node}",
+            {} blocks ahead at {node}",
             count
           );
           longest_count = count;
@@ -104,31 +194,211 @@ pub async fn find_longest_chain_node(
   Ok((longest_name, longest_count as u32))
 }
 
-pub async fn download_blockchain(
+// number of blocks fetched from peers before
+// they are added to the blockchain
+const BLOCK_DOWNLOAD_WINDOW: usize = 64;
+
+// Sync with a node: download and check its chain of headers
+// from where our chains diverge, then fetch the blocks
+// from all known nodes in parallel
+pub async fn download_blockchain(node: &str) -> Result<()> {
+  let (start_height, headers) = download_headers(node).await?;
+  println!(
+    "received {} headers from {node}, downloading blocks",
+    headers.len()
+  );
+  for (window_idx, window) in
+    headers.chunks(BLOCK_DOWNLOAD_WINDOW).enumerate()
+  {
+    let first_height =
+      start_height + window_idx * BLOCK_DOWNLOAD_WINDOW;
+    let blocks = fetch_blocks(node, first_height, window).await?;
+    let mut blockchain = crate::BLOCKCHAIN.write().await;
+    // orphans waiting for these blocks get connected too
+    let mut orphans = crate::ORPHANS.lock().unwrap();
+    for (peer, block) in blocks {
+      if let Err(e) = orphans.add_block(&mut blockchain, block) {
+        // a block ahead of our clock may just be early,
+        // anything else is on whoever sent it
+        if !matches!(e, BtcError::TimestampTooFarInFuture) {
+          println!("{peer} sent an invalid block: {e}, dropping it");
+          peers::misbehaved(&peer, &NetworkError::InvalidBlock(e));
+        }
+        // the rest of the chain builds on that block
+        bail!("the chain of {node} contains a rejected block");
+      }
+    }
+  }
+  Ok(())
+}
+
+// Fetch headers from a node until it has no more to offer.
+// Returns the height of the first header and the headers
+async fn download_headers(
+  node: &str,
+) -> Result<(usize, Vec<BlockHeader>)> {
+  // take the stream out of the pool while we use it
+  let (_, mut stream) = crate::NODES.remove(node).context("no node")?;
+  let result = receive_headers(&mut stream, node).await;
+  if result.is_ok() {
+    crate::NODES.insert(node.to_string(), stream);
+  } else {
+    crate::PEERS.disconnected(node);
+  }
+  result
+}
+
+// the GetHeaders requests of download_headers
+async fn receive_headers(
+  stream: &mut TcpStream,
   node: &str,
-  count: u32,
+) -> Result<(usize, Vec<BlockHeader>)> {
+  let mut locator =
+    crate::BLOCKCHAIN.read().await.block_locator();
+  let mut start_height = None;
+  let mut headers: Vec<BlockHeader> = vec![];
+  loop {
+    Message::GetHeaders(locator).send_async(stream).await?;
+    let batch = match relay::receive_reply(stream, Some(node)).await? {
+      Message::Headers(batch) => batch,
+      e => bail!("unexpected message from {node}: {e:?}"),
+    };
+    let Some(first) = batch.first() else {
+      break;
+    };
+    if start_height.is_none() {
+      // the first header has to build on a block we have
+      let prev_hash = first.prev_block_hash;
+      start_height = Some(if prev_hash == Hash::zero() {
+        0
+      } else {
+        crate::BLOCKCHAIN
+          .read()
+          .await
+          .find_fork(&[prev_hash])
+          .context("headers do not connect to our chain")?
+          as usize
+          + 1
+      });
+    }
+    verify_headers(headers.last(), &batch)?;
+    let full = batch.len() == MAX_HEADERS;
+    headers.extend(batch);
+    if !full {
+      break;
+    }
+    locator = vec![headers.last().unwrap().hash()];
+  }
+  Ok((start_height.unwrap_or(0), headers))
+}
+
+// Check that headers form a chain and carry valid proof of
+// work. Their targets and timestamps depend on the blocks
+// before them, add_block checks those once blocks arrive
+fn verify_headers<'a>(
+  mut prev: Option<&'a BlockHeader>,
+  headers: &'a [BlockHeader],
 ) -> Result<()> {
-  let mut stream = crate::NODES.get_mut(node).unwrap();
-  for i in 0..count as usize {
-    let message = Message::FetchBlock(i);
-    message.send_async(&mut *stream).await?;
-    let message =
-      Message::receive_async(&mut *stream).await?;
-    match message {
-      Message::NewBlock(block) => {
-        let mut blockchain =
-          crate::BLOCKCHAIN.write().await;
-        blockchain.add_block(block)?;
+  for header in headers {
+    if let Some(prev) = prev {
+      ensure!(
+        header.prev_block_hash == prev.hash(),
+        "headers do not form a chain"
+      );
+    }
+    ensure!(
+      header.hash().matches_target(header.target()),
+      "header does not match its target"
+    );
+    prev = Some(header);
+  }
+  Ok(())
+}
+
+// Fetch the blocks for a window of headers, spreading the
+// requests over all known nodes. Blocks that a node could not
+// deliver are fetched from the node we synced headers from.
+// Each block comes with the node that sent it
+async fn fetch_blocks(
+  sync_node: &str,
+  first_height: usize,
+  headers: &[BlockHeader],
+) -> Result<Vec<(String, Block)>> {
+  let peers = crate::NODES
+    .iter()
+    .map(|x| x.key().clone())
+    .collect::<Vec<_>>();
+  ensure!(!peers.is_empty(), "no nodes to fetch blocks from");
+  let mut assignments = vec![vec![]; peers.len()];
+  for (idx, header) in headers.iter().enumerate() {
+    assignments[idx % peers.len()]
+      .push((first_height + idx, header.hash()));
+  }
+  let mut tasks = JoinSet::new();
+  for (peer, jobs) in peers.into_iter().zip(assignments) {
+    if jobs.is_empty() {
+      continue;
+    }
+    // take the stream out of the pool while we use it
+    let Some((peer, mut stream)) = crate::NODES.remove(&peer) else {
+      continue;
+    };
+    tasks.spawn(async move {
+      let mut blocks = vec![];
+      for (height, hash) in jobs {
+        match fetch_block(&mut stream, &peer, height).await {
+          Ok(block) if block.hash() == hash => blocks.push(block),
+          Ok(_) => println!("{peer} sent an unexpected block {height}"),
+          Err(e) => {
+            println!("failed to fetch block {height} from {peer}: {e}");
+            break;
+          }
+        }
       }
-      _ => {
-        println!(
-          "unexpected message from {}",
-          node
+      (peer, stream, blocks)
+    });
+  }
+  let mut downloaded = HashMap::new();
+  while let Some(result) = tasks.join_next().await {
+    let (peer, stream, blocks) = result?;
+    for block in blocks {
+      downloaded.insert(block.hash(), (peer.clone(), block));
+    }
+    crate::NODES.insert(peer, stream);
+  }
+  let mut blocks = vec![];
+  for (idx, header) in headers.iter().enumerate() {
+    let hash = header.hash();
+    let block = match downloaded.remove(&hash) {
+      Some(downloaded) => downloaded,
+      None => {
+        let mut stream =
+          crate::NODES.get_mut(sync_node).context("no node")?;
+        let block =
+          fetch_block(&mut stream, sync_node, first_height + idx).await?;
+        ensure!(
+          block.hash() == hash,
+          "{sync_node} sent a block that does not match its header"
         );
+        (sync_node.to_string(), block)
       }
-    } 
+    };
+    blocks.push(block);
+  }
+  Ok(blocks)
+}
+
+async fn fetch_block(
+  stream: &mut TcpStream,
+  node: &str,
+  height: usize,
+) -> Result<Block> {
+  Message::FetchBlock(height).send_async(stream).await?;
+  match relay::receive_reply(stream, Some(node)).await? {
+    Message::NewBlock(block) => Ok(block),
+    Message::NotFound(_) => bail!("{node} has no block at height {height}"),
+    e => bail!("unexpected message: {e:?}"),
   }
-  Ok(())
 }
 
 pub async fn cleanup() {
@@ -140,15 +410,24 @@ pub async fn cleanup() {
     );
     let mut blockchain = crate::BLOCKCHAIN.write().await;
     blockchain.cleanup_mempool();
+    crate::ORPHANS.lock().unwrap().expire();
   }
 }
 
-pub async fn save(name: String) {
+pub async fn save(mut store: BlockStore) {
   let mut interval = time::interval(time::Duration::from_secs(15));
   loop {
     interval.tick().await;
-    println!("saving blockchain to drive...");
-    let blockchain = crate::BLOCKCHAIN.read().await;
-    blockchain.save_to_file(name.clone()).unwrap();
+    let blocks = {
+      let blockchain = crate::BLOCKCHAIN.read().await;
+      store.missing_blocks(&blockchain)
+    };
+    if blocks.is_empty() {
+      continue;
+    }
+    println!("saving {} new blocks to drive...", blocks.len());
+    if let Err(e) = store.append(&blocks) {
+      println!("failed to save blocks: {e}");
+    }
   }
 }
\ No newline at end of file
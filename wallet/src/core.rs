@@ -1,18 +1,29 @@
 use anyhow::{Result};
+use chrono::{DateTime, Utc};
 use crossbeam_skiplist::SkipMap;
 use kanal::Sender;
 use serde::{Deserialize, Serialize};
 use tokio::net::TcpStream;
 use tokio::sync::Mutex;
 use tracing::{debug, error, info};
+use std::collections::{HashMap, HashSet};
 use std::fs;
 use std::path::PathBuf;
 use std::sync::Arc;
-use btclib::crypto::{PrivateKey, PublicKey};
-use btclib::network::Message;
-use btclib::types::{Transaction, TransactionOutput};
+use btclib::crypto::{PrivateKey, PublicKey, Signature};
+use btclib::network::{handshake, Message, UTXOUpdate, Version};
+use btclib::params::ChainParams;
+use btclib::sha256::Hash;
+use btclib::types::{
+  OutPoint, SigHashType, SpendingCondition, Transaction,
+  TransactionInput, TransactionOutput,
+};
 use btclib::util::Saveable;
 
+/// Announced to the node in the handshake
+const USER_AGENT: &str =
+  concat!("/wallet:", env!("CARGO_PKG_VERSION"), "/");
+
 /// Represent a key pair with paths to public and private keys.
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Key {
@@ -65,14 +76,64 @@ pub struct Config {
   pub my_keys: Vec<Key>,
   pub contacts: Vec<Recipient>,
   pub default_node: String,
+  /// Network to use: mainnet, testnet, regtest or the
+  /// path of a TOML file with custom chain parameters
+  #[serde(default = "default_network")]
+  pub network: String,
   pub fee_config: FeeConfig,
 }
+fn default_network() -> String {
+  "mainnet".to_string()
+}
+/// Confirmed UTXOs of a key as of the last sync, so
+/// the next sync only has to fetch the changes.
+#[derive(Clone, Default)]
+struct KeySync {
+  /// Height and hash of the node's tip at the last sync
+  tip: Option<(u64, Hash)>,
+  /// Median time past of that tip
+  median_time_past: Option<DateTime<Utc>>,
+  /// Confirmed UTXOs
+  confirmed: HashMap<OutPoint, TransactionOutput>,
+}
+impl KeySync {
+  /// Apply an update from the node, returning all UTXOs of
+  /// the key and whether they are marked as spent.
+  fn apply(
+    &mut self,
+    update: UTXOUpdate,
+  ) -> KeyUtxos {
+    if update.full {
+      self.confirmed.clear();
+    }
+    for outpoint in &update.spent {
+      self.confirmed.remove(outpoint);
+    }
+    self.confirmed.extend(update.created);
+    self.tip = update.tip;
+    self.median_time_past = update.median_time_past;
+    let pending: HashSet<OutPoint> =
+      update.unconfirmed_spent.into_iter().collect();
+    self
+      .confirmed
+      .iter()
+      .map(|(outpoint, output)| {
+        (*outpoint, pending.contains(outpoint), output.clone())
+      })
+      .chain(update.unconfirmed.into_iter().map(|(outpoint, output)| {
+        (outpoint, pending.contains(&outpoint), output)
+      }))
+      .collect()
+  }
+}
+/// UTXOs of a key and whether they are marked as spent.
+type KeyUtxos = Vec<(OutPoint, bool, TransactionOutput)>;
 /// Store and manage Unspent Transaction Outputs (UTXOs).
 #[derive(Clone)]
 struct UtxoStore {
   my_keys: Vec<LoadedKey>,
-  utxos:
-    Arc<SkipMap<PublicKey, Vec<(bool, TransactionOutput)>>>,
+  utxos: Arc<SkipMap<PublicKey, KeyUtxos>>,
+  synced: Arc<SkipMap<PublicKey, KeySync>>,
 }
 impl UtxoStore {
   /// Create a new UtxoStore.
@@ -80,6 +141,7 @@ impl UtxoStore {
     UtxoStore {
         my_keys: Vec::new(),
         utxos: Arc::new(SkipMap::new()),
+        synced: Arc::new(SkipMap::new()),
     }
   }
   /// Add a new key to the UtxoStore.
@@ -91,16 +153,23 @@ impl UtxoStore {
 #[derive(Clone)]
 pub struct Core {
   pub config: Config,
+  pub params: ChainParams,
   utxos: UtxoStore,
   pub tx_sender: Sender<Transaction>,
   pub stream: Arc<Mutex<TcpStream>>
 }
 impl Core {
   /// Create a new Core instance.
-  fn new(config: Config, utxos: UtxoStore, stream: TcpStream) -> Self {
+  fn new(
+    config: Config,
+    params: ChainParams,
+    utxos: UtxoStore,
+    stream: TcpStream,
+  ) -> Self {
     let (tx_sender, _) = kanal::bounded(10);
     Core {
       config,
+      params,
       utxos,
       tx_sender,
       stream: Arc::new(Mutex::new(stream)),
@@ -110,44 +179,57 @@ impl Core {
   pub async fn load(config_path: PathBuf) -> Result<Self> {
     info!("Loading core from config: {:?}", config_path);
     let config: Config = toml::from_str(&fs::read_to_string(&config_path)?)?;
+    let params = ChainParams::load(&config.network)?;
+    info!("Using network: {}", params.name);
+    btclib::network::set_max_block_size(&params);
     let mut utxos = UtxoStore::new();
-    let stream = TcpStream::connect(&config.default_node).await?;
+    let mut stream = TcpStream::connect(&config.default_node).await?;
+    // a wallet offers no services and keeps no chain
+    let version = Version::new(&params, 0, 0, USER_AGENT);
+    let node = handshake(&mut stream, &version).await?;
+    info!(
+      "Connected to {} at height {}",
+      node.user_agent, node.best_height
+    );
     // Load keys from config
     for key in &config.my_keys {
       let public = PublicKey::load_from_file(&key.public)?;
       let private = PrivateKey::load_from_file(&key.private)?;
       utxos.add_key(LoadedKey { public, private });
     }
-    Ok(Core::new(config, utxos, stream))
+    Ok(Core::new(config, params, utxos, stream))
   }
-  /// Fetch UTXOs from the node for all loaded keys.
+  /// Fetch UTXOs from the node for all loaded keys. After
+  /// the first sync only the changes since then are fetched.
   pub async fn fetch_utxos(&self) -> Result<()> {
     debug!(
       "Fetching UTXOs from node: {}",
       self.config.default_node
     );
-    // let mut stream =
-    //   TcpStream::connect(&self.config.default_node)
-    //   .await?;
     for key in &self.utxos.my_keys {
-      let message = Message::FetchUTXOs(key.public.clone());
+      let mut sync = self
+        .utxos
+        .synced
+        .get(&key.public)
+        .map(|entry| entry.value().clone())
+        .unwrap_or_default();
+      let message = Message::FetchUTXOsSince {
+        pubkey: key.public.clone(),
+        since: sync.tip,
+      };
       message.send_async(&mut *self.stream.lock().await).await?;
-      if let Message::UTXOs(utxos) =
+      if let Message::UTXOUpdate(update) =
         Message::receive_async(&mut *self.stream.lock().await,).await?
       {
         debug!(
-          "Received {} UTXOs for key: {:?}",
-          utxos.len(),
+          "Received {} new and {} spent UTXOs for key: {:?}",
+          update.created.len(),
+          update.spent.len(),
           key.public
         );
-        // Replace the entire UTXO set for this key
-        self.utxos.utxos.insert(
-        key.public.clone(),
-        utxos
-          .into_iter()
-          .map(|(output, marked)| (marked, output))
-          .collect(),
-        );
+        let utxos = sync.apply(update);
+        self.utxos.utxos.insert(key.public.clone(), utxos);
+        self.utxos.synced.insert(key.public.clone(), sync);
       } else {
         error!("Unexpected response from node");
         return Err(anyhow::anyhow!(
@@ -207,7 +289,7 @@ impl Core {
       entry
         .value()
         .iter()
-        .map(|utxo| utxo.1.value)
+        .map(|utxo| utxo.2.value)
         .sum::<u64>()
     })
     .sum()
@@ -219,54 +301,97 @@ impl Core {
   ) -> Result<Transaction> {
     let fee = self.calculate_fee(amount);
     let total_amount = amount + fee;
-    let mut inputs = Vec::new();
+    let mut selected = Vec::new();
     let mut input_sum = 0;
     for entry in self.utxos.utxos.iter() {
       let pubkey = entry.key();
       let utxos = entry.value();
-      for (marked, utxo) in utxos.iter() {
+      // time locks are checked against the block after the
+      // node's tip at the last sync: its height, and the
+      // median time past of the tip
+      let next_block = self.utxos.synced.get(pubkey).and_then(|sync| {
+        let sync = sync.value();
+        Some((sync.tip?.0 + 1, sync.median_time_past?))
+      });
+      for (outpoint, marked, utxo) in utxos.iter() {
         if *marked {
           continue; // Skip marked UTXOs
         }
+        // Skip outputs this wallet can't spend on its own,
+        // or not yet. Without a synced tip, locked outputs
+        // are treated as still locked
+        if utxo.condition != SpendingCondition::Signature {
+          continue;
+        }
+        if let Some(timelock) = &utxo.timelock {
+          let unlocked = next_block.is_some_and(|(height, median_time_past)| {
+            timelock.is_unlocked(height, median_time_past)
+          });
+          if !unlocked {
+            continue;
+          }
+        }
         if input_sum >= total_amount {
           break;
         }
-        inputs.push(btclib::types::TransactionInput {
-          prev_transaction_output_hash: utxo.hash(),
-          signature:
-            btclib::crypto::Signature::sign_output(
-              &utxo.hash(),
-              &self
-                .utxos
-                .my_keys
-                .iter()
-                .find(|k| k.public == *pubkey)
-                .unwrap()
-                .private,
-              )
-      });
-      input_sum += utxo.value;
+        let private_key = &self
+          .utxos
+          .my_keys
+          .iter()
+          .find(|k| k.public == *pubkey)
+          .unwrap()
+          .private;
+        selected.push((*outpoint, private_key.clone()));
+        input_sum += utxo.value;
+      }
+      if input_sum >= total_amount {
+        break;
+      }
     }
-    if input_sum >= total_amount {
-      break;
+    if input_sum < total_amount {
+      return Err(anyhow::anyhow!("Insufficient funds"));
     }
-  }
-  if input_sum < total_amount {
-    return Err(anyhow::anyhow!("Insufficient funds"));
-  }
-  let mut outputs = vec![TransactionOutput {
-    value: amount,
-    unique_id: uuid::Uuid::new_v4(),
-    pubkey: recipient.clone(),
-  }];
-  if input_sum > total_amount {
-    outputs.push(TransactionOutput {
-      value: input_sum - total_amount,
+    let mut outputs = vec![TransactionOutput {
+      value: amount,
       unique_id: uuid::Uuid::new_v4(),
-      pubkey: self.utxos.my_keys[0].public.clone(),
-    });
-  }
-  Ok(Transaction::new(inputs, outputs))
+      pubkey: recipient.clone(),
+      condition: SpendingCondition::Signature,
+      timelock: None,
+    }];
+    if input_sum > total_amount {
+      outputs.push(TransactionOutput {
+        value: input_sum - total_amount,
+        unique_id: uuid::Uuid::new_v4(),
+        pubkey: self.utxos.my_keys[0].public.clone(),
+        condition: SpendingCondition::Signature,
+        timelock: None,
+      });
+    }
+    // Sign every input over all inputs and outputs
+    let prev_outputs: Vec<OutPoint> =
+      selected.iter().map(|(outpoint, _)| *outpoint).collect();
+    let sequences =
+      vec![TransactionInput::SEQUENCE_FINAL; prev_outputs.len()];
+    let mut inputs = Vec::new();
+    for (input_index, (prev_output, private_key)) in
+      selected.iter().enumerate()
+    {
+      let signature_hash = Transaction::signature_hash_for(
+        &prev_outputs,
+        &sequences,
+        &outputs,
+        input_index,
+        SigHashType::ALL,
+      )?;
+      inputs.push(TransactionInput {
+        prev_output: *prev_output,
+        signature: Signature::sign(&signature_hash, private_key),
+        extra_signatures: vec![],
+        sighash: SigHashType::ALL,
+        sequence: TransactionInput::SEQUENCE_FINAL,
+      });
+    }
+    Ok(Transaction::new(inputs, outputs))
   }
   /// Calculate the fee for a transaction.
   fn calculate_fee(&self, amount: u64) -> u64 {
@@ -44,7 +44,7 @@ fn setup_siv(
   balance_content: TextContent,
 ) {
   siv.set_autorefresh(true);
-  siv.set_window_title("BTC wallet".to_string());
+  siv.set_window_title(format!("BTC wallet ({})", core.params.name));
   siv.add_global_callback('q', |s| {
   info!("Quit command received");
   s.quit()
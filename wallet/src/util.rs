@@ -48,6 +48,7 @@ pub fn generate_dummy_config(path: &PathBuf) -> Result<()> {
           },
       ],
       default_node: "127.0.0.1:9000".to_string(),
+      network: "mainnet".to_string(),
       fee_config: FeeConfig {
           fee_type: FeeType::Percent,
           value: 0.1,